@@ -1,9 +1,28 @@
+use std::{env, fs, io::Write, path::Path};
+
+use flate2::{write::GzEncoder, Compression};
+
 fn main() {
     linker_be_nice();
+    compress_dashboard();
     // make sure linkall.x is the last linker script (otherwise might cause problems with flip-link)
     println!("cargo:rustc-link-arg=-Tlinkall.x");
 }
 
+/// Gzips the dashboard into `OUT_DIR`, where `http.rs` includes it from.
+fn compress_dashboard() {
+    let source = "web/index.html";
+    println!("cargo:rerun-if-changed={source}");
+
+    let html = fs::read(source).expect("dashboard source is missing");
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::best());
+    encoder.write_all(&html).unwrap();
+    let compressed = encoder.finish().unwrap();
+
+    let out_dir = env::var("OUT_DIR").unwrap();
+    fs::write(Path::new(&out_dir).join("index.html.gz"), compressed).unwrap();
+}
+
 fn linker_be_nice() {
     let args: Vec<String> = std::env::args().collect();
     if args.len() > 1 {
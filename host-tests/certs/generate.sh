@@ -0,0 +1,45 @@
+#!/bin/sh
+# Regenerates the EC test certificates of `src/mqtt_cert.rs`, the same ones a Mosquitto
+# stand-in can serve:
+#
+#   listener 8883
+#   tls_version tlsv1.3
+#   certfile broker.crt
+#   keyfile broker.key
+set -e
+cd "$(dirname "$0")"
+
+curve="-newkey ec -pkeyopt ec_paramgen_curve:P-256"
+tmp=$(mktemp -d)
+trap 'rm -rf "$tmp"' EXIT
+
+ca() {
+    openssl req -x509 $curve -nodes -keyout "$tmp/$1.key" -out "$tmp/$1.crt" \
+        -subj "/CN=$2" -not_before 20250101000000Z -not_after 21250101000000Z -addext basicConstraints=critical,CA:TRUE
+    openssl x509 -in "$tmp/$1.crt" -outform der -out "$1.der"
+}
+
+# Signs a broker certificate for `broker.local` and 192.168.101.7 with the test CA.
+broker() {
+    openssl req $curve -nodes -keyout "$tmp/$1.key" -out "$tmp/$1.csr" -subj "/CN=$2"
+    openssl x509 -req -in "$tmp/$1.csr" -CA "$tmp/ca.crt" -CAkey "$tmp/ca.key" \
+        -set_serial "0x$(openssl rand -hex 8)" -not_before "$3" -not_after "$4" \
+        -extfile "$tmp/san.cnf" -out "$tmp/$1.crt"
+    openssl x509 -in "$tmp/$1.crt" -outform der -out "$1.der"
+}
+
+printf 'subjectAltName=DNS:broker.local,IP:192.168.101.7\n' > "$tmp/san.cnf"
+
+ca ca "Test Broker CA"
+ca other_ca "Other CA"
+broker broker broker.local 20250101000000Z 21250101000000Z
+broker expired broker.local 20200101000000Z 20210101000000Z
+
+# Without a subject alternative name, so the common name is matched.
+printf 'basicConstraints=CA:FALSE\n' > "$tmp/san.cnf"
+broker cn_only broker.local 20250101000000Z 21250101000000Z
+
+# Raw P-256 scalar of the broker key, to sign test handshakes with.
+openssl ec -in "$tmp/broker.key" -text -noout 2>/dev/null |
+    sed -n '/priv:/,/pub:/p' | grep -v 'priv:\|pub:' | tr -d ' :\n' |
+    xxd -r -p | tail -c 32 > broker_key.bin
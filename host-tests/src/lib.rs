@@ -0,0 +1,15 @@
+//! Host build of the firmware modules that do not depend on the ESP32, so their tests run
+//! with `cargo test` in this directory.
+
+#![no_std]
+// `is_multiple_of` is newer than the toolchain the firmware is built with.
+#![allow(unknown_lints, clippy::manual_is_multiple_of)]
+
+#[path = "../../src/cors.rs"]
+pub mod cors;
+#[path = "../../src/flash_log.rs"]
+pub mod flash_log;
+#[path = "../../src/mqtt_cert.rs"]
+pub mod mqtt_cert;
+#[path = "../../src/protocol.rs"]
+pub mod protocol;
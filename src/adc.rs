@@ -0,0 +1,40 @@
+use core::cell::RefCell;
+
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_time::Timer;
+use esp_hal::{
+    analog::adc::{Adc, AdcChannel, AdcPin},
+    peripherals::ADC1,
+    Blocking,
+};
+use esp_println::println;
+
+/// ADC1 shared between the analog sensors, each of them owns its own enabled pin.
+///
+/// Every pin has to be enabled on the `AdcConfig` before the `Adc` is created, so `main`
+/// builds the driver once and hands out references to it.
+pub type SharedAdc = Mutex<CriticalSectionRawMutex, RefCell<Adc<'static, ADC1, Blocking>>>;
+
+/// Reads the raw 12 bit value of `pin`, retrying until the conversion succeeds.
+pub async fn read_oneshot<PIN: AdcChannel>(adc: &SharedAdc, pin: &mut AdcPin<PIN, ADC1>) -> u16 {
+    loop {
+        let val_err = adc.lock(|adc| adc.borrow_mut().read_oneshot(pin));
+
+        match val_err {
+            Ok(value) => return value,
+            Err(err) => {
+                println!("Error in adc read_oneshot {err:?}");
+                Timer::after_millis(50).await;
+            }
+        }
+    }
+}
+
+/// Single attempt of [`read_oneshot`] for the sensor drivers, `None` while the conversion
+/// is in progress.
+pub fn try_read_oneshot<PIN: AdcChannel>(
+    adc: &SharedAdc,
+    pin: &mut AdcPin<PIN, ADC1>,
+) -> Option<u16> {
+    adc.lock(|adc| adc.borrow_mut().read_oneshot(pin)).ok()
+}
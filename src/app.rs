@@ -1,59 +1,144 @@
-use core::array;
+use core::ops::RangeInclusive;
 
 use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
 use embassy_sync::mutex::Mutex;
-use heapless::String;
+use heapless::{String, Vec};
 use serde::{Deserialize, Serialize};
 use ufmt::uwrite;
 
-use super::utils::FloatRepresentation;
+use super::mqtt_status::{mqtt_status, MqttStatus};
+use super::protocol;
+use super::readiness::{readiness, ReadinessStatus};
+use super::sensor_registry::{Reading, Readings, SensorKind, MAX_CHANNELS};
+use super::utils::Temperature;
 
 pub struct AppState {
     pub counter: u32,
 }
 
-#[derive(Debug, Default, Clone)]
+#[derive(Debug, Default, Clone, Copy, Serialize)]
 pub struct SensorValues {
-    pub temp: f64,
+    pub temp: Temperature,
     pub gas: u16,
     pub flame: bool,
+    /// Seconds left of the gas sensor heater warm-up, 0 once `gas` is meaningful.
+    pub gas_warmup: u16,
+    /// Channels of the additional sensors in the registry.
+    pub extra: Readings,
 }
 
+/// Capacity of [`SensorValues::to_string`], `,<channel>:<value>` takes at most 16 characters.
+pub const SENSOR_STRING_LENGTH: usize = 15 + MAX_CHANNELS * 16;
+/// Capacity of [`SensorValues::to_bytes`], every extra channel takes 6 bytes.
+pub const SENSOR_BYTES_LENGTH: usize = 5 + MAX_CHANNELS * 6;
+
 impl SensorValues {
-    pub fn to_string(self) -> String<12> {
-        let mut string = String::new();
+    pub const EMPTY: Self = Self {
+        temp: Temperature(0),
+        gas: 0,
+        flame: false,
+        gas_warmup: 0,
+        extra: Readings::new(),
+    };
+
+    pub fn gas_ready(&self) -> bool {
+        self.gas_warmup == 0
+    }
 
-        let (int_part, dec_part) = self.temp.float_to_parts(2);
+    /// Status blob published next to the readings: a flags byte (bit 0 set while the gas
+    /// sensor warms up) followed by the remaining warm-up seconds.
+    pub fn status_bytes(&self) -> [u8; 3] {
+        let flags = !self.gas_ready() as u8;
+        let warmup_bytes = self.gas_warmup.to_le_bytes();
+        [flags, warmup_bytes[0], warmup_bytes[1]]
+    }
+
+    pub fn to_string(self) -> String<SENSOR_STRING_LENGTH> {
+        let mut string = String::new();
 
         uwrite!(
             &mut string,
-            "{}.{},{},{}",
-            int_part,
-            dec_part,
+            "{},{},{}",
+            self.temp,
             self.gas,
             self.flame as u8
         )
         .unwrap();
 
+        for reading in self.extra.iter() {
+            uwrite!(&mut string, ",{}:{}", reading.channel, reading.value).unwrap();
+        }
+
         string
     }
 
-    pub fn to_bytes(&self) -> [u8; 5] {
-        let temp_scaled = (self.temp * 100.0) as u16;
-        let temp_bytes = temp_scaled.to_le_bytes();
+    /// The 5 byte blob of the signed centi-degree temperature, gas and flame, followed by `channel`, `kind` and the
+    /// little endian `i32` value of every extra channel.
+    pub fn to_bytes(&self) -> Vec<u8, SENSOR_BYTES_LENGTH> {
+        let temp_bytes = self.temp.to_le_bytes();
         let gas_bytes = self.gas.to_le_bytes();
         let flame_byte = self.flame as u8;
-        [
-            temp_bytes[0],
-            temp_bytes[1],
-            gas_bytes[0],
-            gas_bytes[1],
-            flame_byte,
-        ]
+
+        let mut bytes = Vec::new();
+        bytes
+            .extend_from_slice(&[
+                temp_bytes[0],
+                temp_bytes[1],
+                gas_bytes[0],
+                gas_bytes[1],
+                flame_byte,
+            ])
+            .unwrap();
+
+        for reading in self.extra.iter() {
+            bytes
+                .extend_from_slice(&[reading.channel, reading.kind.to_byte()])
+                .unwrap();
+            bytes
+                .extend_from_slice(&reading.value.to_le_bytes())
+                .unwrap();
+        }
+
+        bytes
+    }
+
+    /// [`SensorValues::to_bytes`] as published in `wire_format`.
+    pub fn to_payload(&self, wire_format: WireFormat) -> Vec<u8, SENSOR_BYTES_LENGTH> {
+        let bytes = self.to_bytes();
+
+        match wire_format {
+            WireFormat::Legacy => Vec::from_slice(protocol::legacy_readings(&bytes)).unwrap(),
+            WireFormat::Framed => bytes,
+        }
+    }
+
+    /// Parses [`SensorValues::to_bytes`], the warm-up is not part of it and reads as 0.
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < 5 {
+            return None;
+        }
+
+        let mut values = Self {
+            temp: Temperature::from_le_bytes([bytes[0], bytes[1]]),
+            gas: u16::from_le_bytes([bytes[2], bytes[3]]),
+            flame: bytes[4] != 0,
+            ..Self::EMPTY
+        };
+
+        for channel in bytes[5..].chunks_exact(6) {
+            let reading = Reading {
+                channel: channel[0],
+                kind: SensorKind::from_byte(channel[1])?,
+                value: i32::from_le_bytes(channel[2..6].try_into().unwrap()),
+            };
+            values.extra.push(reading).ok()?;
+        }
+
+        Some(values)
     }
 }
 
-#[derive(Clone)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
 pub enum Risk {
     Low,
     Moderate,
@@ -68,88 +153,65 @@ impl Risk {
             Risk::High => 2,
         }
     }
-}
-
-pub const HISTORY_LENGTH: usize = 10;
-
-pub struct ValueHistory<const N: usize> {
-    temp: History<f64, N>,
-    ppm: History<u16, N>,
-    flame: History<bool, N>,
-    new_change: bool,
-}
-
-pub struct ValueHistoryArray([SensorValues; 10]);
 
-impl ValueHistoryArray {
-    pub fn to_string(self) -> String<130> {
-        let mut string = String::new();
-
-        for value in self.0 {
-            string.push_str(&value.to_string()).unwrap();
-            string.push('|').unwrap();
+    pub fn name(&self) -> &'static str {
+        match self {
+            Risk::Low => "low",
+            Risk::Moderate => "moderate",
+            Risk::High => "high",
         }
+    }
 
-        string.pop().unwrap();
-
-        string
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0 => Some(Risk::Low),
+            1 => Some(Risk::Moderate),
+            2 => Some(Risk::High),
+            _ => None,
+        }
     }
 }
 
-impl<const N: usize> ValueHistory<N> {
-    pub fn push_values(&mut self, sensor_values: SensorValues) {
-        self.new_change = true;
-        self.flame.push_value(sensor_values.flame);
-        self.ppm.push_value(sensor_values.gas);
-        self.temp.push_value(sensor_values.temp);
-    }
+/// Encoding of the published messages.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+pub enum WireFormat {
+    /// The bare blobs deployed consumers expect.
+    Legacy,
+    /// Versioned frames, see [`crate::protocol`].
+    Framed,
+}
 
-    pub fn current_values(&self) -> SensorValues {
-        SensorValues {
-            flame: *self.flame.get_current_value(),
-            gas: *self.ppm.get_current_value(),
-            temp: *self.temp.get_current_value(),
+impl WireFormat {
+    pub fn to_byte(&self) -> u8 {
+        match self {
+            WireFormat::Legacy => 0,
+            WireFormat::Framed => 1,
         }
     }
 
-    pub fn new_change(&mut self) -> bool {
-        if self.new_change {
-            self.new_change = false;
-            true
-        } else {
-            false
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0 => Some(WireFormat::Legacy),
+            1 => Some(WireFormat::Framed),
+            _ => None,
         }
     }
 }
 
-impl ValueHistory<10> {
-    /// Very expesive function to run
-    pub fn get_current_values_history(&self) -> ValueHistoryArray {
-        let temp_values = self.temp.get_values_ordered();
-        let ppm_values = self.ppm.get_values_ordered();
-        let flame_values = self.flame.get_values_ordered();
-        let arr = array::from_fn(|i| SensorValues {
-            temp: *temp_values[i],
-            gas: *ppm_values[i],
-            flame: *flame_values[i],
-        });
-
-        ValueHistoryArray(arr)
-    }
-}
-
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct Config {
-    pub temp_threshold: f64,
+    pub temp_threshold: Temperature,
     pub gas_threshold: u16,
     pub alarms_enabled: bool,
     pub data_point_interval: u8,
+    pub wire_format: WireFormat,
+    /// Seconds the gas readings are flagged as not ready after boot.
+    pub gas_warmup: u16,
 }
 
 impl Config {
     pub fn to_bytes(&self) -> [u8; 6] {
-        let temp_threshold_scaled = (self.temp_threshold * 100.0) as u16;
-        let temp_threshold_bytes = temp_threshold_scaled.to_le_bytes();
+        let temp_threshold_bytes = self.temp_threshold.to_le_bytes();
         let gas_threshold_bytes = self.gas_threshold.to_le_bytes();
         let alarms_enabled_byte = self.alarms_enabled as u8;
         let data_point_interval_byte = self.data_point_interval;
@@ -163,86 +225,226 @@ impl Config {
         ]
     }
 
+    /// Config payload of the framed protocol, the legacy blob followed by the wire format
+    /// and the gas warm-up.
+    pub fn to_payload(&self) -> [u8; 9] {
+        let bytes = self.to_bytes();
+        let gas_warmup_bytes = self.gas_warmup.to_le_bytes();
+        [
+            bytes[0],
+            bytes[1],
+            bytes[2],
+            bytes[3],
+            bytes[4],
+            bytes[5],
+            self.wire_format.to_byte(),
+            gas_warmup_bytes[0],
+            gas_warmup_bytes[1],
+        ]
+    }
+
+    /// Parses a framed config payload, the gas warm-up is the default without its bytes.
+    pub fn from_payload(payload: &[u8]) -> Result<Self, ConfigError> {
+        if payload.len() < 7 {
+            return Err(ConfigError::InvalidLength {
+                length: payload.len(),
+            });
+        }
+
+        let mut config = Self::from_bytes(payload[..6].try_into().unwrap());
+        config.wire_format = WireFormat::from_byte(payload[6])
+            .ok_or(ConfigError::InvalidWireFormat { value: payload[6] })?;
+        if let Some(gas_warmup) = payload.get(7..9) {
+            config.gas_warmup = u16::from_le_bytes([gas_warmup[0], gas_warmup[1]]);
+        }
+
+        Ok(config)
+    }
+
+    /// Parses the legacy blob, which has no wire format or gas warm-up, so
+    /// [`WireFormat::Legacy`] and the default warm-up are assumed.
     pub fn from_bytes(bytes: [u8; 6]) -> Self {
-        let temp_threshold_scaled = u16::from_le_bytes([bytes[0], bytes[1]]);
+        let temp_threshold = Temperature::from_le_bytes([bytes[0], bytes[1]]);
         let gas_threshold = u16::from_le_bytes([bytes[2], bytes[3]]);
         let alarms_enabled = bytes[4] != 0;
         let data_point_interval = bytes[5];
         Self {
-            temp_threshold: (temp_threshold_scaled as f64) / 100.0,
+            temp_threshold,
             gas_threshold,
             alarms_enabled,
             data_point_interval,
+            wire_format: WireFormat::Legacy,
+            gas_warmup: DEFAULT_GAS_WARMUP,
         }
     }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        check_range(
+            ConfigField::TempThreshold,
+            self.temp_threshold.0,
+            TEMP_THRESHOLD_RANGE,
+        )?;
+        check_range(
+            ConfigField::GasThreshold,
+            self.gas_threshold,
+            GAS_THRESHOLD_RANGE,
+        )?;
+        check_range(
+            ConfigField::DataPointInterval,
+            self.data_point_interval,
+            DATA_POINT_INTERVAL_RANGE,
+        )?;
+        check_range(ConfigField::GasWarmup, self.gas_warmup, GAS_WARMUP_RANGE)
+    }
+}
+
+/// Temperature delta threshold, 0.5 to 50 °C.
+pub const TEMP_THRESHOLD_RANGE: RangeInclusive<i16> = 50..=5000;
+/// Gas threshold, within the 12 bit ADC range.
+pub const GAS_THRESHOLD_RANGE: RangeInclusive<u16> = 1..=4095;
+/// Readings between history data points.
+pub const DATA_POINT_INTERVAL_RANGE: RangeInclusive<u8> = 1..=60;
+/// Gas sensor warm-up in seconds, up to an hour.
+pub const GAS_WARMUP_RANGE: RangeInclusive<u16> = 0..=3600;
+/// Heater warm-up recommended for MQ-series sensors before their readings settle.
+pub const DEFAULT_GAS_WARMUP: u16 = 180;
+
+fn check_range<T: Copy + PartialOrd + Into<i32>>(
+    field: ConfigField,
+    value: T,
+    range: RangeInclusive<T>,
+) -> Result<(), ConfigError> {
+    if range.contains(&value) {
+        return Ok(());
+    }
+
+    Err(ConfigError::OutOfRange {
+        field,
+        min: (*range.start()).into(),
+        max: (*range.end()).into(),
+    })
 }
 
-pub struct History<T: Default + Copy, const N: usize> {
-    inner_values: [T; N],
-    pointer: usize,
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConfigField {
+    TempThreshold,
+    GasThreshold,
+    DataPointInterval,
+    GasWarmup,
 }
 
-impl<T: Default + Copy, const N: usize> History<T, N> {
-    pub fn push_value(&mut self, val: T) {
-        self.inner_values[self.pointer] = val;
-        self.pointer += 1;
+/// Why a config was rejected, serialized as e.g.
+/// `{"reason":"out_of_range","field":"gas_threshold","min":1,"max":4095}`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(tag = "reason", rename_all = "snake_case")]
+pub enum ConfigError {
+    OutOfRange {
+        field: ConfigField,
+        min: i32,
+        max: i32,
+    },
+    InvalidWireFormat {
+        value: u8,
+    },
+    InvalidLength {
+        length: usize,
+    },
+    /// Neither a config payload nor a JSON patch.
+    Malformed,
+}
 
-        if self.pointer >= N {
-            self.pointer = 0;
-        }
+impl ConfigError {
+    pub fn to_json(&self) -> String<96> {
+        serde_json_core::to_string(self).unwrap()
     }
+}
+
+/// Partial config update, fields left out keep their current value.
+#[derive(Debug, Default, Clone, Deserialize)]
+pub struct ConfigPatch {
+    pub temp_threshold: Option<Temperature>,
+    pub gas_threshold: Option<u16>,
+    pub alarms_enabled: Option<bool>,
+    pub data_point_interval: Option<u8>,
+    pub wire_format: Option<WireFormat>,
+    pub gas_warmup: Option<u16>,
+}
 
-    const fn default_value(val: T) -> Self {
+impl ConfigPatch {
+    /// Patch from the legacy blob, which sets everything but the wire format and the gas
+    /// warm-up.
+    pub fn from_bytes(bytes: [u8; 6]) -> Self {
         Self {
-            inner_values: [val; N],
-            pointer: 0,
+            wire_format: None,
+            gas_warmup: None,
+            ..Config::from_bytes(bytes).into()
         }
     }
 
-    pub fn get_current_value(&self) -> &T {
-        &self.inner_values[self.pointer]
+    /// Patch from a framed config payload, which keeps the gas warm-up if it leaves it out.
+    pub fn from_payload(payload: &[u8]) -> Result<Self, ConfigError> {
+        let config = Config::from_payload(payload)?;
+
+        Ok(Self {
+            gas_warmup: payload.get(7..9).map(|_| config.gas_warmup),
+            ..config.into()
+        })
     }
 
-    pub fn get_values_ordered(&self) -> [&T; N] {
-        let initial_pointer = self.pointer;
-        let mut current_pointer = if initial_pointer + 1 == N {
-            0
-        } else {
-            initial_pointer + 1
+    /// The patched config, if it passes validation.
+    pub fn apply(&self, config: &Config) -> Result<Config, ConfigError> {
+        let config = Config {
+            temp_threshold: self.temp_threshold.unwrap_or(config.temp_threshold),
+            gas_threshold: self.gas_threshold.unwrap_or(config.gas_threshold),
+            alarms_enabled: self.alarms_enabled.unwrap_or(config.alarms_enabled),
+            data_point_interval: self
+                .data_point_interval
+                .unwrap_or(config.data_point_interval),
+            wire_format: self.wire_format.unwrap_or(config.wire_format),
+            gas_warmup: self.gas_warmup.unwrap_or(config.gas_warmup),
         };
-        let mut return_values: [&T; N] = [&self.inner_values[0]; N]; // Temporary valid initialization
-        return_values[0] = &self.inner_values[initial_pointer];
-        let mut return_values_pointer = 1;
-        while current_pointer != initial_pointer {
-            return_values[return_values_pointer] = &self.inner_values[current_pointer];
-            current_pointer = if current_pointer + 1 == N {
-                0
-            } else {
-                current_pointer + 1
-            };
-            return_values_pointer += 1;
+        config.validate()?;
+
+        Ok(config)
+    }
+}
+
+impl From<Config> for ConfigPatch {
+    fn from(config: Config) -> Self {
+        Self {
+            temp_threshold: Some(config.temp_threshold),
+            gas_threshold: Some(config.gas_threshold),
+            alarms_enabled: Some(config.alarms_enabled),
+            data_point_interval: Some(config.data_point_interval),
+            wire_format: Some(config.wire_format),
+            gas_warmup: Some(config.gas_warmup),
         }
-        return_values
     }
 }
 
 pub static CONFIG: Mutex<CriticalSectionRawMutex, Config> = Mutex::new(Config {
-    temp_threshold: 5.,
+    temp_threshold: Temperature::from_degrees(5),
     gas_threshold: 1500,
     alarms_enabled: true,
     data_point_interval: 3,
+    wire_format: WireFormat::Legacy,
+    gas_warmup: DEFAULT_GAS_WARMUP,
 });
 
-pub static VALUE_HISTORY: Mutex<CriticalSectionRawMutex, ValueHistory<10>> =
-    Mutex::new(ValueHistory {
-        temp: History::default_value(0.0),
-        ppm: History::default_value(0),
-        flame: History::default_value(false),
-        new_change: true,
-    });
-
-pub static CURRENT_VALUE: Mutex<CriticalSectionRawMutex, SensorValues> = Mutex::new(SensorValues {
-    temp: 0.,
-    gas: 0,
-    flame: false,
-});
+/// Health of the device, as reported by `GET /status` and the `get_status` command.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct DeviceStatus {
+    pub readiness: ReadinessStatus,
+    pub mqtt: MqttStatus,
+}
+
+pub fn device_status() -> DeviceStatus {
+    DeviceStatus {
+        readiness: readiness(),
+        mqtt: mqtt_status(),
+    }
+}
+
+pub static CURRENT_VALUE: Mutex<CriticalSectionRawMutex, SensorValues> =
+    Mutex::new(SensorValues::EMPTY);
@@ -0,0 +1,329 @@
+use core::cell::RefCell;
+
+use base64::engine::{general_purpose::STANDARD, Engine};
+use embassy_net::IpAddress;
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_time::{Duration, Instant};
+use esp_println::println;
+use heapless::{String, Vec};
+use picoserve::{
+    io::Read,
+    request::RequestParts,
+    response::{IntoResponse, Response, ResponseWriter, StatusCode},
+    ResponseSent,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::http::HttpClient;
+use crate::settings::{self, SettingsError};
+
+pub const MAX_USERNAME_LENGTH: usize = 32;
+pub const MAX_SECRET_LENGTH: usize = 64;
+pub const DEFAULT_USERNAME: &str = "admin";
+pub const DEFAULT_PASSWORD: &str = "admin";
+
+const SALT_LENGTH: usize = 16;
+/// Fits a base64 encoded `username:password`.
+const BASIC_CREDENTIALS_LENGTH: usize = MAX_USERNAME_LENGTH + 1 + MAX_SECRET_LENGTH;
+
+/// Failed attempts of one client after which its requests are refused for [`LOCKOUT`].
+const MAX_FAILED_ATTEMPTS: u8 = 5;
+/// Clients with failed attempts that are remembered, the one failing longest ago makes room.
+const MAX_TRACKED_CLIENTS: usize = 8;
+const LOCKOUT: Duration = Duration::from_secs(60);
+
+const REALM: &str = "Basic realm=\"fire-monitor\", charset=\"UTF-8\"";
+
+type Hash = [u8; 32];
+
+/// Credentials of the HTTP API, only salted SHA-256 hashes of the secrets are kept.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuthSettings {
+    pub username: String<MAX_USERNAME_LENGTH>,
+    salt: [u8; SALT_LENGTH],
+    password_hash: Hash,
+    token_hash: Option<Hash>,
+    /// Lets read-only requests through without credentials.
+    pub public_read: bool,
+}
+
+impl AuthSettings {
+    pub fn new(username: &str, password: &str, salt: [u8; SALT_LENGTH]) -> Option<Self> {
+        let mut settings = Self {
+            username: String::try_from(username).ok()?,
+            salt,
+            password_hash: [0; 32],
+            token_hash: None,
+            public_read: true,
+        };
+        settings.password_hash = settings.hash(password);
+
+        Some(settings)
+    }
+
+    pub fn set_password(&mut self, password: &str) {
+        self.password_hash = self.hash(password);
+    }
+
+    /// Sets the bearer token, or disables bearer auth for an empty token.
+    pub fn set_token(&mut self, token: &str) {
+        self.token_hash = (!token.is_empty()).then(|| self.hash(token));
+    }
+
+    pub fn is_default(&self) -> bool {
+        self.username == DEFAULT_USERNAME && self.check_password(DEFAULT_PASSWORD)
+    }
+
+    fn hash(&self, secret: &str) -> Hash {
+        let mut hasher = Sha256::new();
+        hasher.update(self.salt);
+        hasher.update(secret.as_bytes());
+        hasher.finalize().into()
+    }
+
+    fn check_password(&self, password: &str) -> bool {
+        constant_time_eq(&self.hash(password), &self.password_hash)
+    }
+
+    fn check_token(&self, token: &str) -> bool {
+        self.token_hash
+            .is_some_and(|token_hash| constant_time_eq(&self.hash(token), &token_hash))
+    }
+
+    /// Checks an `Authorization` header value, either `Basic` or `Bearer`.
+    fn check_authorization(&self, authorization: &str) -> bool {
+        if let Some(token) = authorization.strip_prefix("Bearer ") {
+            return self.check_token(token.trim());
+        }
+
+        let Some(encoded) = authorization.strip_prefix("Basic ") else {
+            return false;
+        };
+
+        let mut credentials = [0; BASIC_CREDENTIALS_LENGTH];
+        let Ok(length) = STANDARD.decode_slice(encoded.trim(), &mut credentials) else {
+            return false;
+        };
+
+        let Some((username, password)) = core::str::from_utf8(&credentials[..length])
+            .ok()
+            .and_then(|credentials| credentials.split_once(':'))
+        else {
+            return false;
+        };
+
+        // Both are checked so a wrong username takes as long as a wrong password.
+        let username_matches = username == self.username;
+        self.check_password(password) && username_matches
+    }
+}
+
+fn constant_time_eq(a: &Hash, b: &Hash) -> bool {
+    a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+#[derive(Debug, Clone, Copy)]
+struct FailedAttempts {
+    /// `None` for clients whose address is unknown, which share an entry.
+    client: Option<IpAddress>,
+    count: u8,
+    last_failure: Instant,
+    locked_until: Option<Instant>,
+}
+
+impl FailedAttempts {
+    fn is_locked(&self, now: Instant) -> bool {
+        self.locked_until.is_some_and(|until| now < until)
+    }
+}
+
+static FAILED_ATTEMPTS: Mutex<
+    CriticalSectionRawMutex,
+    RefCell<Vec<FailedAttempts, MAX_TRACKED_CLIENTS>>,
+> = Mutex::new(RefCell::new(Vec::new()));
+
+fn locked_out(client: Option<IpAddress>) -> bool {
+    FAILED_ATTEMPTS.lock(|attempts| {
+        attempts
+            .borrow()
+            .iter()
+            .any(|attempts| attempts.client == client && attempts.is_locked(Instant::now()))
+    })
+}
+
+fn record_attempt(client: Option<IpAddress>, success: bool) {
+    FAILED_ATTEMPTS.lock(|attempts| {
+        let mut attempts = attempts.borrow_mut();
+        let index = attempts
+            .iter()
+            .position(|attempts| attempts.client == client);
+
+        if success {
+            if let Some(index) = index {
+                attempts.swap_remove(index);
+            }
+            return;
+        }
+
+        let now = Instant::now();
+        let index = index.unwrap_or_else(|| {
+            // Locked out clients are kept, so others failing can not lift their lockout.
+            if attempts.is_full() {
+                let oldest = (0..attempts.len())
+                    .min_by_key(|&i| (attempts[i].is_locked(now), attempts[i].last_failure))
+                    .unwrap();
+                attempts.swap_remove(oldest);
+            }
+
+            attempts
+                .push(FailedAttempts {
+                    client,
+                    count: 0,
+                    last_failure: now,
+                    locked_until: None,
+                })
+                .unwrap();
+            attempts.len() - 1
+        });
+
+        let attempts = &mut attempts[index];
+        attempts.count += 1;
+        attempts.last_failure = now;
+        if attempts.count >= MAX_FAILED_ATTEMPTS {
+            println!(
+                "Too many failed HTTP logins from {:?}, locking out for {}s",
+                client,
+                LOCKOUT.as_secs()
+            );
+            attempts.count = 0;
+            attempts.locked_until = Some(now + LOCKOUT);
+        }
+    });
+}
+
+/// Requests that only read state. The dashboard socket is excluded as it carries commands.
+fn is_read_only(request_parts: &RequestParts<'_>) -> bool {
+    matches!(request_parts.method(), "GET" | "HEAD") && request_parts.path().encoded() != "/ws"
+}
+
+/// Requires HTTP Basic or bearer credentials for every request that changes state, and for
+/// reads too unless [`AuthSettings::public_read`] is set. Clients failing too often are
+/// locked out by address, and with the default credentials only `POST /auth` is accepted
+/// among the writes.
+///
+/// Preflights are answered by the CORS layer before they get here, so this goes inside of
+/// it.
+pub struct AuthLayer;
+
+impl<PathParameters> picoserve::routing::Layer<HttpClient, PathParameters> for AuthLayer {
+    type NextState = HttpClient;
+    type NextPathParameters = PathParameters;
+
+    async fn call_layer<
+        'a,
+        R: Read + 'a,
+        NextLayer: picoserve::routing::Next<'a, R, Self::NextState, Self::NextPathParameters>,
+        W: ResponseWriter<Error = R::Error>,
+    >(
+        &self,
+        next: NextLayer,
+        state: &HttpClient,
+        path_parameters: PathParameters,
+        request_parts: RequestParts<'_>,
+        response_writer: W,
+    ) -> Result<ResponseSent, W::Error> {
+        // Without loaded settings there are no credentials to check, so only reads get in.
+        let Some(auth) = settings::read(|settings| settings.auth.clone()).await else {
+            if is_read_only(&request_parts) {
+                return next.run(state, path_parameters, response_writer).await;
+            }
+
+            let response = (StatusCode::SERVICE_UNAVAILABLE, "Settings unavailable\n");
+            return response_writer
+                .write_response(next.into_connection(), response.into_response())
+                .await;
+        };
+
+        if auth.public_read && is_read_only(&request_parts) {
+            return next.run(state, path_parameters, response_writer).await;
+        }
+
+        if locked_out(state.address) {
+            let response =
+                Response::new(StatusCode::TOO_MANY_REQUESTS, "Too many failed attempts\n")
+                    .with_header("Retry-After", LOCKOUT.as_secs());
+            return response_writer
+                .write_response(next.into_connection(), response)
+                .await;
+        }
+
+        let authorization = request_parts
+            .headers()
+            .get("Authorization")
+            .and_then(|authorization| authorization.as_str().ok());
+
+        let authorized = authorization.is_some_and(|authorization| {
+            let authorized = auth.check_authorization(authorization);
+            record_attempt(state.address, authorized);
+            authorized
+        });
+
+        // Anyone on the network knows the default credentials, so they only get to change
+        // them.
+        if authorized
+            && auth.is_default()
+            && !is_read_only(&request_parts)
+            && request_parts.path().encoded() != "/auth"
+        {
+            let response = (
+                StatusCode::FORBIDDEN,
+                "Change the default credentials with POST /auth first\n",
+            );
+            return response_writer
+                .write_response(next.into_connection(), response.into_response())
+                .await;
+        }
+
+        if authorized {
+            return next.run(state, path_parameters, response_writer).await;
+        }
+
+        let response = Response::new(StatusCode::UNAUTHORIZED, "Unauthorized\n")
+            .with_header("WWW-Authenticate", REALM);
+        response_writer
+            .write_response(next.into_connection(), response)
+            .await
+    }
+}
+
+/// Body of `POST /auth`, fields left out keep their value.
+#[derive(Debug, Deserialize)]
+pub struct AuthUpdate {
+    pub username: Option<String<MAX_USERNAME_LENGTH>>,
+    pub password: Option<String<MAX_SECRET_LENGTH>>,
+    /// An empty token disables bearer auth.
+    pub token: Option<String<MAX_SECRET_LENGTH>>,
+    pub public_read: Option<bool>,
+}
+
+/// Applies and persists new credentials.
+pub async fn update(update: AuthUpdate) -> Result<(), SettingsError> {
+    settings::update(|settings| {
+        let auth = &mut settings.auth;
+
+        if let Some(username) = update.username {
+            auth.username = username;
+        }
+        if let Some(password) = update.password {
+            auth.set_password(&password);
+        }
+        if let Some(token) = update.token {
+            auth.set_token(&token);
+        }
+        if let Some(public_read) = update.public_read {
+            auth.public_read = public_read;
+        }
+    })
+    .await
+}
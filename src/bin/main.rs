@@ -1,11 +1,16 @@
 #![no_std]
 #![no_main]
 
+use core::cell::RefCell;
+
 use embassy_executor::Spawner;
+use embassy_sync::blocking_mutex::Mutex;
+use esp_hal::analog::adc::{Adc, AdcConfig, Attenuation};
 use esp_hal::clock::CpuClock;
 use esp_hal::rng::Rng;
 use esp_hal::timer::timg::TimerGroup;
 use esp_println::println;
+use esp_storage::FlashStorage;
 
 #[panic_handler]
 fn panic(err: &core::panic::PanicInfo) -> ! {
@@ -17,7 +22,10 @@ extern crate alloc;
 
 use async_esp_server as lib;
 use esp_wifi::EspWifiController;
+use lib::adc::SharedAdc;
+use lib::flame_sensor::{flame_edge_task, FlameConfig, FlameInput};
 use lib::peripheral_tasks::*;
+use lib::sensor_registry::SensorRegistry;
 
 #[esp_hal_embassy::main]
 async fn main(spawner: Spawner) {
@@ -34,34 +42,77 @@ async fn main(spawner: Spawner) {
     let timer1 = TimerGroup::new(peripherals.TIMG0);
 
     let rng = Rng::new(peripherals.RNG);
-    let esp_wifi_ctrl = &*lib::mk_static!(
-        EspWifiController<'static>,
-        esp_wifi::init(timer1.timer0, rng, peripherals.RADIO_CLK).unwrap()
-    );
+    lib::settings::init(FlashStorage::new(), rng).await;
 
-    let stack = lib::wifi::start_wifi(esp_wifi_ctrl, peripherals.WIFI, rng, &spawner).await;
+    lib::history_log::init(FlashStorage::new()).await;
+    spawner.must_spawn(lib::history_log::history_log_task());
 
-    spawner.must_spawn(lib::mqtt::mqtt_task(stack));
+    // spawner.must_spawn(test_load());
 
-    println!("Mqtt client started");
+    let mut adc_config = AdcConfig::default();
+    let gas_pin = adc_config.enable_pin(peripherals.GPIO34, Attenuation::_11dB);
+    let adc = &*lib::mk_static!(
+        SharedAdc,
+        Mutex::new(RefCell::new(Adc::new(peripherals.ADC1, adc_config)))
+    );
 
-    // spawner.must_spawn(test_load());
+    let registry = lib::mk_static!(SensorRegistry<'static>, SensorRegistry::new());
+    // Additional sensors are registered here, e.g. an SHT3x on I2C1:
+    // let humidity = lib::mk_static!(
+    //     Sht3x<'static>,
+    //     Sht3x::new(
+    //         peripherals.I2C1.into(),
+    //         peripherals.GPIO22.into(),
+    //         peripherals.GPIO21.into(),
+    //         SHT3X_ADDRESS
+    //     )
+    // );
+    // registry.register(FIRST_USER_CHANNEL, humidity).unwrap();
 
+    let flame_config = FlameConfig::default();
+
+    spawner.must_spawn(flame_edge_task(peripherals.GPIO19, flame_config.min_on_time));
     spawner.must_spawn(sensor_reader_task(
         peripherals.GPIO15,
-        peripherals.ADC1,
-        peripherals.GPIO34,
-        peripherals.GPIO19,
+        adc,
+        gas_pin,
+        FlameInput::EdgeCaptured,
+        flame_config,
+        registry,
     ));
     spawner.must_spawn(display_task(
         peripherals.I2C0.into(),
         peripherals.GPIO18,
         peripherals.GPIO23,
     ));
+    spawner.must_spawn(lib::commands::reboot_task());
     spawner.must_spawn(alarms_task(
         peripherals.GPIO12,
         peripherals.GPIO13,
         peripherals.GPIO14,
         peripherals.GPIO27,
     ));
+
+    println!("Sensors and alarms started");
+
+    // Everything below only needs the network, which comes up in the background and
+    // leaves the local alarms alone if it never does.
+    let esp_wifi_ctrl = &*lib::mk_static!(
+        EspWifiController<'static>,
+        esp_wifi::init(timer1.timer0, rng, peripherals.RADIO_CLK).unwrap()
+    );
+
+    let stack = lib::wifi::start_wifi(esp_wifi_ctrl, peripherals.WIFI, rng, &spawner).await;
+
+    spawner.must_spawn(lib::mqtt::mqtt_task(stack, rng));
+
+    println!("Mqtt client started");
+
+    spawner.must_spawn(lib::sntp::sntp_task(stack));
+    lib::http::start_web_server(stack, &spawner);
+
+    println!("Web server started");
+
+    spawner.must_spawn(lib::mdns::mdns_task(stack));
+    spawner.must_spawn(lib::udp::udp_task(stack));
 }
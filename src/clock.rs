@@ -0,0 +1,119 @@
+use core::cell::Cell;
+
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_time::Instant;
+use serde::Serialize;
+use ufmt::{uDisplay, uWrite, Formatter};
+
+/// Unix time at boot in seconds, known once SNTP answered.
+static BOOT_UNIX_TIME: Mutex<CriticalSectionRawMutex, Cell<Option<u64>>> =
+    Mutex::new(Cell::new(None));
+
+/// Point in time with a resolution of one second, in UTC once the clock is synced and in
+/// seconds since boot before that.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Timestamp {
+    pub secs: u32,
+    pub utc: bool,
+}
+
+impl Timestamp {
+    /// `u8` clock (`0` since boot, `1` UTC) followed by the little endian seconds.
+    pub fn to_bytes(&self) -> [u8; 5] {
+        let secs = self.secs.to_le_bytes();
+        [self.utc as u8, secs[0], secs[1], secs[2], secs[3]]
+    }
+
+    /// Converts a timestamp taken since this boot to UTC if the clock got synced since.
+    pub fn resolve(self) -> Self {
+        if self.utc {
+            return self;
+        }
+
+        match boot_unix_time() {
+            Some(boot) => Self {
+                secs: (boot + self.secs as u64) as u32,
+                utc: true,
+            },
+            None => self,
+        }
+    }
+}
+
+/// `YYYY-MM-DDTHH:MM:SSZ` in UTC, `+<seconds>` since boot otherwise.
+impl uDisplay for Timestamp {
+    fn fmt<W: uWrite + ?Sized>(&self, f: &mut Formatter<'_, W>) -> Result<(), W::Error> {
+        if !self.utc {
+            return ufmt::uwrite!(f, "+{}", self.secs);
+        }
+
+        let (year, month, day) = civil_from_days(self.secs / 86400);
+        let secs_of_day = self.secs % 86400;
+
+        ufmt::uwrite!(f, "{}-", year)?;
+        write_two_digits(f, month)?;
+        f.write_str("-")?;
+        write_two_digits(f, day)?;
+        f.write_str("T")?;
+        write_two_digits(f, secs_of_day / 3600)?;
+        f.write_str(":")?;
+        write_two_digits(f, secs_of_day / 60 % 60)?;
+        f.write_str(":")?;
+        write_two_digits(f, secs_of_day % 60)?;
+        f.write_str("Z")
+    }
+}
+
+fn write_two_digits<W: uWrite + ?Sized>(
+    f: &mut Formatter<'_, W>,
+    value: u32,
+) -> Result<(), W::Error> {
+    if value < 10 {
+        f.write_str("0")?;
+    }
+
+    ufmt::uwrite!(f, "{}", value)
+}
+
+/// Year, month and day of the days since 1970-01-01, from Howard Hinnant's `civil_from_days`.
+fn civil_from_days(days: u32) -> (u32, u32, u32) {
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let day_of_era = z - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + (month <= 2) as u32;
+
+    (year, month, day)
+}
+
+pub fn boot_unix_time() -> Option<u64> {
+    BOOT_UNIX_TIME.lock(|boot| boot.get())
+}
+
+pub fn is_synced() -> bool {
+    boot_unix_time().is_some()
+}
+
+/// Sets the wall clock from the current unix time in seconds.
+pub fn set_unix_time(unix_secs: u64) {
+    let boot = unix_secs.saturating_sub(Instant::now().as_secs());
+
+    BOOT_UNIX_TIME.lock(|boot_unix_time| boot_unix_time.set(Some(boot)));
+}
+
+pub fn now() -> Timestamp {
+    Timestamp {
+        secs: Instant::now().as_secs() as u32,
+        utc: false,
+    }
+    .resolve()
+}
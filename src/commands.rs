@@ -0,0 +1,174 @@
+use core::cell::Cell;
+
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Instant, Timer};
+use esp_println::println;
+use serde::Deserialize;
+
+use crate::app::{ConfigError, ConfigPatch, CONFIG};
+use crate::events::{self, Event};
+use crate::settings;
+
+/// Command accepted from the dashboard and MQTT.
+///
+/// As JSON it is externally tagged, e.g. `"ack_alarm"` or `{"silence": 60}`. The binary
+/// form is an opcode followed by its arguments, see [`Command::from_bytes`].
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Command {
+    /// Changes the given config fields, see [`ConfigPatch`].
+    SetConfig(ConfigPatch),
+    /// Stops the buzzer until the risk drops below high.
+    AckAlarm,
+    /// Stops the buzzer for the given seconds.
+    Silence(u16),
+    /// Cycles the LED colors and sounds the buzzer once.
+    SelfTest,
+    Reboot,
+}
+
+/// Why the binary form of a command could not be parsed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CommandError {
+    /// Unknown opcode or missing arguments.
+    Invalid,
+    /// A set config with a payload that is not a valid config.
+    Config(ConfigError),
+}
+
+pub const OPCODE_SET_CONFIG: u8 = 0x01;
+pub const OPCODE_ACK_ALARM: u8 = 0x02;
+pub const OPCODE_SILENCE: u8 = 0x03;
+pub const OPCODE_SELF_TEST: u8 = 0x04;
+pub const OPCODE_REBOOT: u8 = 0x05;
+
+impl Command {
+    /// Parses `u8` opcode followed by the config payload for set config and `u16` seconds
+    /// for silence. A config payload that does not decode is rejected like one failing
+    /// validation in [`dispatch`].
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, CommandError> {
+        let (opcode, arguments) = bytes.split_first().ok_or(CommandError::Invalid)?;
+
+        match *opcode {
+            OPCODE_SET_CONFIG => ConfigPatch::from_payload(arguments)
+                .map(Command::SetConfig)
+                .map_err(CommandError::Config),
+            OPCODE_ACK_ALARM => Ok(Command::AckAlarm),
+            OPCODE_SILENCE => {
+                let secs = arguments.get(..2).ok_or(CommandError::Invalid)?;
+                Ok(Command::Silence(u16::from_le_bytes([secs[0], secs[1]])))
+            }
+            OPCODE_SELF_TEST => Ok(Command::SelfTest),
+            OPCODE_REBOOT => Ok(Command::Reboot),
+            _ => Err(CommandError::Invalid),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Command::SetConfig(_) => "set_config",
+            Command::AckAlarm => "ack_alarm",
+            Command::Silence(_) => "silence",
+            Command::SelfTest => "self_test",
+            Command::Reboot => "reboot",
+        }
+    }
+}
+
+/// Buzzer overrides set by [`Command::AckAlarm`] and [`Command::Silence`].
+#[derive(Debug, Default, Clone, Copy)]
+pub struct AlarmControl {
+    pub acknowledged: bool,
+    pub silenced_until: Option<Instant>,
+}
+
+impl AlarmControl {
+    pub fn buzzer_muted(&self) -> bool {
+        self.acknowledged
+            || self
+                .silenced_until
+                .is_some_and(|until| Instant::now() < until)
+    }
+}
+
+static ALARM_CONTROL: Mutex<CriticalSectionRawMutex, Cell<AlarmControl>> =
+    Mutex::new(Cell::new(AlarmControl {
+        acknowledged: false,
+        silenced_until: None,
+    }));
+
+/// Wakes the alarms task when the overrides change or a self-test is requested.
+pub static ALARM_CONTROL_SIGNAL: Signal<CriticalSectionRawMutex, AlarmRequest> = Signal::new();
+static REBOOT_SIGNAL: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+#[derive(Debug, Clone, Copy)]
+pub enum AlarmRequest {
+    Refresh,
+    SelfTest,
+}
+
+pub fn alarm_control() -> AlarmControl {
+    ALARM_CONTROL.lock(|control| control.get())
+}
+
+/// Clears the acknowledgement, once the alarm it was given for is over.
+pub fn clear_acknowledgement() {
+    ALARM_CONTROL.lock(|control| {
+        control.set(AlarmControl {
+            acknowledged: false,
+            ..control.get()
+        })
+    });
+}
+
+fn update_alarm_control(update: impl FnOnce(&mut AlarmControl)) {
+    ALARM_CONTROL.lock(|control| {
+        let mut value = control.get();
+        update(&mut value);
+        control.set(value);
+    });
+
+    ALARM_CONTROL_SIGNAL.signal(AlarmRequest::Refresh);
+}
+
+/// Runs a command, whichever transport it came from. Only a set config can be rejected,
+/// when the patched config fails validation.
+pub async fn dispatch(command: Command) -> Result<(), ConfigError> {
+    println!("Running command {}", command.name());
+
+    match command {
+        Command::SetConfig(patch) => {
+            let config = {
+                let mut current = CONFIG.lock().await;
+                let config = patch.apply(&current).inspect_err(|e| {
+                    println!("Rejected config: {:?}", e);
+                })?;
+                *current = config.clone();
+                config
+            };
+
+            if let Err(e) = settings::update(|settings| settings.config = config.clone()).await {
+                println!("Failed to persist config: {:?}", e);
+            }
+            events::publish(Event::Config(config));
+        }
+        Command::AckAlarm => update_alarm_control(|control| control.acknowledged = true),
+        Command::Silence(secs) => update_alarm_control(|control| {
+            control.silenced_until = Some(Instant::now() + Duration::from_secs(secs as u64))
+        }),
+        Command::SelfTest => ALARM_CONTROL_SIGNAL.signal(AlarmRequest::SelfTest),
+        Command::Reboot => REBOOT_SIGNAL.signal(()),
+    }
+
+    Ok(())
+}
+
+/// Resets the chip once a reboot command came in, after a delay that lets the reply go out.
+#[embassy_executor::task]
+pub async fn reboot_task() {
+    REBOOT_SIGNAL.wait().await;
+    println!("Rebooting");
+    Timer::after(Duration::from_secs(1)).await;
+    esp_hal::system::software_reset();
+}
@@ -0,0 +1,221 @@
+//! Which origins may call the API from a browser and how, persisted in the settings and
+//! applied by [`crate::cors_layer`]. Without any origin, only the dashboard served by the
+//! device itself can.
+
+use heapless::{String, Vec};
+use serde::{Deserialize, Serialize};
+
+pub const MAX_ALLOWED_ORIGINS: usize = 4;
+pub const MAX_ORIGIN_LENGTH: usize = 64;
+pub const MAX_LIST_LENGTH: usize = 64;
+
+pub type Origin = String<MAX_ORIGIN_LENGTH>;
+/// Comma separated methods or headers, e.g. `GET, POST`.
+pub type List = String<MAX_LIST_LENGTH>;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CorsSettings {
+    /// Exact origins like `http://192.168.1.20:8080`, or `*` for any.
+    pub allowed_origins: Vec<Origin, MAX_ALLOWED_ORIGINS>,
+    /// Answered to preflights as `Access-Control-Allow-Methods`.
+    pub allowed_methods: List,
+    /// Answered to preflights as `Access-Control-Allow-Headers`.
+    pub allowed_headers: List,
+    /// Seconds browsers may cache a preflight answer.
+    pub max_age: u32,
+}
+
+impl Default for CorsSettings {
+    fn default() -> Self {
+        Self {
+            allowed_origins: Vec::new(),
+            allowed_methods: List::try_from("GET, POST, OPTIONS").unwrap(),
+            allowed_headers: List::try_from("Content-Type, Authorization").unwrap(),
+            max_age: 600,
+        }
+    }
+}
+
+/// How a request is answered, from its `Origin` header, method and
+/// `Access-Control-Request-Method` header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CorsResponse<'r> {
+    /// Same origin or not allowed, the response goes out without CORS headers.
+    Unchanged,
+    /// A preflight from an allowed origin, answered with 204 without running the routes.
+    Preflight(&'r str),
+    /// The response of the routes with its status, allowing the origin to read it.
+    Allowed(&'r str),
+}
+
+impl CorsSettings {
+    /// Every origin is `*` or a scheme and host with an optional port, the methods and
+    /// headers are lists of tokens.
+    pub fn is_valid(&self) -> bool {
+        self.allowed_origins
+            .iter()
+            .all(|origin| origin == "*" || is_valid_origin(origin))
+            && is_valid_list(&self.allowed_methods)
+            && is_valid_list(&self.allowed_headers)
+    }
+
+    /// The `Access-Control-Allow-Origin` value for a request from `origin`, if allowed.
+    pub fn allow_origin<'r>(&self, origin: &'r str) -> Option<&'r str> {
+        if self.allowed_origins.iter().any(|allowed| allowed == "*") {
+            return Some("*");
+        }
+
+        self.allowed_origins
+            .iter()
+            .any(|allowed| allowed == origin)
+            .then_some(origin)
+    }
+
+    /// Only an OPTIONS request with `request_method` is a preflight, any other gets to the
+    /// routes.
+    pub fn response<'r>(
+        &self,
+        origin: Option<&'r str>,
+        method: &str,
+        request_method: Option<&str>,
+    ) -> CorsResponse<'r> {
+        match origin.and_then(|origin| self.allow_origin(origin)) {
+            None => CorsResponse::Unchanged,
+            Some(allowed) if method == "OPTIONS" && request_method.is_some() => {
+                CorsResponse::Preflight(allowed)
+            }
+            Some(allowed) => CorsResponse::Allowed(allowed),
+        }
+    }
+}
+
+fn is_valid_origin(origin: &str) -> bool {
+    let Some(authority) = origin
+        .strip_prefix("http://")
+        .or_else(|| origin.strip_prefix("https://"))
+    else {
+        return false;
+    };
+
+    !authority.is_empty()
+        && authority
+            .bytes()
+            .all(|byte| byte.is_ascii_graphic() && !matches!(byte, b'/' | b'?' | b'#' | b'@'))
+}
+
+fn is_valid_list(list: &str) -> bool {
+    list.split(',').all(|item| {
+        let item = item.trim_matches(' ');
+
+        !item.is_empty()
+            && item
+                .bytes()
+                .all(|byte| byte.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&byte))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DASHBOARD: &str = "http://192.168.1.20:8080";
+
+    fn settings(origins: &[&str]) -> CorsSettings {
+        CorsSettings {
+            allowed_origins: origins
+                .iter()
+                .map(|origin| String::try_from(*origin).unwrap())
+                .collect(),
+            ..CorsSettings::default()
+        }
+    }
+
+    #[test]
+    fn answers_preflight_from_allowed_origin() {
+        assert_eq!(
+            settings(&[DASHBOARD]).response(Some(DASHBOARD), "OPTIONS", Some("POST")),
+            CorsResponse::Preflight(DASHBOARD)
+        );
+    }
+
+    #[test]
+    fn passes_options_without_request_method_to_routes() {
+        assert_eq!(
+            settings(&[DASHBOARD]).response(Some(DASHBOARD), "OPTIONS", None),
+            CorsResponse::Allowed(DASHBOARD)
+        );
+    }
+
+    #[test]
+    fn keeps_responses_for_allowed_origin() {
+        for method in ["GET", "POST"] {
+            assert_eq!(
+                settings(&[DASHBOARD]).response(Some(DASHBOARD), method, None),
+                CorsResponse::Allowed(DASHBOARD)
+            );
+        }
+    }
+
+    #[test]
+    fn ignores_disallowed_origin() {
+        let settings = settings(&[DASHBOARD]);
+
+        for method in ["OPTIONS", "GET"] {
+            assert_eq!(
+                settings.response(Some("http://evil.example"), method, Some("POST")),
+                CorsResponse::Unchanged
+            );
+            assert_eq!(
+                settings.response(None, method, Some("POST")),
+                CorsResponse::Unchanged
+            );
+        }
+    }
+
+    #[test]
+    fn allows_no_origin_by_default() {
+        assert_eq!(
+            CorsSettings::default().response(Some(DASHBOARD), "OPTIONS", Some("POST")),
+            CorsResponse::Unchanged
+        );
+    }
+
+    #[test]
+    fn allows_any_origin_with_wildcard() {
+        assert_eq!(
+            settings(&["*"]).response(Some("http://evil.example"), "GET", None),
+            CorsResponse::Allowed("*")
+        );
+    }
+
+    #[test]
+    fn validates_methods_and_headers() {
+        let mut settings = settings(&[DASHBOARD]);
+        settings.allowed_methods = List::try_from("GET,POST, DELETE").unwrap();
+        settings.allowed_headers = List::try_from("X-Api-Key").unwrap();
+        assert!(settings.is_valid());
+
+        for invalid in ["", "GET,", "GET\r\nX-Evil: 1", "GET;POST"] {
+            settings.allowed_headers = List::try_from(invalid).unwrap();
+            assert!(!settings.is_valid(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn validates_origins() {
+        for valid in ["*", DASHBOARD, "https://dashboard.local"] {
+            assert!(settings(&[valid]).is_valid(), "{valid}");
+        }
+        for invalid in [
+            "",
+            "192.168.1.20",
+            "http://",
+            "http://a/",
+            "ftp://a",
+            "http://a b",
+        ] {
+            assert!(!settings(&[invalid]).is_valid(), "{invalid}");
+        }
+    }
+}
@@ -1,61 +1,65 @@
-use anyhow::Ok;
 use picoserve::{
     io::Read,
-    response::{ResponseWriter, StatusCode},
+    request::RequestParts,
+    response::{Body, Connection, HeadersIter, Response, ResponseWriter, StatusCode},
+    ResponseSent,
 };
 
-struct CorsResponseWriter<W> {
+use crate::cors::{CorsResponse, CorsSettings};
+use crate::settings::{self, SettingsError};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CorsUpdateError {
+    /// An origin that is neither `*` nor a scheme and host with an optional port, or
+    /// methods or headers that are not lists of tokens.
+    Invalid,
+    Settings(SettingsError),
+}
+
+/// The stored settings, without any origin if they are unavailable.
+pub async fn cors_settings() -> CorsSettings {
+    settings::read(|settings| settings.cors.clone())
+        .await
+        .unwrap_or_default()
+}
+
+/// Persists new settings, which apply from the next request on.
+pub async fn update(cors: CorsSettings) -> Result<(), CorsUpdateError> {
+    if !cors.is_valid() {
+        return Err(CorsUpdateError::Invalid);
+    }
+
+    settings::update(|settings| settings.cors = cors)
+        .await
+        .map_err(CorsUpdateError::Settings)
+}
+
+/// Adds the allowed origin to the responses of the routes.
+struct CorsResponseWriter<'r, W> {
     response_writer: W,
+    allow_origin: &'r str,
 }
 
-impl<W: ResponseWriter> ResponseWriter for CorsResponseWriter<W> {
+impl<W: ResponseWriter> ResponseWriter for CorsResponseWriter<'_, W> {
     type Error = W::Error;
 
-    async fn write_response<
-        R: Read<Error = Self::Error>,
-        H: picoserve::response::HeadersIter,
-        B: picoserve::response::Body,
-    >(
+    async fn write_response<R: Read<Error = Self::Error>, H: HeadersIter, B: Body>(
         self,
-        connection: picoserve::response::Connection<'_, R>,
-        response: picoserve::response::Response<H, B>,
-    ) -> Result<picoserve::ResponseSent, Self::Error> {
-        //if self.request_method == "OPTIONS" {
-        //    let new_response = response
-        //        .with_header("Access-Control-Allow-Origin", "*")
-        //        .with_header(
-        //            "Access-Control-Allow-Methods",
-        //            "GET, POST, PUT, DELETE, OPTIONS",
-        //        )
-        //        .with_header("Access-Control-Allow-Headers", "*")
-        //        .with_status_code(StatusCode::new(200));
-        //
-        //    self.response_writer
-        //        .write_response(connection, new_response)
-        //        .await
-        //} else {
-        //    let new_response = response.with_header("Access-Control-Allow-Origin", "*");
-        //
-        //    self.response_writer
-        //        .write_response(connection, new_response)
-        //        .await
-        //}
-
-        let new_response = response
-            .with_header("Access-Control-Allow-Origin", "*")
-            .with_header(
-                "Access-Control-Allow-Methods",
-                "GET, POST, PUT, DELETE, OPTIONS",
-            )
-            .with_header("Access-Control-Allow-Headers", "*")
-            .with_status_code(StatusCode::new(200));
+        connection: Connection<'_, R>,
+        response: Response<H, B>,
+    ) -> Result<ResponseSent, Self::Error> {
+        let response = response
+            .with_header("Access-Control-Allow-Origin", self.allow_origin)
+            .with_header("Vary", "Origin");
 
         self.response_writer
-            .write_response(connection, new_response)
+            .write_response(connection, response)
             .await
     }
 }
 
+/// Adds CORS headers for the origins in the settings, keeping the status the handler chose,
+/// and answers their preflights with 204 before the routes see them.
 pub struct CorsLayer;
 
 impl<State, PathParameters> picoserve::routing::Layer<State, PathParameters> for CorsLayer {
@@ -72,14 +76,49 @@ impl<State, PathParameters> picoserve::routing::Layer<State, PathParameters> for
         next: NextLayer,
         state: &State,
         path_parameters: PathParameters,
-        _request_parts: picoserve::request::RequestParts<'_>,
+        request_parts: RequestParts<'_>,
         response_writer: W,
-    ) -> Result<picoserve::ResponseSent, W::Error> {
-        next.run(
-            state,
-            path_parameters,
-            CorsResponseWriter { response_writer },
-        )
-        .await
+    ) -> Result<ResponseSent, W::Error> {
+        let headers = request_parts.headers();
+        let origin = headers
+            .get("Origin")
+            .and_then(|origin| origin.as_str().ok());
+        let request_method = headers
+            .get("Access-Control-Request-Method")
+            .and_then(|method| method.as_str().ok());
+
+        let cors = cors_settings().await;
+        match cors.response(origin, request_parts.method(), request_method) {
+            CorsResponse::Unchanged => next.run(state, path_parameters, response_writer).await,
+            CorsResponse::Preflight(allow_origin) => {
+                let response = Response::new(StatusCode::NO_CONTENT, "")
+                    .with_header("Access-Control-Allow-Origin", allow_origin)
+                    .with_header(
+                        "Access-Control-Allow-Methods",
+                        cors.allowed_methods.as_str(),
+                    )
+                    .with_header(
+                        "Access-Control-Allow-Headers",
+                        cors.allowed_headers.as_str(),
+                    )
+                    .with_header("Access-Control-Max-Age", cors.max_age)
+                    .with_header("Vary", "Origin");
+
+                response_writer
+                    .write_response(next.into_connection(), response)
+                    .await
+            }
+            CorsResponse::Allowed(allow_origin) => {
+                next.run(
+                    state,
+                    path_parameters,
+                    CorsResponseWriter {
+                        response_writer,
+                        allow_origin,
+                    },
+                )
+                .await
+            }
+        }
     }
 }
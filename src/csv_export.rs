@@ -0,0 +1,151 @@
+use heapless::String;
+use picoserve::io::Write;
+use picoserve::response::chunked::{ChunkWriter, Chunks, ChunksWritten};
+use ufmt::uwrite;
+
+use crate::app::Risk;
+use crate::clock::Timestamp;
+use crate::history::{HistoryQuery, Resolution, VALUE_HISTORY};
+use crate::history_log;
+use crate::utils::Temperature;
+
+/// Rows read and sent per chunk.
+const CSV_PAGE_LENGTH: usize = 16;
+/// `2025-01-01T00:00:00Z,-327.68,65535,1,moderate\r\n` is 47 characters.
+const CSV_ROW_LENGTH: usize = 48;
+
+const CSV_HEADER: &str = "timestamp,temperature,gas,flame,risk\r\n";
+
+/// History as CSV, streamed in chunks of [`CSV_PAGE_LENGTH`] rows.
+///
+/// Raw rows come from the flash log, which has the risk of every sample, or from the
+/// samples in RAM with an empty risk if the log did not open. Minute and hour rows come
+/// from the aggregates in RAM, with the average temperature and gas, a flame if any sample
+/// saw one, and an empty risk.
+pub struct HistoryCsv(pub HistoryQuery);
+
+impl Chunks for HistoryCsv {
+    fn content_type(&self) -> &'static str {
+        "text/csv"
+    }
+
+    async fn write_chunks<W: Write>(
+        self,
+        mut chunk_writer: ChunkWriter<W>,
+    ) -> Result<ChunksWritten, W::Error> {
+        let query = self.0;
+
+        chunk_writer.write_chunk(CSV_HEADER.as_bytes()).await?;
+
+        let log_available = history_log::is_available().await;
+
+        match query.resolution() {
+            Resolution::Raw if log_available => {
+                let mut cursor = None;
+
+                loop {
+                    let (entries, next) =
+                        history_log::read_page::<CSV_PAGE_LENGTH>(cursor, |logged| {
+                            query.contains(logged.entry.timestamp)
+                        })
+                        .await;
+
+                    let mut chunk = String::<{ CSV_PAGE_LENGTH * CSV_ROW_LENGTH }>::new();
+                    for logged in entries.iter() {
+                        let entry = logged.entry;
+                        write_row(
+                            &mut chunk,
+                            entry.timestamp,
+                            entry.values.temp,
+                            entry.values.gas,
+                            entry.values.flame,
+                            Some(entry.risk),
+                        );
+                    }
+
+                    if !chunk.is_empty() {
+                        chunk_writer.write_chunk(chunk.as_bytes()).await?;
+                    }
+
+                    match next {
+                        Some(next) => cursor = Some(next),
+                        None => break,
+                    }
+                }
+            }
+            _ => {
+                let mut query = query;
+                // Points at `query.from` already sent with the previous page.
+                let mut sent = 0;
+
+                loop {
+                    let page = VALUE_HISTORY.lock().await.query::<CSV_PAGE_LENGTH>(&query);
+
+                    let mut chunk = String::<{ CSV_PAGE_LENGTH * CSV_ROW_LENGTH }>::new();
+                    for point in page.points.iter().skip(sent) {
+                        write_row(
+                            &mut chunk,
+                            point.timestamp,
+                            point.temp.avg,
+                            point.gas.avg,
+                            point.flame > 0,
+                            None,
+                        );
+                    }
+
+                    if !chunk.is_empty() {
+                        chunk_writer.write_chunk(chunk.as_bytes()).await?;
+                    }
+
+                    // Raw samples can share a second, so the next page starts at the second
+                    // of the last point and skips the ones sent at it.
+                    let Some(last) = page.points.last().filter(|_| page.points.is_full()) else {
+                        break;
+                    };
+                    let secs = last.timestamp.secs;
+                    sent = page
+                        .points
+                        .iter()
+                        .filter(|point| point.timestamp.secs == secs)
+                        .count();
+
+                    // A full page within one second can not be skipped past by count, so
+                    // the rest of that second is left out and the next one follows.
+                    if sent == page.points.len() {
+                        let Some(next_secs) = secs.checked_add(1) else {
+                            break;
+                        };
+                        query.from = Some(next_secs);
+                        sent = 0;
+                    } else {
+                        query.from = Some(secs);
+                    }
+                }
+            }
+        }
+
+        chunk_writer.finalize().await
+    }
+}
+
+fn write_row<const N: usize>(
+    chunk: &mut String<N>,
+    timestamp: Timestamp,
+    temp: Temperature,
+    gas: u16,
+    flame: bool,
+    risk: Option<Risk>,
+) {
+    let risk = risk.as_ref().map(Risk::name).unwrap_or("");
+
+    uwrite!(
+        chunk,
+        "{},{},{},{},{}\r\n",
+        timestamp,
+        temp,
+        gas,
+        flame as u8,
+        risk
+    )
+    .unwrap();
+}
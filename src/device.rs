@@ -0,0 +1,98 @@
+//! Identity of the device on the network: an ID from the MAC and a name usable as a DNS
+//! label, which defaults to one derived from the ID.
+
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
+use heapless::String;
+use serde::{Deserialize, Serialize};
+
+use crate::network;
+use crate::settings::{self, SettingsError};
+
+pub const FIRMWARE_VERSION: &str = env!("CARGO_PKG_VERSION");
+pub const MAX_DEVICE_NAME_LENGTH: usize = 32;
+
+/// The MAC as 12 lowercase hex digits.
+pub type DeviceId = String<12>;
+pub type DeviceName = String<MAX_DEVICE_NAME_LENGTH>;
+
+const DEFAULT_NAME_PREFIX: &str = "esp-sensor-";
+
+/// Signaled when the name changes, so it gets announced again.
+pub static NAME_CHANGED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Answer of `GET /device`.
+#[derive(Debug, Clone, Serialize)]
+pub struct DeviceInfo {
+    pub id: DeviceId,
+    pub name: DeviceName,
+    pub firmware: &'static str,
+}
+
+/// Body of `POST /device`, a missing name goes back to the default one.
+#[derive(Debug, Deserialize)]
+pub struct DeviceUpdate {
+    pub name: Option<DeviceName>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceUpdateError {
+    /// Not a DNS label of lowercase letters, digits and inner hyphens.
+    InvalidName,
+    Settings(SettingsError),
+}
+
+pub fn device_id() -> DeviceId {
+    let mut id = String::new();
+
+    for byte in network::mac().unwrap_or_default() {
+        for nibble in [byte >> 4, byte & 0x0F] {
+            id.push(char::from_digit(nibble as u32, 16).unwrap()).unwrap();
+        }
+    }
+
+    id
+}
+
+/// The stored name, or `esp-sensor-` followed by the last six digits of the ID.
+pub async fn device_name() -> DeviceName {
+    if let Some(Some(name)) = settings::read(|settings| settings.device_name.clone()).await {
+        return name;
+    }
+
+    let id = device_id();
+    let mut name = String::try_from(DEFAULT_NAME_PREFIX).unwrap();
+    name.push_str(&id[id.len() - 6..]).unwrap();
+
+    name
+}
+
+pub async fn device_info() -> DeviceInfo {
+    DeviceInfo {
+        id: device_id(),
+        name: device_name().await,
+        firmware: FIRMWARE_VERSION,
+    }
+}
+
+pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with('-')
+        && !name.ends_with('-')
+        && name
+            .bytes()
+            .all(|byte| byte.is_ascii_lowercase() || byte.is_ascii_digit() || byte == b'-')
+}
+
+pub async fn update(update: DeviceUpdate) -> Result<(), DeviceUpdateError> {
+    if update.name.as_deref().is_some_and(|name| !is_valid_name(name)) {
+        return Err(DeviceUpdateError::InvalidName);
+    }
+
+    settings::update(|settings| settings.device_name = update.name)
+        .await
+        .map_err(DeviceUpdateError::Settings)?;
+
+    NAME_CHANGED.signal(());
+
+    Ok(())
+}
@@ -0,0 +1,95 @@
+use embassy_futures::select::{select, Either};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::pubsub::{PubSubChannel, Subscriber, WaitResult};
+use embassy_time::{Duration, Timer};
+use esp_println::println;
+use heapless::String;
+use picoserve::io::Write;
+use picoserve::response::sse::{EventSource, EventWriter};
+
+use crate::app::{Config, Risk, SensorValues};
+
+/// Concurrent event streams and dashboard sockets, each one keeps a web task busy for as
+/// long as it is open.
+pub const MAX_EVENT_STREAMS: usize = 2;
+/// Events a slow stream can fall behind before it misses some.
+const EVENT_QUEUE_LENGTH: usize = 4;
+/// Comment sent when nothing happened for a while, so proxies keep the connection open.
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(15);
+/// Fits the JSON of readings with all extra channels.
+pub const EVENT_DATA_LENGTH: usize = 384;
+
+#[derive(Debug, Clone)]
+pub enum Event {
+    Readings(SensorValues),
+    Risk(Risk),
+    Config(Config),
+}
+
+type EventChannel =
+    PubSubChannel<CriticalSectionRawMutex, Event, EVENT_QUEUE_LENGTH, MAX_EVENT_STREAMS, 0>;
+
+pub type EventSubscriber =
+    Subscriber<'static, CriticalSectionRawMutex, Event, EVENT_QUEUE_LENGTH, MAX_EVENT_STREAMS, 0>;
+
+static EVENTS: EventChannel = PubSubChannel::new();
+
+/// Publishes to every open stream, dropping the oldest queued event of streams that fell
+/// behind.
+pub fn publish(event: Event) {
+    EVENTS.immediate_publisher().publish_immediate(event);
+}
+
+/// `None` once [`MAX_EVENT_STREAMS`] streams are open.
+pub fn subscribe() -> Option<EventSubscriber> {
+    EVENTS.subscriber().ok()
+}
+
+/// Server-Sent Events stream of the readings, risk changes and config changes, as JSON.
+pub struct LiveEvents(pub EventSubscriber);
+
+impl EventSource for LiveEvents {
+    async fn write_events<W: Write>(
+        mut self,
+        mut writer: EventWriter<'_, W>,
+    ) -> Result<(), W::Error> {
+        loop {
+            let next_event = select(self.0.next_message(), Timer::after(KEEPALIVE_INTERVAL));
+
+            let event = match next_event.await {
+                Either::First(WaitResult::Message(event)) => event,
+                Either::First(WaitResult::Lagged(missed)) => {
+                    println!("Event stream lagged, {} events missed", missed);
+                    continue;
+                }
+                Either::Second(()) => {
+                    writer.write_keepalive().await?;
+                    continue;
+                }
+            };
+
+            writer
+                .write_event(event.name(), event.to_json().as_str())
+                .await?;
+        }
+    }
+}
+
+impl Event {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Event::Readings(_) => "readings",
+            Event::Risk(_) => "risk",
+            Event::Config(_) => "config",
+        }
+    }
+
+    pub fn to_json(&self) -> String<EVENT_DATA_LENGTH> {
+        match self {
+            Event::Readings(values) => serde_json_core::to_string(values),
+            Event::Risk(risk) => serde_json_core::to_string(risk),
+            Event::Config(config) => serde_json_core::to_string(config),
+        }
+        .unwrap()
+    }
+}
@@ -0,0 +1,187 @@
+use core::cell::Cell;
+
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_time::{Duration, Instant};
+use esp_hal::{
+    analog::adc::AdcPin,
+    gpio::{GpioPin, Input, InputConfig},
+    peripherals::ADC1,
+};
+
+use crate::adc::{self, SharedAdc};
+
+/// Pulses seen by [`flame_edge_task`], shared with [`FlameSensor::read`].
+static EDGE_STATE: Mutex<CriticalSectionRawMutex, Cell<EdgeState>> =
+    Mutex::new(Cell::new(EdgeState {
+        on_since: None,
+        reported: false,
+        latched: false,
+    }));
+
+#[derive(Clone, Copy)]
+struct EdgeState {
+    /// Start of the current pulse, `None` while the input is high.
+    on_since: Option<Instant>,
+    /// Set once the sensor task reported the current pulse while it lasted.
+    reported: bool,
+    /// Set when a pulse long enough to count ended before the sensor task reported it.
+    latched: bool,
+}
+
+/// How the flame module is wired.
+pub enum FlameInput {
+    /// Digital output sampled once per sensor loop.
+    Polled(GpioPin<19>),
+    /// Digital output watched by [`flame_edge_task`], so flashes between two polls still count.
+    EdgeCaptured,
+    /// Analog output on the shared ADC, the reading carries the flame intensity. The pin must
+    /// have been enabled with 11dB attenuation on the config the ADC was created from.
+    Analog(AdcPin<GpioPin<35>, ADC1>),
+}
+
+#[derive(Clone, Copy)]
+pub struct FlameConfig {
+    /// Time the flame has to be seen continuously before it is reported.
+    pub min_on_time: Duration,
+    /// Intensity (0-4095) from which an analog module reports a flame.
+    pub analog_threshold: u16,
+}
+
+impl Default for FlameConfig {
+    fn default() -> Self {
+        Self {
+            min_on_time: Duration::from_millis(250),
+            analog_threshold: 1500,
+        }
+    }
+}
+
+pub struct FlameReading {
+    pub detected: bool,
+    /// Only reported by analog modules.
+    pub intensity: Option<u16>,
+}
+
+enum Source<'a> {
+    Polled(Input<'a>),
+    EdgeCaptured,
+    Analog {
+        adc: &'a SharedAdc,
+        pin: AdcPin<GpioPin<35>, ADC1>,
+    },
+}
+
+pub struct FlameSensor<'a> {
+    source: Source<'a>,
+    config: FlameConfig,
+    on_since: Option<Instant>,
+}
+
+impl<'a> FlameSensor<'a> {
+    pub fn polled(pin: GpioPin<19>, config: FlameConfig) -> Self {
+        let input = Input::new(pin, InputConfig::default());
+
+        Self::with_source(Source::Polled(input), config)
+    }
+
+    /// Reads the state recorded by [`flame_edge_task`], which must be running on the pin.
+    pub fn edge_captured(config: FlameConfig) -> Self {
+        Self::with_source(Source::EdgeCaptured, config)
+    }
+
+    pub fn analog(
+        adc: &'a SharedAdc,
+        pin: AdcPin<GpioPin<35>, ADC1>,
+        config: FlameConfig,
+    ) -> Self {
+        Self::with_source(Source::Analog { adc, pin }, config)
+    }
+
+    fn with_source(source: Source<'a>, config: FlameConfig) -> Self {
+        Self {
+            source,
+            config,
+            on_since: None,
+        }
+    }
+
+    pub async fn read(&mut self) -> FlameReading {
+        match &mut self.source {
+            Source::Polled(input) => {
+                let active = input.is_low();
+
+                FlameReading {
+                    detected: self.debounce(active),
+                    intensity: None,
+                }
+            }
+            Source::EdgeCaptured => {
+                let detected = EDGE_STATE.lock(|state| {
+                    let mut edge = state.get();
+                    let sustained = edge
+                        .on_since
+                        .is_some_and(|since| since.elapsed() >= self.config.min_on_time);
+                    let detected = sustained || edge.latched;
+
+                    edge.reported |= sustained;
+                    edge.latched = false;
+                    state.set(edge);
+
+                    detected
+                });
+
+                FlameReading {
+                    detected,
+                    intensity: None,
+                }
+            }
+            Source::Analog { adc, pin } => {
+                let intensity = 4095 - adc::read_oneshot(adc, pin).await;
+                let active = intensity >= self.config.analog_threshold;
+
+                FlameReading {
+                    detected: self.debounce(active),
+                    intensity: Some(intensity),
+                }
+            }
+        }
+    }
+
+    fn debounce(&mut self, active: bool) -> bool {
+        if !active {
+            self.on_since = None;
+            return false;
+        }
+
+        let since = *self.on_since.get_or_insert_with(Instant::now);
+
+        since.elapsed() >= self.config.min_on_time
+    }
+}
+
+/// Timestamps every edge of the digital flame output from the GPIO interrupt, so that
+/// pulses shorter than the sensor loop period but longer than `min_on_time` are kept
+/// until [`FlameSensor::read`] picks them up. Pulses it already reported are not kept.
+#[embassy_executor::task]
+pub async fn flame_edge_task(pin: GpioPin<19>, min_on_time: Duration) {
+    let mut input = Input::new(pin, InputConfig::default());
+
+    loop {
+        input.wait_for_low().await;
+        let start = Instant::now();
+        EDGE_STATE.lock(|state| {
+            let mut edge = state.get();
+            edge.on_since = Some(start);
+            edge.reported = false;
+            state.set(edge);
+        });
+
+        input.wait_for_high().await;
+        EDGE_STATE.lock(|state| {
+            let mut edge = state.get();
+            edge.on_since = None;
+            edge.latched |= !edge.reported && start.elapsed() >= min_on_time;
+            state.set(edge);
+        });
+    }
+}
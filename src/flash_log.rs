@@ -0,0 +1,454 @@
+//! Append-only log of small records on a region of NOR flash.
+//!
+//! The region is split into erase sectors of fixed size record slots. Records are written
+//! one after the other, moving into the next sector (and erasing it) once a sector is
+//! full, so every sector is erased equally often and the oldest sector is dropped first.
+//!
+//! | offset          | size | field                                     |
+//! |-----------------|------|-------------------------------------------|
+//! | 0               | 4    | sequence number, little endian            |
+//! | 4               | 1    | payload length `n`                        |
+//! | 5               | n    | payload                                   |
+//! | RECORD_SIZE - 2 | 2    | CRC-16/CCITT-FALSE of bytes `0..5 + n`    |
+//!
+//! Slots with a bad CRC, e.g. torn by a reset during the write, are skipped. On open the
+//! log finds the record with the highest sequence number and continues after it.
+//!
+//! This module only depends on `embedded-storage`, `heapless` and [`crate::protocol`],
+//! so the `host-tests` crate runs its tests on the host over a RAM backed `NorFlash`.
+
+use embedded_storage::nor_flash::{NorFlash, NorFlashError, NorFlashErrorKind};
+use heapless::Vec;
+
+use crate::protocol::crc16;
+
+pub const RECORD_SIZE: usize = 64;
+const HEADER_SIZE: usize = 5;
+const CRC_SIZE: usize = 2;
+pub const MAX_PAYLOAD: usize = RECORD_SIZE - HEADER_SIZE - CRC_SIZE;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    pub seq: u32,
+    pub payload: Vec<u8, MAX_PAYLOAD>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogError {
+    Flash(NorFlashErrorKind),
+    /// The region or the record size do not line up with the flash geometry.
+    Misaligned,
+    PayloadTooLarge(usize),
+}
+
+enum Slot {
+    Erased,
+    Corrupt,
+    Valid(Record),
+}
+
+pub struct FlashLog<F> {
+    flash: F,
+    offset: u32,
+    sectors: u32,
+    next_slot: u32,
+    next_seq: u32,
+}
+
+impl<F: NorFlash> FlashLog<F> {
+    const SLOTS_PER_SECTOR: u32 = (F::ERASE_SIZE / RECORD_SIZE) as u32;
+
+    /// Opens the log kept in `sectors` erase sectors starting at `offset`.
+    pub fn open(flash: F, offset: u32, sectors: u32) -> Result<Self, LogError> {
+        if offset as usize % F::ERASE_SIZE != 0
+            || F::ERASE_SIZE % RECORD_SIZE != 0
+            || RECORD_SIZE % F::WRITE_SIZE != 0
+            || sectors < 2
+        {
+            return Err(LogError::Misaligned);
+        }
+
+        let mut log = Self {
+            flash,
+            offset,
+            sectors,
+            next_slot: 0,
+            next_seq: 0,
+        };
+
+        let mut newest: Option<(u32, u32)> = None;
+        for slot in 0..log.slot_count() {
+            if let Slot::Valid(record) = log.read_slot(slot)? {
+                if newest.is_none_or(|(_, seq)| record.seq > seq) {
+                    newest = Some((slot, record.seq));
+                }
+            }
+        }
+
+        if let Some((slot, seq)) = newest {
+            log.next_seq = seq + 1;
+            log.next_slot = log.first_free_after(slot)?;
+        }
+
+        Ok(log)
+    }
+
+    /// Sequence number the next appended record gets.
+    pub fn next_seq(&self) -> u32 {
+        self.next_seq
+    }
+
+    /// Appends a record, returning its sequence number.
+    pub fn append(&mut self, payload: &[u8]) -> Result<u32, LogError> {
+        if payload.len() > MAX_PAYLOAD {
+            return Err(LogError::PayloadTooLarge(payload.len()));
+        }
+
+        if self.next_slot % Self::SLOTS_PER_SECTOR == 0 {
+            let from = self.slot_address(self.next_slot);
+            self.flash
+                .erase(from, from + F::ERASE_SIZE as u32)
+                .map_err(flash_error)?;
+        }
+
+        let seq = self.next_seq;
+        let mut bytes = [0xFF; RECORD_SIZE];
+        bytes[..4].copy_from_slice(&seq.to_le_bytes());
+        bytes[4] = payload.len() as u8;
+        bytes[HEADER_SIZE..HEADER_SIZE + payload.len()].copy_from_slice(payload);
+        let crc = crc16(&bytes[..HEADER_SIZE + payload.len()]);
+        bytes[RECORD_SIZE - CRC_SIZE..].copy_from_slice(&crc.to_le_bytes());
+
+        self.flash
+            .write(self.slot_address(self.next_slot), &bytes)
+            .map_err(flash_error)?;
+
+        self.next_seq += 1;
+        self.next_slot = (self.next_slot + 1) % self.slot_count();
+
+        Ok(seq)
+    }
+
+    /// Valid records from the oldest to the newest.
+    pub fn records(&mut self) -> Records<'_, F> {
+        let slot_count = self.slot_count();
+        // The oldest records are in the sector after the current one, which is the one at
+        // `next_slot` itself while it is on a sector boundary and not erased yet.
+        let oldest = self.next_slot.next_multiple_of(Self::SLOTS_PER_SECTOR) % slot_count;
+        let remaining = match (self.next_slot + slot_count - oldest) % slot_count {
+            0 => slot_count,
+            remaining => remaining,
+        };
+
+        Records {
+            slot: oldest,
+            remaining,
+            log: self,
+        }
+    }
+
+    /// Valid records from `slot` on, as returned by [`Records::position`], to the newest.
+    pub fn records_from(&mut self, slot: u32) -> Records<'_, F> {
+        let remaining = (self.next_slot + self.slot_count() - slot) % self.slot_count();
+
+        Records {
+            slot,
+            remaining,
+            log: self,
+        }
+    }
+
+    fn slot_count(&self) -> u32 {
+        self.sectors * Self::SLOTS_PER_SECTOR
+    }
+
+    fn slot_address(&self, slot: u32) -> u32 {
+        self.offset + slot * RECORD_SIZE as u32
+    }
+
+    /// First erased slot after `slot` in its sector, or the start of the next sector.
+    fn first_free_after(&mut self, slot: u32) -> Result<u32, LogError> {
+        let sector_end = (slot / Self::SLOTS_PER_SECTOR + 1) * Self::SLOTS_PER_SECTOR;
+
+        for free in slot + 1..sector_end {
+            if let Slot::Erased = self.read_slot(free)? {
+                return Ok(free);
+            }
+        }
+
+        Ok(sector_end % self.slot_count())
+    }
+
+    fn read_slot(&mut self, slot: u32) -> Result<Slot, LogError> {
+        let mut bytes = [0; RECORD_SIZE];
+        self.flash
+            .read(self.slot_address(slot), &mut bytes)
+            .map_err(flash_error)?;
+
+        if bytes.iter().all(|byte| *byte == 0xFF) {
+            return Ok(Slot::Erased);
+        }
+
+        let length = bytes[4] as usize;
+        if length > MAX_PAYLOAD {
+            return Ok(Slot::Corrupt);
+        }
+
+        let crc = u16::from_le_bytes([bytes[RECORD_SIZE - 2], bytes[RECORD_SIZE - 1]]);
+        if crc != crc16(&bytes[..HEADER_SIZE + length]) {
+            return Ok(Slot::Corrupt);
+        }
+
+        Ok(Slot::Valid(Record {
+            seq: u32::from_le_bytes(bytes[..4].try_into().unwrap()),
+            payload: Vec::from_slice(&bytes[HEADER_SIZE..HEADER_SIZE + length]).unwrap(),
+        }))
+    }
+}
+
+pub struct Records<'a, F> {
+    log: &'a mut FlashLog<F>,
+    slot: u32,
+    remaining: u32,
+}
+
+impl<F> Records<'_, F> {
+    /// Slot the iteration continues at, to resume it later with [`FlashLog::records_from`].
+    pub fn position(&self) -> u32 {
+        self.slot
+    }
+
+    /// Slots left to read, including erased and corrupt ones.
+    pub fn remaining(&self) -> u32 {
+        self.remaining
+    }
+}
+
+impl<F: NorFlash> Iterator for Records<'_, F> {
+    type Item = Result<Record, LogError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.remaining > 0 {
+            let slot = self.slot;
+            self.slot = (self.slot + 1) % self.log.slot_count();
+            self.remaining -= 1;
+
+            match self.log.read_slot(slot) {
+                Ok(Slot::Valid(record)) => return Some(Ok(record)),
+                Ok(Slot::Erased | Slot::Corrupt) => continue,
+                Err(e) => return Some(Err(e)),
+            }
+        }
+
+        None
+    }
+}
+
+fn flash_error<E: NorFlashError>(error: E) -> LogError {
+    LogError::Flash(error.kind())
+}
+
+#[cfg(test)]
+mod tests {
+    use embedded_storage::nor_flash::{
+        check_erase, check_read, check_write, ErrorType, ReadNorFlash,
+    };
+
+    use super::*;
+
+    const SECTOR_SIZE: usize = 256;
+    const SECTORS: u32 = 3;
+    const SLOTS: u32 = SECTORS * (SECTOR_SIZE / RECORD_SIZE) as u32;
+
+    /// NOR flash in RAM, writes can only clear bits as on the real chip.
+    struct RamFlash([u8; SECTOR_SIZE * SECTORS as usize]);
+
+    impl RamFlash {
+        fn new() -> Self {
+            Self([0xFF; SECTOR_SIZE * SECTORS as usize])
+        }
+    }
+
+    impl ErrorType for RamFlash {
+        type Error = NorFlashErrorKind;
+    }
+
+    impl ReadNorFlash for RamFlash {
+        const READ_SIZE: usize = 1;
+
+        fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+            check_read(self, offset, bytes.len())?;
+            let offset = offset as usize;
+            bytes.copy_from_slice(&self.0[offset..offset + bytes.len()]);
+
+            Ok(())
+        }
+
+        fn capacity(&self) -> usize {
+            self.0.len()
+        }
+    }
+
+    impl NorFlash for RamFlash {
+        const WRITE_SIZE: usize = 4;
+        const ERASE_SIZE: usize = SECTOR_SIZE;
+
+        fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+            check_erase(self, from, to)?;
+            self.0[from as usize..to as usize].fill(0xFF);
+
+            Ok(())
+        }
+
+        fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+            check_write(self, offset, bytes.len())?;
+            for (cell, byte) in self.0[offset as usize..].iter_mut().zip(bytes) {
+                *cell &= byte;
+            }
+
+            Ok(())
+        }
+    }
+
+    fn append_all(log: &mut FlashLog<&mut RamFlash>, seqs: core::ops::Range<u32>) {
+        for seq in seqs {
+            assert_eq!(log.append(&seq.to_le_bytes()), Ok(seq));
+        }
+    }
+
+    /// Checks the log holds exactly the records `seqs`, oldest first, each with its
+    /// sequence number as payload.
+    fn assert_records(log: &mut FlashLog<&mut RamFlash>, seqs: core::ops::Range<u32>) {
+        let mut expected = seqs.clone();
+
+        for record in log.records() {
+            let record = record.unwrap();
+            assert_eq!(Some(record.seq), expected.next());
+            assert_eq!(record.payload, record.seq.to_le_bytes());
+        }
+
+        assert_eq!(expected.next(), None, "records missing from {:?}", seqs);
+    }
+
+    #[test]
+    fn reads_appended_records() {
+        let mut flash = RamFlash::new();
+        let mut log = FlashLog::open(&mut flash, 0, SECTORS).unwrap();
+
+        assert_records(&mut log, 0..0);
+        append_all(&mut log, 0..5);
+        assert_records(&mut log, 0..5);
+        assert_eq!(log.next_seq(), 5);
+    }
+
+    #[test]
+    fn continues_after_reopen() {
+        let mut flash = RamFlash::new();
+        append_all(&mut FlashLog::open(&mut flash, 0, SECTORS).unwrap(), 0..6);
+
+        let mut log = FlashLog::open(&mut flash, 0, SECTORS).unwrap();
+        assert_eq!(log.next_seq(), 6);
+        assert_records(&mut log, 0..6);
+
+        append_all(&mut log, 6..9);
+        assert_records(&mut log, 0..9);
+    }
+
+    #[test]
+    fn keeps_order_on_wraparound() {
+        let mut flash = RamFlash::new();
+        let mut log = FlashLog::open(&mut flash, 0, SECTORS).unwrap();
+        let per_sector = SLOTS / SECTORS;
+
+        // Full log, the next append erases the sector holding the oldest records.
+        append_all(&mut log, 0..SLOTS);
+        assert_records(&mut log, 0..SLOTS);
+
+        for count in SLOTS + 1..=4 * SLOTS {
+            append_all(&mut log, count - 1..count);
+
+            // The sector being filled and the full ones before it.
+            let kept = SLOTS - per_sector + (count - 1) % per_sector + 1;
+            assert_records(&mut log, count - kept..count);
+        }
+    }
+
+    #[test]
+    fn keeps_order_after_reopen_on_wraparound() {
+        let mut flash = RamFlash::new();
+        append_all(
+            &mut FlashLog::open(&mut flash, 0, SECTORS).unwrap(),
+            0..2 * SLOTS,
+        );
+
+        let mut log = FlashLog::open(&mut flash, 0, SECTORS).unwrap();
+        assert_eq!(log.next_seq(), 2 * SLOTS);
+        assert_records(&mut log, SLOTS..2 * SLOTS);
+    }
+
+    #[test]
+    fn resumes_from_position() {
+        let mut flash = RamFlash::new();
+        let mut log = FlashLog::open(&mut flash, 0, SECTORS).unwrap();
+        append_all(&mut log, 0..SLOTS + 2);
+
+        let mut records = log.records();
+        records.next();
+        records.next();
+        let position = records.position();
+
+        let seqs: heapless::Vec<u32, 16> = log
+            .records_from(position)
+            .map(|record| record.unwrap().seq)
+            .collect();
+        assert_eq!(seqs, [6, 7, 8, 9, 10, 11, 12, 13]);
+    }
+
+    #[test]
+    fn skips_records_with_bad_crc() {
+        let mut flash = RamFlash::new();
+        append_all(&mut FlashLog::open(&mut flash, 0, SECTORS).unwrap(), 0..3);
+
+        // Clears a payload bit of the middle record, as a torn write would.
+        flash.0[RECORD_SIZE + HEADER_SIZE] &= 0xFE;
+
+        let mut log = FlashLog::open(&mut flash, 0, SECTORS).unwrap();
+        let seqs: heapless::Vec<u32, 4> = log.records().map(|record| record.unwrap().seq).collect();
+        assert_eq!(seqs, [0, 2]);
+        assert_eq!(log.next_seq(), 3);
+    }
+
+    #[test]
+    fn ignores_corrupt_newest_record_on_reopen() {
+        let mut flash = RamFlash::new();
+        append_all(&mut FlashLog::open(&mut flash, 0, SECTORS).unwrap(), 0..3);
+
+        flash.0[2 * RECORD_SIZE + RECORD_SIZE - 1] = 0x00;
+
+        let mut log = FlashLog::open(&mut flash, 0, SECTORS).unwrap();
+        assert_eq!(log.next_seq(), 2);
+        assert_eq!(log.append(&[]), Ok(2));
+        let seqs: heapless::Vec<u32, 4> = log.records().map(|record| record.unwrap().seq).collect();
+        assert_eq!(seqs, [0, 1, 2]);
+    }
+
+    #[test]
+    fn rejects_large_payload_and_bad_geometry() {
+        let mut flash = RamFlash::new();
+
+        assert!(matches!(
+            FlashLog::open(&mut flash, RECORD_SIZE as u32, SECTORS),
+            Err(LogError::Misaligned)
+        ));
+        assert!(matches!(
+            FlashLog::open(&mut flash, 0, 1),
+            Err(LogError::Misaligned)
+        ));
+
+        let mut log = FlashLog::open(&mut flash, 0, SECTORS).unwrap();
+        assert_eq!(
+            log.append(&[0; MAX_PAYLOAD + 1]),
+            Err(LogError::PayloadTooLarge(MAX_PAYLOAD + 1))
+        );
+    }
+}
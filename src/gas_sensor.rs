@@ -1,44 +1,41 @@
-use embassy_time::Timer;
-use esp_hal::{
-    analog::adc::{Adc, AdcConfig, AdcPin},
-    gpio::GpioPin,
-    peripherals::ADC1,
-    Blocking,
-};
-use esp_println::println;
+use embassy_time::{Duration, Instant};
+use esp_hal::{analog::adc::AdcPin, gpio::GpioPin, peripherals::ADC1};
+
+use crate::adc::{self, SharedAdc};
 
 pub struct GasSensor<'a> {
-    //pin: GpioPin<34>,
-    adc: Adc<'a, ADC1, Blocking>,
+    adc: &'a SharedAdc,
     analog_pin: AdcPin<GpioPin<34>, ADC1>,
+    started: Instant,
 }
 
-impl<'a> GasSensor<'_> {
-    pub fn new(adc: ADC1, pin: GpioPin<34>) -> Self {
-        let mut adc_config = AdcConfig::default();
-
-        let analog_pin = adc_config.enable_pin(pin, esp_hal::analog::adc::Attenuation::_11dB);
-
-        let adc = Adc::new(adc, adc_config);
-
-        Self { adc, analog_pin }
+impl<'a> GasSensor<'a> {
+    /// Creates the gas sensor, the heater warm-up counts from here.
+    ///
+    /// `analog_pin` must have been enabled with 11dB attenuation on the config the shared
+    /// ADC was created from.
+    pub fn new(adc: &'a SharedAdc, analog_pin: AdcPin<GpioPin<34>, ADC1>) -> Self {
+        Self {
+            adc,
+            analog_pin,
+            started: Instant::now(),
+        }
     }
 
-    pub async fn get_value(&mut self) -> u16 {
-        let value;
-        loop {
-            let val_err = self.adc.read_oneshot(&mut self.analog_pin);
-
-            if let Err(err) = val_err {
-                println!("Error in gas sensor get_value {err:?}");
-                Timer::after_millis(50).await;
-                continue;
-            }
+    /// Time left of a `warmup` long heater warm-up, `None` once the readings can be
+    /// trusted.
+    pub fn warmup_remaining(&self, warmup: Duration) -> Option<Duration> {
+        let warmup_until = self.started + warmup;
+        let now = Instant::now();
 
-            value = 4095 - val_err.unwrap();
-            break;
+        if now < warmup_until {
+            Some(warmup_until - now)
+        } else {
+            None
         }
+    }
 
-        value
+    pub async fn get_value(&mut self) -> u16 {
+        4095 - adc::read_oneshot(self.adc, &mut self.analog_pin).await
     }
 }
@@ -0,0 +1,481 @@
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::mutex::Mutex;
+use heapless::Vec;
+use serde::{Deserialize, Serialize};
+
+use crate::app::SensorValues;
+use crate::clock::Timestamp;
+use crate::sensor_registry::{Readings, MAX_CHANNELS};
+use crate::utils::Temperature;
+
+/// Raw samples kept, one every `data_point_interval` readings.
+pub const RAW_HISTORY_LENGTH: usize = 120;
+/// One-minute buckets, covering the last day.
+pub const MINUTE_BUCKETS: usize = 24 * 60;
+/// One-hour buckets, covering the last week.
+pub const HOUR_BUCKETS: usize = 7 * 24;
+/// Points returned by one query, longer ranges are paged through with `from`.
+pub const MAX_QUERY_POINTS: usize = 60;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Resolution {
+    Raw,
+    Minute,
+    Hour,
+}
+
+impl Resolution {
+    pub fn to_byte(&self) -> u8 {
+        match self {
+            Resolution::Raw => 0,
+            Resolution::Minute => 1,
+            Resolution::Hour => 2,
+        }
+    }
+
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0 => Some(Resolution::Raw),
+            1 => Some(Resolution::Minute),
+            2 => Some(Resolution::Hour),
+            _ => None,
+        }
+    }
+}
+
+/// Query of a history range, from the HTTP query string or the MQTT `history/get`
+/// payload. Missing bounds are open and the resolution defaults to raw.
+#[derive(Debug, Default, Clone, Copy, Deserialize)]
+pub struct HistoryQuery {
+    pub resolution: Option<Resolution>,
+    pub from: Option<u32>,
+    pub to: Option<u32>,
+}
+
+impl HistoryQuery {
+    /// Parses `u8` resolution, `u32` from and `u32` to, all optional from the end.
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let secs = |offset: usize| {
+            bytes
+                .get(offset..offset + 4)
+                .map(|secs| u32::from_le_bytes(secs.try_into().unwrap()))
+        };
+
+        let resolution = match bytes.first() {
+            Some(byte) => Some(Resolution::from_byte(*byte)?),
+            None => None,
+        };
+
+        Some(Self {
+            resolution,
+            from: secs(1),
+            to: secs(5),
+        })
+    }
+
+    pub fn resolution(&self) -> Resolution {
+        self.resolution.unwrap_or(Resolution::Raw)
+    }
+
+    /// Whether `timestamp` lies between `from` and `to`, both inclusive.
+    pub fn contains(&self, timestamp: Timestamp) -> bool {
+        self.from.is_none_or(|from| timestamp.secs >= from)
+            && self.to.is_none_or(|to| timestamp.secs <= to)
+    }
+}
+
+/// Raw sample as pushed by the sensor pipeline.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct HistoryEntry {
+    pub timestamp: Timestamp,
+    pub values: SensorValues,
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct Stat<T> {
+    pub min: T,
+    pub avg: T,
+    pub max: T,
+}
+
+impl<T: Copy> Stat<T> {
+    fn single(value: T) -> Self {
+        Self {
+            min: value,
+            avg: value,
+            max: value,
+        }
+    }
+}
+
+/// A raw sample or an aggregated bucket, as returned by [`HistoryStore::query`].
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct HistoryPoint {
+    /// Time of the sample, or start of the bucket.
+    pub timestamp: Timestamp,
+    pub temp: Stat<Temperature>,
+    pub gas: Stat<u16>,
+    /// Samples in which a flame was detected.
+    pub flame: u16,
+    pub samples: u16,
+    /// Additional channels, only kept for raw samples.
+    pub extra: Readings,
+}
+
+/// Capacity of [`HistoryPoint::to_bytes`].
+pub const HISTORY_POINT_BYTES_LENGTH: usize = 5 + 6 + 6 + 2 + 2 + MAX_CHANNELS * 6;
+
+/// Capacity of the history payload holding `points` points.
+pub const fn history_bytes_length(points: usize) -> usize {
+    2 + (HISTORY_POINT_BYTES_LENGTH + 1) * points
+}
+
+impl HistoryPoint {
+    fn from_entry(entry: &HistoryEntry) -> Self {
+        Self {
+            timestamp: entry.timestamp.resolve(),
+            temp: Stat::single(entry.values.temp),
+            gas: Stat::single(entry.values.gas),
+            flame: entry.values.flame as u16,
+            samples: 1,
+            extra: entry.values.extra,
+        }
+    }
+
+    /// Timestamp, temperature and gas as min, avg and max, flame samples, samples and
+    /// the extra channels, see [`crate::protocol`].
+    pub fn to_bytes(&self) -> Vec<u8, HISTORY_POINT_BYTES_LENGTH> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&self.timestamp.to_bytes()).unwrap();
+
+        for temp in [self.temp.min, self.temp.avg, self.temp.max] {
+            bytes.extend_from_slice(&temp.to_le_bytes()).unwrap();
+        }
+
+        for gas in [self.gas.min, self.gas.avg, self.gas.max] {
+            bytes.extend_from_slice(&gas.to_le_bytes()).unwrap();
+        }
+
+        bytes.extend_from_slice(&self.flame.to_le_bytes()).unwrap();
+        bytes.extend_from_slice(&self.samples.to_le_bytes()).unwrap();
+
+        for reading in self.extra.iter() {
+            bytes
+                .extend_from_slice(&[reading.channel, reading.kind.to_byte()])
+                .unwrap();
+            bytes
+                .extend_from_slice(&reading.value.to_le_bytes())
+                .unwrap();
+        }
+
+        bytes
+    }
+}
+
+/// Result of [`HistoryStore::query`].
+#[derive(Debug, Clone, Serialize)]
+pub struct HistoryPage<const N: usize> {
+    pub resolution: Resolution,
+    pub points: Vec<HistoryPoint, N>,
+}
+
+impl<const N: usize> HistoryPage<N> {
+    /// History payload of the framed protocol, see [`crate::protocol`]. `M` has to hold
+    /// [`history_bytes_length`] of `N`.
+    pub fn to_bytes<const M: usize>(&self) -> Vec<u8, M> {
+        let mut bytes = Vec::new();
+        bytes.push(self.resolution.to_byte()).unwrap();
+        bytes.push(self.points.len() as u8).unwrap();
+
+        for point in self.points.iter() {
+            let point_bytes = point.to_bytes();
+            bytes.push(point_bytes.len() as u8).unwrap();
+            bytes.extend_from_slice(&point_bytes).unwrap();
+        }
+
+        bytes
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Aggregate {
+    start: Timestamp,
+    temp: Stat<Temperature>,
+    gas: Stat<u16>,
+    flame: u16,
+    samples: u16,
+}
+
+impl Aggregate {
+    const EMPTY: Self = Self {
+        start: Timestamp {
+            secs: 0,
+            utc: false,
+        },
+        temp: Stat {
+            min: Temperature(0),
+            avg: Temperature(0),
+            max: Temperature(0),
+        },
+        gas: Stat {
+            min: 0,
+            avg: 0,
+            max: 0,
+        },
+        flame: 0,
+        samples: 0,
+    };
+
+    fn to_point(self) -> HistoryPoint {
+        HistoryPoint {
+            timestamp: self.start.resolve(),
+            temp: self.temp,
+            gas: self.gas,
+            flame: self.flame,
+            samples: self.samples,
+            extra: Readings::new(),
+        }
+    }
+}
+
+/// Running min, max and sum of the samples falling in one bucket.
+#[derive(Clone, Copy)]
+struct Accumulator {
+    start: Timestamp,
+    bucket_secs: u32,
+    temp_min: Temperature,
+    temp_max: Temperature,
+    temp_sum: i32,
+    gas_min: u16,
+    gas_max: u16,
+    gas_sum: u32,
+    flame: u16,
+    samples: u16,
+}
+
+impl Accumulator {
+    fn new(start: Timestamp, bucket_secs: u32) -> Self {
+        Self {
+            start: Timestamp {
+                secs: start.secs - start.secs % bucket_secs,
+                utc: start.utc,
+            },
+            bucket_secs,
+            temp_min: Temperature(i16::MAX),
+            temp_max: Temperature(i16::MIN),
+            temp_sum: 0,
+            gas_min: u16::MAX,
+            gas_max: 0,
+            gas_sum: 0,
+            flame: 0,
+            samples: 0,
+        }
+    }
+
+    fn contains(&self, timestamp: Timestamp) -> bool {
+        timestamp.utc == self.start.utc
+            && timestamp.secs >= self.start.secs
+            && timestamp.secs - self.start.secs < self.bucket_secs
+    }
+
+    fn add(&mut self, temp: Stat<Temperature>, gas: Stat<u16>, flame: u16, samples: u16) {
+        self.temp_min = self.temp_min.min(temp.min);
+        self.temp_max = self.temp_max.max(temp.max);
+        self.temp_sum += temp.avg.0 as i32 * samples as i32;
+        self.gas_min = self.gas_min.min(gas.min);
+        self.gas_max = self.gas_max.max(gas.max);
+        self.gas_sum += gas.avg as u32 * samples as u32;
+        self.flame = self.flame.saturating_add(flame);
+        self.samples = self.samples.saturating_add(samples);
+    }
+
+    fn finish(&self) -> Aggregate {
+        let samples = self.samples.max(1);
+
+        Aggregate {
+            start: self.start,
+            temp: Stat {
+                min: self.temp_min,
+                avg: Temperature((self.temp_sum / samples as i32) as i16),
+                max: self.temp_max,
+            },
+            gas: Stat {
+                min: self.gas_min,
+                avg: (self.gas_sum / samples as u32) as u16,
+                max: self.gas_max,
+            },
+            flame: self.flame,
+            samples: self.samples,
+        }
+    }
+}
+
+/// Fixed capacity ring buffer overwriting its oldest value.
+pub struct Ring<T: Copy, const N: usize> {
+    values: [T; N],
+    next: usize,
+    len: usize,
+}
+
+impl<T: Copy, const N: usize> Ring<T, N> {
+    pub const fn new(fill: T) -> Self {
+        Self {
+            values: [fill; N],
+            next: 0,
+            len: 0,
+        }
+    }
+
+    pub fn push(&mut self, value: T) {
+        self.values[self.next] = value;
+        self.next = (self.next + 1) % N;
+        self.len = (self.len + 1).min(N);
+    }
+
+    pub fn latest(&self) -> Option<&T> {
+        if self.len == 0 {
+            return None;
+        }
+
+        Some(&self.values[(self.next + N - 1) % N])
+    }
+
+    /// Values from the oldest to the newest.
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        let start = (self.next + N - self.len) % N;
+
+        (0..self.len).map(move |i| &self.values[(start + i) % N])
+    }
+}
+
+/// Multi-resolution sensor history: raw samples for the last minutes, one-minute
+/// buckets for the last day and one-hour buckets for the last week.
+///
+/// With the default sizes the store takes about 40 KiB of static RAM, which keeps it out
+/// of the 72 KiB heap the Wi-Fi driver allocates from.
+pub struct HistoryStore {
+    raw: Ring<HistoryEntry, RAW_HISTORY_LENGTH>,
+    minutes: Ring<Aggregate, MINUTE_BUCKETS>,
+    hours: Ring<Aggregate, HOUR_BUCKETS>,
+    minute: Option<Accumulator>,
+    hour: Option<Accumulator>,
+    new_change: bool,
+}
+
+impl HistoryStore {
+    const fn new() -> Self {
+        Self {
+            raw: Ring::new(HistoryEntry {
+                timestamp: Timestamp {
+                    secs: 0,
+                    utc: false,
+                },
+                values: SensorValues::EMPTY,
+            }),
+            minutes: Ring::new(Aggregate::EMPTY),
+            hours: Ring::new(Aggregate::EMPTY),
+            minute: None,
+            hour: None,
+            new_change: true,
+        }
+    }
+
+    /// Stores a raw sample.
+    pub fn push_values(&mut self, timestamp: Timestamp, sensor_values: SensorValues) {
+        self.new_change = true;
+        self.raw.push(HistoryEntry {
+            timestamp,
+            values: sensor_values,
+        });
+    }
+
+    /// Feeds a reading into the minute and hour buckets, meant to be called for every
+    /// reading so spikes between raw samples still show up in the min and max.
+    pub fn aggregate(&mut self, timestamp: Timestamp, sensor_values: &SensorValues) {
+        // Buckets line up with wall clock minutes and hours once the clock is synced.
+        let timestamp = timestamp.resolve();
+
+        if let Some(minute) = self.minute.filter(|minute| !minute.contains(timestamp)) {
+            let finished = minute.finish();
+            self.minutes.push(finished);
+            self.aggregate_hour(finished);
+            self.minute = None;
+        }
+
+        self.minute
+            .get_or_insert_with(|| Accumulator::new(timestamp, 60))
+            .add(
+                Stat::single(sensor_values.temp),
+                Stat::single(sensor_values.gas),
+                sensor_values.flame as u16,
+                1,
+            );
+    }
+
+    fn aggregate_hour(&mut self, minute: Aggregate) {
+        if let Some(hour) = self.hour.filter(|hour| !hour.contains(minute.start)) {
+            self.hours.push(hour.finish());
+            self.hour = None;
+        }
+
+        self.hour
+            .get_or_insert_with(|| Accumulator::new(minute.start, 60 * 60))
+            .add(minute.temp, minute.gas, minute.flame, minute.samples);
+    }
+
+    pub fn current_values(&self) -> Option<SensorValues> {
+        self.raw.latest().map(|entry| entry.values)
+    }
+
+    pub fn new_change(&mut self) -> bool {
+        if self.new_change {
+            self.new_change = false;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Up to `N` points between `from` and `to` (inclusive, in seconds of the resolved
+    /// timestamps), oldest first. The bucket still filling up is included.
+    pub fn query<const N: usize>(&self, query: &HistoryQuery) -> HistoryPage<N> {
+        let in_range = |point: &HistoryPoint| query.contains(point.timestamp);
+
+        let resolution = query.resolution();
+        let mut points = Vec::new();
+
+        match resolution {
+            Resolution::Raw => points.extend(
+                self.raw
+                    .iter()
+                    .map(HistoryPoint::from_entry)
+                    .filter(in_range)
+                    .take(N),
+            ),
+            Resolution::Minute => points.extend(
+                self.minutes
+                    .iter()
+                    .copied()
+                    .chain(self.minute.map(|minute| minute.finish()))
+                    .map(Aggregate::to_point)
+                    .filter(in_range)
+                    .take(N),
+            ),
+            Resolution::Hour => points.extend(
+                self.hours
+                    .iter()
+                    .copied()
+                    .chain(self.hour.map(|hour| hour.finish()))
+                    .map(Aggregate::to_point)
+                    .filter(in_range)
+                    .take(N),
+            ),
+        }
+
+        HistoryPage { resolution, points }
+    }
+}
+
+pub static VALUE_HISTORY: Mutex<CriticalSectionRawMutex, HistoryStore> =
+    Mutex::new(HistoryStore::new());
@@ -0,0 +1,261 @@
+use embassy_futures::yield_now;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::Channel;
+use embassy_sync::mutex::Mutex;
+use esp_println::println;
+use esp_storage::FlashStorage;
+use heapless::Vec;
+use serde::{Deserialize, Serialize};
+
+use crate::app::{Risk, SensorValues, SENSOR_BYTES_LENGTH};
+use crate::clock::Timestamp;
+use crate::flash_log::FlashLog;
+use crate::history::VALUE_HISTORY;
+
+/// Start of the log, the last 256 KiB of the 4 MiB flash, which the partition table must
+/// leave unused.
+pub const HISTORY_LOG_OFFSET: u32 = 0x3C_0000;
+/// 64 sectors of 64 records, which lasts about an hour with a record every second and
+/// erases each sector about 21 times a day.
+pub const HISTORY_LOG_SECTORS: u32 = 64;
+/// Records returned by one query, later ones are paged through with `from`.
+pub const MAX_LOG_RECORDS: usize = 20;
+/// Slots read by one [`read_page`] call at most, two sectors, so a filter rejecting
+/// everything does not hold the log for a scan of the whole flash.
+const MAX_SCANNED_SLOTS: u32 = 128;
+
+static HISTORY_LOG: Mutex<CriticalSectionRawMutex, Option<FlashLog<FlashStorage>>> =
+    Mutex::new(None);
+static LOG_CHANNEL: Channel<CriticalSectionRawMutex, LogEntry, 8> = Channel::new();
+
+/// Raw sample as persisted in flash, with the risk assessed for it.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct LogEntry {
+    pub timestamp: Timestamp,
+    pub risk: Risk,
+    pub values: SensorValues,
+}
+
+/// Capacity of [`LogEntry::to_bytes`].
+pub const LOG_ENTRY_BYTES_LENGTH: usize = 5 + 1 + SENSOR_BYTES_LENGTH;
+
+impl LogEntry {
+    /// Timestamp, risk and the readings payload.
+    pub fn to_bytes(&self) -> Vec<u8, LOG_ENTRY_BYTES_LENGTH> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&self.timestamp.to_bytes()).unwrap();
+        bytes.push(self.risk.to_byte()).unwrap();
+        bytes.extend_from_slice(&self.values.to_bytes()).unwrap();
+
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < 6 {
+            return None;
+        }
+
+        Some(Self {
+            timestamp: Timestamp {
+                utc: bytes[0] != 0,
+                secs: u32::from_le_bytes(bytes[1..5].try_into().unwrap()),
+            },
+            risk: Risk::from_byte(bytes[5])?,
+            values: SensorValues::from_bytes(&bytes[6..])?,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct LoggedEntry {
+    pub seq: u32,
+    pub entry: LogEntry,
+}
+
+/// Capacity of the log payload holding `records` records.
+pub const fn log_bytes_length(records: usize) -> usize {
+    1 + (1 + 4 + LOG_ENTRY_BYTES_LENGTH) * records
+}
+
+/// Log payload of the framed protocol, see [`crate::protocol`].
+pub fn log_to_bytes<const M: usize>(entries: &[LoggedEntry]) -> Vec<u8, M> {
+    let mut bytes = Vec::new();
+    bytes.push(entries.len() as u8).unwrap();
+
+    for logged in entries {
+        let entry_bytes = logged.entry.to_bytes();
+        bytes.push((4 + entry_bytes.len()) as u8).unwrap();
+        bytes.extend_from_slice(&logged.seq.to_le_bytes()).unwrap();
+        bytes.extend_from_slice(&entry_bytes).unwrap();
+    }
+
+    bytes
+}
+
+#[derive(Debug, Default, Clone, Copy, Deserialize)]
+pub struct LogQuery {
+    /// First sequence number returned.
+    pub from: Option<u32>,
+}
+
+impl LogQuery {
+    /// Parses an optional `u32` first sequence number.
+    pub fn from_bytes(bytes: &[u8]) -> Self {
+        Self {
+            from: bytes
+                .get(..4)
+                .map(|from| u32::from_le_bytes(from.try_into().unwrap())),
+        }
+    }
+}
+
+/// Opens the log and loads the samples of the previous boots into [`VALUE_HISTORY`],
+/// has to run before the sensor tasks start pushing.
+pub async fn init(flash: FlashStorage) {
+    let mut log = match FlashLog::open(flash, HISTORY_LOG_OFFSET, HISTORY_LOG_SECTORS) {
+        Ok(log) => log,
+        Err(e) => {
+            println!("Failed to open history log: {:?}", e);
+            return;
+        }
+    };
+
+    let mut value_history = VALUE_HISTORY.lock().await;
+    let mut restored = 0;
+
+    for record in log.records() {
+        let record = match record {
+            Ok(record) => record,
+            Err(e) => {
+                println!("Failed to read history log: {:?}", e);
+                break;
+            }
+        };
+
+        // Seconds since a previous boot can not be placed in time anymore.
+        let Some(entry) =
+            LogEntry::from_bytes(&record.payload).filter(|entry| entry.timestamp.utc)
+        else {
+            continue;
+        };
+
+        value_history.aggregate(entry.timestamp, &entry.values);
+        value_history.push_values(entry.timestamp, entry.values);
+        restored += 1;
+    }
+
+    println!("Restored {} history records", restored);
+
+    drop(value_history);
+    *HISTORY_LOG.lock().await = Some(log);
+}
+
+/// Queues a sample for [`history_log_task`], dropping it if the flash falls behind.
+pub fn log(entry: LogEntry) {
+    if LOG_CHANNEL.try_send(entry).is_err() {
+        println!("History log queue full, dropping record");
+    }
+}
+
+/// Whether the log opened, without it only the samples in RAM are left.
+pub async fn is_available() -> bool {
+    HISTORY_LOG.lock().await.is_some()
+}
+
+/// Up to `N` records starting at `query.from`, oldest first.
+pub async fn query<const N: usize>(query: &LogQuery) -> Vec<LoggedEntry, N> {
+    let from = query.from.unwrap_or(0);
+    let mut entries = Vec::new();
+    let mut cursor = None;
+
+    loop {
+        let (page, next) = read_page::<N>(cursor, |logged| logged.seq >= from).await;
+
+        for logged in page {
+            if entries.push(logged).is_err() {
+                return entries;
+            }
+        }
+
+        match next {
+            Some(next) => cursor = Some(next),
+            None => return entries,
+        }
+    }
+}
+
+/// Where a paged read of the log continues.
+#[derive(Debug, Clone, Copy)]
+pub struct LogCursor(u32);
+
+/// Up to `N` records matching `filter`, oldest first, from `cursor` or the oldest record
+/// on. Also returns the cursor of the next page, `None` once the newest record was read.
+///
+/// At most [`MAX_SCANNED_SLOTS`] are read per call, so a page can hold fewer records than
+/// match while its cursor is `Some`.
+pub async fn read_page<const N: usize>(
+    cursor: Option<LogCursor>,
+    filter: impl Fn(&LoggedEntry) -> bool,
+) -> (Vec<LoggedEntry, N>, Option<LogCursor>) {
+    let mut entries = Vec::new();
+
+    // Lets the log task and other readers in between the pages of one read.
+    if cursor.is_some() {
+        yield_now().await;
+    }
+
+    let mut log = HISTORY_LOG.lock().await;
+    let Some(log) = log.as_mut() else {
+        return (entries, None);
+    };
+
+    let mut records = match cursor {
+        Some(LogCursor(slot)) => log.records_from(slot),
+        None => log.records(),
+    };
+    let last_scanned = records.remaining().saturating_sub(MAX_SCANNED_SLOTS);
+
+    while !entries.is_full() && records.remaining() > last_scanned {
+        let record = match records.next() {
+            Some(Ok(record)) => record,
+            Some(Err(e)) => {
+                println!("Failed to read history log: {:?}", e);
+                return (entries, None);
+            }
+            None => return (entries, None),
+        };
+
+        let Some(entry) = LogEntry::from_bytes(&record.payload) else {
+            continue;
+        };
+
+        let logged = LoggedEntry {
+            seq: record.seq,
+            entry,
+        };
+
+        if filter(&logged) {
+            entries.push(logged).unwrap();
+        }
+    }
+
+    let next = (records.remaining() > 0).then(|| LogCursor(records.position()));
+
+    (entries, next)
+}
+
+#[embassy_executor::task]
+pub async fn history_log_task() {
+    loop {
+        let entry = LOG_CHANNEL.receive().await;
+
+        let mut log = HISTORY_LOG.lock().await;
+        let Some(log) = log.as_mut() else {
+            continue;
+        };
+
+        if let Err(e) = log.append(&entry.to_bytes()) {
+            println!("Failed to append history record: {:?}", e);
+        }
+    }
+}
@@ -0,0 +1,368 @@
+use embassy_executor::Spawner;
+use embassy_net::{tcp::TcpSocket, IpAddress, Stack};
+use embassy_time::Duration;
+use esp_println::println;
+use heapless::Vec;
+use picoserve::{
+    extract::{Json as JsonBody, Query},
+    io::Write,
+    response::{
+        chunked::ChunkedResponse, sse::EventStream, ws::WebSocketUpgrade, Content, File,
+        IntoResponse, Json, StatusCode,
+    },
+    routing::{get, get_service, post, PathRouter},
+    AppRouter, AppWithStateBuilder, Router,
+};
+
+use crate::{
+    app::{device_status, ConfigPatch, CONFIG},
+    auth::{self, AuthLayer, AuthUpdate},
+    commands::{self, Command},
+    cors::CorsSettings,
+    cors_layer::{self, cors_settings, CorsLayer, CorsUpdateError},
+    csv_export::HistoryCsv,
+    device::{self, DeviceUpdate, DeviceUpdateError},
+    events::{self, LiveEvents, MAX_EVENT_STREAMS},
+    history::{history_bytes_length, HistoryQuery, MAX_QUERY_POINTS, VALUE_HISTORY},
+    history_log::{self, log_bytes_length, log_to_bytes, LogQuery, MAX_LOG_RECORDS},
+    mk_static,
+    mqtt::{self, BrokerUpdate, BrokerUpdateError},
+    network::{self, network_settings, NetworkSettings, NetworkUpdateError},
+    protocol::{self, MessageType, FRAME_OVERHEAD},
+    telemetry::{self, TelemetryPolicy, TelemetryUpdateError},
+    udp::{self, udp_settings, UdpSettings, UdpUpdateError},
+    websocket::DashboardSocket,
+};
+
+pub const HTTP_PORT: u16 = 80;
+
+/// Leaves two tasks for plain requests while all event streams and sockets are open.
+pub const WEB_TASK_POOL_SIZE: usize = MAX_EVENT_STREAMS + 2;
+
+/// `web/index.html`, gzipped by `build.rs`.
+const DASHBOARD: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/index.html.gz"));
+
+/// Escaped characters a JSON command body may contain.
+const JSON_UNESCAPE_BUFFER_LENGTH: usize = 32;
+type CommandBody = JsonBody<Command, JSON_UNESCAPE_BUFFER_LENGTH>;
+type ConfigBody = JsonBody<ConfigPatch, JSON_UNESCAPE_BUFFER_LENGTH>;
+type AuthBody = JsonBody<AuthUpdate, JSON_UNESCAPE_BUFFER_LENGTH>;
+type BrokerBody = JsonBody<BrokerUpdate, JSON_UNESCAPE_BUFFER_LENGTH>;
+type TelemetryBody = JsonBody<TelemetryPolicy, JSON_UNESCAPE_BUFFER_LENGTH>;
+type NetworkBody = JsonBody<NetworkSettings, JSON_UNESCAPE_BUFFER_LENGTH>;
+type DeviceBody = JsonBody<DeviceUpdate, JSON_UNESCAPE_BUFFER_LENGTH>;
+type UdpBody = JsonBody<UdpSettings, JSON_UNESCAPE_BUFFER_LENGTH>;
+type CorsBody = JsonBody<CorsSettings, JSON_UNESCAPE_BUFFER_LENGTH>;
+
+const HISTORY_FRAME_LENGTH: usize = history_bytes_length(MAX_QUERY_POINTS) + FRAME_OVERHEAD;
+const LOG_FRAME_LENGTH: usize = log_bytes_length(MAX_LOG_RECORDS) + FRAME_OVERHEAD;
+
+/// Response carrying bytes of the binary protocol.
+pub struct Binary<const N: usize>(pub Vec<u8, N>);
+
+impl<const N: usize> Content for Binary<N> {
+    fn content_type(&self) -> &'static str {
+        "application/octet-stream"
+    }
+
+    fn content_length(&self) -> usize {
+        self.0.len()
+    }
+
+    async fn write_content<W: Write>(self, mut writer: W) -> Result<(), W::Error> {
+        writer.write_all(&self.0).await
+    }
+}
+
+/// The state of the routes, the client of the connection they answer.
+pub struct HttpClient {
+    pub address: Option<IpAddress>,
+}
+
+pub struct AppProps;
+
+impl AppWithStateBuilder for AppProps {
+    type State = HttpClient;
+    type PathRouter = impl PathRouter<HttpClient>;
+
+    fn build_app(self) -> Router<Self::PathRouter, HttpClient> {
+        Router::new()
+            .route(
+                "/",
+                get_service(File::with_content_type_and_headers(
+                    "text/html; charset=utf-8",
+                    DASHBOARD,
+                    &[("Content-Encoding", "gzip")],
+                )),
+            )
+            .route(
+                "/config",
+                get(|| async { Json(CONFIG.lock().await.clone()) }).post(
+                    |JsonBody(patch): ConfigBody| async move {
+                        match commands::dispatch(Command::SetConfig(patch)).await {
+                            Ok(()) => Ok(Json(CONFIG.lock().await.clone())),
+                            Err(e) => Err((StatusCode::BAD_REQUEST, Json(e))),
+                        }
+                    },
+                ),
+            )
+            .route(
+                "/status",
+                get(|| async { Json(device_status()) }),
+            )
+            .route(
+                "/commands",
+                post(|JsonBody(command): CommandBody| async move {
+                    match commands::dispatch(command).await {
+                        Ok(()) => Ok(StatusCode::NO_CONTENT),
+                        Err(e) => Err((StatusCode::BAD_REQUEST, Json(e))),
+                    }
+                }),
+            )
+            .route(
+                "/auth",
+                post(|JsonBody(update): AuthBody| async move {
+                    match auth::update(update).await {
+                        Ok(()) => Ok(StatusCode::NO_CONTENT),
+                        Err(e) => {
+                            println!("Failed to update credentials: {:?}", e);
+                            Err((StatusCode::INTERNAL_SERVER_ERROR, "Failed to save\n"))
+                        }
+                    }
+                }),
+            )
+            .route(
+                "/mqtt",
+                post(|JsonBody(update): BrokerBody| async move {
+                    match mqtt::update_broker(update).await {
+                        Ok(()) => Ok(StatusCode::NO_CONTENT),
+                        Err(BrokerUpdateError::InvalidCa) => {
+                            Err((StatusCode::BAD_REQUEST, "Invalid CA certificate\n"))
+                        }
+                        Err(BrokerUpdateError::InvalidPinnedKey) => {
+                            Err((StatusCode::BAD_REQUEST, "Invalid pinned key\n"))
+                        }
+                        Err(BrokerUpdateError::InvalidMaxPacketSize) => {
+                            Err((StatusCode::BAD_REQUEST, "Max packet size too small\n"))
+                        }
+                        Err(BrokerUpdateError::Settings(e)) => {
+                            println!("Failed to update broker: {:?}", e);
+                            Err((StatusCode::INTERNAL_SERVER_ERROR, "Failed to save\n"))
+                        }
+                    }
+                }),
+            )
+            .route(
+                "/device",
+                get(|| async { Json(device::device_info().await) }).post(
+                    |JsonBody(update): DeviceBody| async move {
+                        match device::update(update).await {
+                            Ok(()) => Ok(StatusCode::NO_CONTENT),
+                            Err(DeviceUpdateError::InvalidName) => {
+                                Err((StatusCode::BAD_REQUEST, "Invalid device name\n"))
+                            }
+                            Err(DeviceUpdateError::Settings(e)) => {
+                                println!("Failed to update device: {:?}", e);
+                                Err((StatusCode::INTERNAL_SERVER_ERROR, "Failed to save\n"))
+                            }
+                        }
+                    },
+                ),
+            )
+            .route(
+                "/network",
+                get(|| async { Json(network_settings().await) }).post(
+                    |JsonBody(settings): NetworkBody| async move {
+                        match network::update(settings).await {
+                            Ok(()) => Ok(StatusCode::NO_CONTENT),
+                            Err(NetworkUpdateError::InvalidIpv4) => {
+                                Err((StatusCode::BAD_REQUEST, "Invalid IPv4 config\n"))
+                            }
+                            Err(NetworkUpdateError::Settings(e)) => {
+                                println!("Failed to update network: {:?}", e);
+                                Err((StatusCode::INTERNAL_SERVER_ERROR, "Failed to save\n"))
+                            }
+                        }
+                    },
+                ),
+            )
+            .route(
+                "/udp",
+                get(|| async { Json(udp_settings().await) }).post(
+                    |JsonBody(settings): UdpBody| async move {
+                        match udp::update(settings).await {
+                            Ok(()) => Ok(StatusCode::NO_CONTENT),
+                            Err(UdpUpdateError::InvalidPort) => {
+                                Err((StatusCode::BAD_REQUEST, "Invalid telemetry port\n"))
+                            }
+                            Err(UdpUpdateError::Settings(e)) => {
+                                println!("Failed to update UDP settings: {:?}", e);
+                                Err((StatusCode::INTERNAL_SERVER_ERROR, "Failed to save\n"))
+                            }
+                        }
+                    },
+                ),
+            )
+            .route(
+                "/cors",
+                get(|| async { Json(cors_settings().await) }).post(
+                    |JsonBody(settings): CorsBody| async move {
+                        match cors_layer::update(settings).await {
+                            Ok(()) => Ok(StatusCode::NO_CONTENT),
+                            Err(CorsUpdateError::Invalid) => Err((
+                                StatusCode::BAD_REQUEST,
+                                "Invalid origin, methods or headers\n",
+                            )),
+                            Err(CorsUpdateError::Settings(e)) => {
+                                println!("Failed to update CORS settings: {:?}", e);
+                                Err((StatusCode::INTERNAL_SERVER_ERROR, "Failed to save\n"))
+                            }
+                        }
+                    },
+                ),
+            )
+            .route(
+                "/telemetry",
+                get(|| async { Json(telemetry::policy().await) }).post(
+                    |JsonBody(policy): TelemetryBody| async move {
+                        match telemetry::update(policy).await {
+                            Ok(()) => Ok(StatusCode::NO_CONTENT),
+                            Err(TelemetryUpdateError::InvalidPolicy) => Err((
+                                StatusCode::BAD_REQUEST,
+                                "Heartbeat shorter than the minimum interval\n",
+                            )),
+                            Err(TelemetryUpdateError::Settings(e)) => {
+                                println!("Failed to update telemetry: {:?}", e);
+                                Err((StatusCode::INTERNAL_SERVER_ERROR, "Failed to save\n"))
+                            }
+                        }
+                    },
+                ),
+            )
+            .route(
+                "/history",
+                get(|Query(query): Query<HistoryQuery>| async move {
+                    Json(VALUE_HISTORY.lock().await.query::<MAX_QUERY_POINTS>(&query))
+                }),
+            )
+            .route(
+                "/history/bin",
+                get(|Query(query): Query<HistoryQuery>| async move {
+                    let history = VALUE_HISTORY.lock().await.query::<MAX_QUERY_POINTS>(&query);
+                    let frame = protocol::encode_vec::<HISTORY_FRAME_LENGTH>(
+                        MessageType::History,
+                        0,
+                        &history.to_bytes::<{ history_bytes_length(MAX_QUERY_POINTS) }>(),
+                    )
+                    .unwrap();
+
+                    Binary(frame)
+                }),
+            )
+            .route(
+                "/history.csv",
+                get(|Query(query): Query<HistoryQuery>| async move {
+                    ChunkedResponse::new(HistoryCsv(query))
+                        .into_response()
+                        .with_header(
+                            "Content-Disposition",
+                            "attachment; filename=\"history.csv\"",
+                        )
+                }),
+            )
+            .route(
+                "/history/log",
+                get(|Query(query): Query<LogQuery>| async move {
+                    Json(history_log::query::<MAX_LOG_RECORDS>(&query).await)
+                }),
+            )
+            .route(
+                "/history/log/bin",
+                get(|Query(query): Query<LogQuery>| async move {
+                    let entries = history_log::query::<MAX_LOG_RECORDS>(&query).await;
+                    let frame = protocol::encode_vec::<LOG_FRAME_LENGTH>(
+                        MessageType::Log,
+                        0,
+                        &log_to_bytes::<{ log_bytes_length(MAX_LOG_RECORDS) }>(&entries),
+                    )
+                    .unwrap();
+
+                    Binary(frame)
+                }),
+            )
+            .route(
+                "/events",
+                get(|| async {
+                    match events::subscribe() {
+                        Some(subscriber) => Ok(EventStream(LiveEvents(subscriber))),
+                        None => Err((
+                            StatusCode::SERVICE_UNAVAILABLE,
+                            "Too many event streams\n",
+                        )),
+                    }
+                }),
+            )
+            .route(
+                "/ws",
+                get(|upgrade: WebSocketUpgrade| async move {
+                    match events::subscribe() {
+                        Some(subscriber) => Ok(upgrade.on_upgrade(DashboardSocket(subscriber))),
+                        None => Err((
+                            StatusCode::SERVICE_UNAVAILABLE,
+                            "Too many event streams\n",
+                        )),
+                    }
+                }),
+            )
+            .layer(AuthLayer)
+            .layer(CorsLayer)
+    }
+}
+
+#[embassy_executor::task(pool_size = WEB_TASK_POOL_SIZE)]
+async fn web_task(
+    id: usize,
+    stack: Stack<'static>,
+    app: &'static AppRouter<AppProps>,
+    config: &'static picoserve::Config<Duration>,
+) -> ! {
+    let mut tcp_rx_buffer = [0; 1024];
+    let mut tcp_tx_buffer = [0; 1024];
+    let mut http_buffer = [0; 2048];
+
+    // Accepts itself instead of `picoserve::listen_and_serve`, to hand the client address
+    // to the auth layer.
+    loop {
+        let mut socket = TcpSocket::new(stack, &mut tcp_rx_buffer, &mut tcp_tx_buffer);
+        if let Err(e) = socket.accept(HTTP_PORT).await {
+            println!("Web task {} failed to accept: {:?}", id, e);
+            continue;
+        }
+
+        let client = HttpClient {
+            address: socket.remote_endpoint().map(|endpoint| endpoint.addr),
+        };
+
+        // Browsers time out and reset connections all the time, nothing worth logging.
+        let _ = picoserve::serve_with_state(app, config, &mut http_buffer, socket, &client).await;
+    }
+}
+
+pub fn start_web_server(stack: Stack<'static>, spawner: &Spawner) {
+    let app = &*mk_static!(AppRouter<AppProps>, AppProps.build_app());
+
+    let config = &*mk_static!(
+        picoserve::Config<Duration>,
+        picoserve::Config::new(picoserve::Timeouts {
+            start_read_request: Some(Duration::from_secs(5)),
+            persistent_start_read_request: Some(Duration::from_secs(1)),
+            read_request: Some(Duration::from_secs(1)),
+            write: Some(Duration::from_secs(1)),
+        })
+        .keep_connection_alive()
+    );
+
+    for id in 0..WEB_TASK_POOL_SIZE {
+        spawner.must_spawn(web_task(id, stack, app, config));
+    }
+}
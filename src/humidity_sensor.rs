@@ -0,0 +1,239 @@
+//! Relative humidity sensors on I2C, the SHT3x and the BME280. The single wire DHT22 is
+//! not supported, its protocol is bit-banged with microsecond timing for 5 ms per reading.
+
+use anyhow::{anyhow, Result};
+use embassy_time::Duration;
+use esp_hal::{
+    gpio::AnyPin,
+    i2c::master::{AnyI2c, Config, I2c},
+    Blocking,
+};
+
+use crate::sensor_registry::{SensorDriver, SensorKind};
+
+/// Default address of the SHT3x with the ADDR pin pulled low.
+pub const SHT3X_ADDRESS: u8 = 0x44;
+
+/// Single shot measurement, high repeatability, no clock stretching.
+const MEASURE_COMMAND: [u8; 2] = [0x24, 0x00];
+/// Maximum duration of a high repeatability measurement.
+const MEASURE_TIME: Duration = Duration::from_millis(16);
+
+/// Sensirion SHT3x relative humidity sensor on its own I2C bus.
+pub struct Sht3x<'a> {
+    i2c: I2c<'a, Blocking>,
+    address: u8,
+}
+
+impl<'a> Sht3x<'a> {
+    /// Creates the driver on a dedicated I2C peripheral, the display keeps `I2C0` for itself.
+    pub fn new(i2c: AnyI2c, scl: AnyPin, sda: AnyPin, address: u8) -> Self {
+        let i2c = I2c::new(i2c, Config::default())
+            .unwrap()
+            .with_scl(scl)
+            .with_sda(sda);
+
+        Self { i2c, address }
+    }
+}
+
+impl SensorDriver for Sht3x<'_> {
+    fn kind(&self) -> SensorKind {
+        SensorKind::Humidity
+    }
+
+    fn start(&mut self) -> Result<Duration> {
+        self.i2c
+            .write(self.address, &MEASURE_COMMAND)
+            .map_err(|err| anyhow!("SHT3x write failed: {err:?}"))?;
+
+        Ok(MEASURE_TIME)
+    }
+
+    /// Relative humidity in hundredths of a percent.
+    fn read(&mut self) -> Result<Option<i32>> {
+        let mut data = [0; 6];
+        self.i2c
+            .read(self.address, &mut data)
+            .map_err(|err| anyhow!("SHT3x read failed: {err:?}"))?;
+
+        if crc8(&data[3..5]) != data[5] {
+            anyhow::bail!("SHT3x humidity CRC mismatch");
+        }
+
+        let raw = u16::from_be_bytes([data[3], data[4]]) as i32;
+
+        Ok(Some(raw * 10000 / 65535))
+    }
+}
+
+/// Default address of the BME280 with the SDO pin pulled low.
+pub const BME280_ADDRESS: u8 = 0x76;
+
+const BME280_CHIP_ID: u8 = 0x60;
+
+const REG_CHIP_ID: u8 = 0xD0;
+/// First block of calibration data, `dig_T1` to `dig_H1`.
+const REG_CALIBRATION_1: u8 = 0x88;
+/// Second block of calibration data, `dig_H2` to `dig_H6`.
+const REG_CALIBRATION_2: u8 = 0xE1;
+const REG_CTRL_HUM: u8 = 0xF2;
+const REG_STATUS: u8 = 0xF3;
+const REG_CTRL_MEAS: u8 = 0xF4;
+/// Temperature and humidity readings, the pressure is skipped.
+const REG_TEMPERATURE: u8 = 0xFA;
+
+/// Humidity oversampling x1.
+const CTRL_HUM: u8 = 0x01;
+/// Temperature oversampling x1, pressure skipped, forced mode.
+const CTRL_MEAS_FORCED: u8 = 0x21;
+const STATUS_MEASURING: u8 = 0x08;
+/// Maximum duration of a measurement with the oversampling above.
+const BME280_MEASURE_TIME: Duration = Duration::from_millis(10);
+
+/// Trimming values needed for the humidity, which is compensated with the temperature.
+struct Calibration {
+    t1: u16,
+    t2: i16,
+    t3: i16,
+    h1: u8,
+    h2: i16,
+    h3: u8,
+    h4: i16,
+    h5: i16,
+    h6: i8,
+}
+
+impl Calibration {
+    fn from_registers(block_1: &[u8; 26], block_2: &[u8; 7]) -> Self {
+        Self {
+            t1: u16::from_le_bytes([block_1[0], block_1[1]]),
+            t2: i16::from_le_bytes([block_1[2], block_1[3]]),
+            t3: i16::from_le_bytes([block_1[4], block_1[5]]),
+            h1: block_1[25],
+            h2: i16::from_le_bytes([block_2[0], block_2[1]]),
+            h3: block_2[2],
+            h4: ((block_2[3] as i8 as i16) << 4) | (block_2[4] & 0x0F) as i16,
+            h5: ((block_2[5] as i8 as i16) << 4) | (block_2[4] >> 4) as i16,
+            h6: block_2[6] as i8,
+        }
+    }
+
+    /// Fine temperature, as the datasheet compensation formula names it.
+    fn t_fine(&self, adc_t: i32) -> i32 {
+        let t1 = self.t1 as i32;
+        let var1 = (((adc_t >> 3) - (t1 << 1)) * self.t2 as i32) >> 11;
+        let var2 = (((((adc_t >> 4) - t1) * ((adc_t >> 4) - t1)) >> 12) * self.t3 as i32) >> 14;
+
+        var1 + var2
+    }
+
+    /// Relative humidity in 1/1024 of a percent, from the datasheet compensation formula.
+    fn humidity(&self, adc_h: i32, t_fine: i32) -> i32 {
+        let x = t_fine - 76800;
+        let x = (((adc_h << 14) - ((self.h4 as i32) << 20) - (self.h5 as i32 * x) + 16384) >> 15)
+            * (((((((x * self.h6 as i32) >> 10) * (((x * self.h3 as i32) >> 11) + 32768)) >> 10)
+                + 2097152)
+                * self.h2 as i32
+                + 8192)
+                >> 14);
+        let x = x - (((((x >> 15) * (x >> 15)) >> 7) * self.h1 as i32) >> 4);
+
+        x.clamp(0, 419430400) >> 12
+    }
+}
+
+/// Bosch BME280 in forced mode on its own I2C bus, only its humidity is reported.
+pub struct Bme280<'a> {
+    i2c: I2c<'a, Blocking>,
+    address: u8,
+    calibration: Calibration,
+}
+
+impl<'a> Bme280<'a> {
+    /// Creates the driver on a dedicated I2C peripheral and reads the calibration data.
+    pub fn new(i2c: AnyI2c, scl: AnyPin, sda: AnyPin, address: u8) -> Result<Self> {
+        let mut i2c = I2c::new(i2c, Config::default())
+            .unwrap()
+            .with_scl(scl)
+            .with_sda(sda);
+
+        let mut chip_id = [0];
+        let mut block_1 = [0; 26];
+        let mut block_2 = [0; 7];
+        i2c.write_read(address, &[REG_CHIP_ID], &mut chip_id)
+            .and_then(|_| i2c.write_read(address, &[REG_CALIBRATION_1], &mut block_1))
+            .and_then(|_| i2c.write_read(address, &[REG_CALIBRATION_2], &mut block_2))
+            .map_err(|err| anyhow!("BME280 calibration read failed: {err:?}"))?;
+
+        if chip_id[0] != BME280_CHIP_ID {
+            anyhow::bail!("No BME280 at {address:#04x}, chip id {:#04x}", chip_id[0]);
+        }
+
+        Ok(Self {
+            i2c,
+            address,
+            calibration: Calibration::from_registers(&block_1, &block_2),
+        })
+    }
+}
+
+impl SensorDriver for Bme280<'_> {
+    fn kind(&self) -> SensorKind {
+        SensorKind::Humidity
+    }
+
+    fn start(&mut self) -> Result<Duration> {
+        // The humidity control only applies after the measurement control is written.
+        self.i2c
+            .write(self.address, &[REG_CTRL_HUM, CTRL_HUM])
+            .and_then(|_| {
+                self.i2c
+                    .write(self.address, &[REG_CTRL_MEAS, CTRL_MEAS_FORCED])
+            })
+            .map_err(|err| anyhow!("BME280 write failed: {err:?}"))?;
+
+        Ok(BME280_MEASURE_TIME)
+    }
+
+    /// Relative humidity in hundredths of a percent.
+    fn read(&mut self) -> Result<Option<i32>> {
+        let mut status = [0];
+        let mut data = [0; 5];
+        self.i2c
+            .write_read(self.address, &[REG_STATUS], &mut status)
+            .map_err(|err| anyhow!("BME280 read failed: {err:?}"))?;
+
+        if status[0] & STATUS_MEASURING != 0 {
+            return Ok(None);
+        }
+
+        self.i2c
+            .write_read(self.address, &[REG_TEMPERATURE], &mut data)
+            .map_err(|err| anyhow!("BME280 read failed: {err:?}"))?;
+
+        let adc_t = ((data[0] as i32) << 12) | ((data[1] as i32) << 4) | (data[2] as i32 >> 4);
+        let adc_h = ((data[3] as i32) << 8) | data[4] as i32;
+        let t_fine = self.calibration.t_fine(adc_t);
+
+        Ok(Some(self.calibration.humidity(adc_h, t_fine) * 100 / 1024))
+    }
+}
+
+/// CRC-8 used by Sensirion sensors, polynomial 0x31 and initial value 0xFF.
+fn crc8(data: &[u8]) -> u8 {
+    let mut crc = 0xFF_u8;
+
+    for byte in data {
+        crc ^= byte;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 {
+                (crc << 1) ^ 0x31
+            } else {
+                crc << 1
+            };
+        }
+    }
+
+    crc
+}
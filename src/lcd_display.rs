@@ -13,7 +13,12 @@ use hd44780_driver::{
 };
 use heapless::String;
 
-use crate::utils::FloatRepresentation;
+use crate::mqtt_status::MqttState;
+use crate::utils::Temperature;
+
+const COLUMNS: usize = 16;
+/// Last column of the first row, left to [`Display::display_mqtt_state`].
+const MQTT_STATE_COLUMN: u8 = 15;
 
 pub struct Display<'a> {
     display:
@@ -52,7 +57,7 @@ impl<'a> Display<'_> {
     /// let mut display = Display::new(i2c, scl, sda, i2c_address);
     ///
     /// // Now you can use the display, e.g., to show a message
-    /// display.display_temperature(23.5); // Example usage
+    /// display.display_temperature(Temperature(2350)); // Example usage
     /// ```
     pub fn new(i2c: AnyI2c, scl: AnyPin, sda: AnyPin, i2c_address: u8) -> Self {
         let i2c_bus = I2c::new(i2c, Config::default())
@@ -77,17 +82,12 @@ impl<'a> Display<'_> {
         Self { display: lcd }
     }
 
-    pub fn display_temperature(&mut self, temp: f64) {
+    pub fn display_temperature(&mut self, temp: Temperature) {
         let mut temperature_string: String<16> = String::new();
 
-        let (int_part, dec_part) = temp.float_to_parts(1);
-
-        ufmt::uwrite!(&mut temperature_string, "Temp: {}.{}", int_part, dec_part).unwrap();
+        ufmt::uwrite!(&mut temperature_string, "Temp: {}", DisplayTemperature(temp)).unwrap();
 
-        self.display.set_cursor_xy((0, 0), &mut Delay).unwrap();
-        self.display
-            .write_str(&temperature_string, &mut Delay)
-            .unwrap();
+        self.write_padded(0, temperature_string, MQTT_STATE_COLUMN as usize);
     }
 
     pub fn display_gas(&mut self, gas: u16) {
@@ -95,7 +95,58 @@ impl<'a> Display<'_> {
 
         ufmt::uwrite!(&mut gas_string, "Gas: {}", gas).unwrap();
 
-        self.display.set_cursor_xy((0, 1), &mut Delay).unwrap();
-        self.display.write_str(&gas_string, &mut Delay).unwrap();
+        self.write_row(1, gas_string);
+    }
+
+    /// Replaces the gas reading with the remaining heater warm-up time.
+    pub fn display_gas_warmup(&mut self, remaining_secs: u16) {
+        let mut gas_string: String<16> = String::new();
+
+        ufmt::uwrite!(&mut gas_string, "Gas: warmup {}s", remaining_secs.min(999)).unwrap();
+
+        self.write_row(1, gas_string);
+    }
+
+    /// Shows the MQTT connection in the last column of the first row: `*` connected, `~`
+    /// connecting, `!` waiting to retry and blank when disabled.
+    pub fn display_mqtt_state(&mut self, state: MqttState) {
+        let symbol = match state {
+            MqttState::Connected => "*",
+            MqttState::Connecting => "~",
+            MqttState::Backoff => "!",
+            MqttState::Disabled => " ",
+        };
+
+        self.display
+            .set_cursor_xy((MQTT_STATE_COLUMN, 0), &mut Delay)
+            .unwrap();
+        self.display.write_str(symbol, &mut Delay).unwrap();
+    }
+
+    /// Writes a full row, padding with spaces so a shorter text clears the previous one.
+    fn write_row(&mut self, row: u8, text: String<16>) {
+        self.write_padded(row, text, COLUMNS);
+    }
+
+    /// Writes `text` at the start of `row`, padded with spaces to `width` columns.
+    fn write_padded(&mut self, row: u8, mut text: String<16>, width: usize) {
+        while text.len() < width {
+            text.push(' ').unwrap();
+        }
+
+        self.display.set_cursor_xy((0, row), &mut Delay).unwrap();
+        self.display.write_str(&text, &mut Delay).unwrap();
+    }
+}
+
+/// Shows the temperature with the single decimal that fits the display.
+struct DisplayTemperature(Temperature);
+
+impl ufmt::uDisplay for DisplayTemperature {
+    fn fmt<W: ufmt::uWrite + ?Sized>(
+        &self,
+        f: &mut ufmt::Formatter<'_, W>,
+    ) -> Result<(), W::Error> {
+        self.0.write_decimal(f, 1)
     }
 }
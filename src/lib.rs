@@ -1,14 +1,44 @@
 #![no_std]
 #![feature(impl_trait_in_assoc_type)]
 
+pub mod adc;
 pub mod app;
+pub mod auth;
+pub mod clock;
+pub mod commands;
+pub mod cors;
 pub mod cors_layer;
+pub mod csv_export;
+pub mod device;
+pub mod events;
+pub mod flame_sensor;
+pub mod flash_log;
 pub mod gas_sensor;
+pub mod history;
+pub mod history_log;
+pub mod http;
+pub mod humidity_sensor;
 pub mod lcd_display;
+pub mod mdns;
 pub mod mqtt;
+pub mod mqtt_cert;
+pub mod mqtt_commands;
+pub mod mqtt_limits;
+pub mod mqtt_status;
+pub mod mqtt_tls;
+pub mod network;
+pub mod offline_queue;
 pub mod peripheral_tasks;
+pub mod protocol;
+pub mod readiness;
+pub mod sensor_registry;
+pub mod settings;
+pub mod sntp;
+pub mod telemetry;
 pub mod temp_sensor;
+pub mod udp;
 pub mod utils;
+pub mod websocket;
 pub mod wifi;
 
 #[macro_export]
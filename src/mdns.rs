@@ -0,0 +1,457 @@
+//! mDNS responder (RFC 6762) with DNS-SD service advertisement (RFC 6763), on IPv4.
+//!
+//! Answers `<device-name>.local` and advertises the dashboard as `_http._tcp` and the
+//! device as `_espsensor._tcp`, both with the device ID and firmware version in their TXT
+//! records. Probing and known-answer suppression are left out, the default name contains
+//! the MAC and does not collide.
+
+use embassy_futures::select::{select3, Either3};
+use embassy_net::{
+    udp::{PacketMetadata, UdpSocket},
+    IpEndpoint, Ipv4Address, Ipv6Address, Stack,
+};
+use embassy_time::{Duration, Timer};
+use esp_println::println;
+use heapless::{String, Vec};
+
+use crate::device::{self, DeviceId, DeviceName, FIRMWARE_VERSION, NAME_CHANGED};
+use crate::http::HTTP_PORT;
+
+const MDNS_PORT: u16 = 5353;
+const MDNS_GROUP: Ipv4Address = Ipv4Address::new(224, 0, 0, 251);
+/// Largest packet handled, longer queries are dropped and answers kept below it.
+const MDNS_PACKET_LENGTH: usize = 512;
+/// Longest name of a question that is decoded, longer ones cannot match.
+const MAX_NAME_LENGTH: usize = 96;
+
+/// TTL of the records naming the host, as RFC 6762 recommends.
+const HOST_TTL: u32 = 120;
+const SERVICE_TTL: u32 = 4500;
+
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_TXT: u16 = 16;
+const TYPE_AAAA: u16 = 28;
+const TYPE_SRV: u16 = 33;
+const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+/// Top bit of the class: cache flush in answers, unicast response in questions.
+const CLASS_FLAG: u16 = 0x8000;
+
+/// Service types advertised, both served on [`HTTP_PORT`].
+const SERVICES: [&str; 2] = ["_http", "_espsensor"];
+const SERVICE_ENUMERATION: [&str; 4] = ["_services", "_dns-sd", "_udp", "local"];
+
+/// What the answers are built from, gathered for every packet.
+struct Host {
+    name: DeviceName,
+    id: DeviceId,
+    ipv4: Option<Ipv4Address>,
+    ipv6: Option<Ipv6Address>,
+}
+
+impl Host {
+    async fn current(stack: Stack<'_>) -> Self {
+        Self {
+            name: device::device_name().await,
+            id: device::device_id(),
+            ipv4: stack.config_v4().map(|config| config.address.address()),
+            ipv6: stack.config_v6().map(|config| config.address.address()),
+        }
+    }
+
+    fn host_name(&self) -> [&str; 2] {
+        [&self.name, "local"]
+    }
+
+    fn instance_name<'a>(&'a self, service: &'a str) -> [&'a str; 4] {
+        [&self.name, service, "_tcp", "local"]
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct ServiceRecords {
+    enumeration: bool,
+    ptr: bool,
+    srv: bool,
+    txt: bool,
+}
+
+impl ServiceRecords {
+    fn is_empty(&self) -> bool {
+        !(self.enumeration || self.ptr || self.srv || self.txt)
+    }
+}
+
+/// Records that go into an answer, each one once however many questions asked for it.
+#[derive(Debug, Default, Clone, Copy)]
+struct Selection {
+    a: bool,
+    aaaa: bool,
+    services: [ServiceRecords; SERVICES.len()],
+}
+
+impl Selection {
+    fn all() -> Self {
+        let all = ServiceRecords {
+            enumeration: true,
+            ptr: true,
+            srv: true,
+            txt: true,
+        };
+
+        Self {
+            a: true,
+            aaaa: true,
+            services: [all; SERVICES.len()],
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        !self.a && !self.aaaa && self.services.iter().all(ServiceRecords::is_empty)
+    }
+
+    /// Adds the records answering a question, with the host address as additional
+    /// record of the service ones.
+    fn add(&mut self, host: &Host, name: &str, qtype: u16) {
+        let wants = |rtype: u16| qtype == rtype || qtype == TYPE_ANY;
+
+        if matches(name, &host.host_name()) {
+            self.a |= wants(TYPE_A);
+            self.aaaa |= wants(TYPE_AAAA);
+        }
+
+        for (index, service) in SERVICES.into_iter().enumerate() {
+            let records = &mut self.services[index];
+
+            if wants(TYPE_PTR) && matches(name, &SERVICE_ENUMERATION) {
+                records.enumeration = true;
+            }
+            if wants(TYPE_PTR) && matches(name, &[service, "_tcp", "local"]) {
+                records.ptr = true;
+                records.srv = true;
+                records.txt = true;
+                self.a = true;
+            }
+            if matches(name, &host.instance_name(service)) {
+                records.srv |= wants(TYPE_SRV);
+                records.txt |= wants(TYPE_TXT);
+                self.a |= wants(TYPE_SRV);
+            }
+        }
+    }
+}
+
+/// Compares a decoded name with labels, ignoring ASCII case as DNS does.
+fn matches(name: &str, labels: &[&str]) -> bool {
+    name.split('.').count() == labels.len()
+        && name
+            .split('.')
+            .zip(labels)
+            .all(|(label, expected)| label.eq_ignore_ascii_case(expected))
+}
+
+#[derive(Debug)]
+struct PacketFull;
+
+struct Response {
+    packet: Vec<u8, MDNS_PACKET_LENGTH>,
+    answers: u16,
+}
+
+impl Response {
+    /// Starts an answer to query `id` repeating `questions`, which only legacy unicast
+    /// queries need, or an unsolicited one without both.
+    fn new(id: u16, questions: &[u8], question_count: u16) -> Result<Self, PacketFull> {
+        let mut response = Self {
+            packet: Vec::new(),
+            answers: 0,
+        };
+
+        response.push(&id.to_be_bytes())?;
+        // Response, authoritative.
+        response.push(&0x8400u16.to_be_bytes())?;
+        response.push(&question_count.to_be_bytes())?;
+        // Answer, authority and additional counts, the first one set in `finish`.
+        response.push(&[0; 6])?;
+        response.push(questions)?;
+
+        Ok(response)
+    }
+
+    fn push(&mut self, bytes: &[u8]) -> Result<(), PacketFull> {
+        self.packet.extend_from_slice(bytes).map_err(|_| PacketFull)
+    }
+
+    fn name(&mut self, labels: &[&str]) -> Result<(), PacketFull> {
+        for label in labels {
+            self.push(&[label.len() as u8])?;
+            self.push(label.as_bytes())?;
+        }
+
+        self.push(&[0])
+    }
+
+    fn record(
+        &mut self,
+        name: &[&str],
+        rtype: u16,
+        cache_flush: bool,
+        ttl: u32,
+        rdata: impl FnOnce(&mut Self) -> Result<(), PacketFull>,
+    ) -> Result<(), PacketFull> {
+        let class = if cache_flush {
+            CLASS_IN | CLASS_FLAG
+        } else {
+            CLASS_IN
+        };
+
+        self.name(name)?;
+        self.push(&rtype.to_be_bytes())?;
+        self.push(&class.to_be_bytes())?;
+        self.push(&ttl.to_be_bytes())?;
+
+        let length_offset = self.packet.len();
+        self.push(&[0, 0])?;
+        rdata(self)?;
+        let length = (self.packet.len() - length_offset - 2) as u16;
+        self.packet[length_offset..length_offset + 2].copy_from_slice(&length.to_be_bytes());
+
+        self.answers += 1;
+
+        Ok(())
+    }
+
+    fn txt(&mut self, host: &Host) -> Result<(), PacketFull> {
+        for (key, value) in [("id=", host.id.as_str()), ("version=", FIRMWARE_VERSION)] {
+            self.push(&[(key.len() + value.len()) as u8])?;
+            self.push(key.as_bytes())?;
+            self.push(value.as_bytes())?;
+        }
+
+        Ok(())
+    }
+
+    fn add(&mut self, host: &Host, selection: &Selection) -> Result<(), PacketFull> {
+        for (index, service) in SERVICES.into_iter().enumerate() {
+            let records = &selection.services[index];
+            let service_type = [service, "_tcp", "local"];
+            let instance = host.instance_name(service);
+
+            if records.enumeration {
+                self.record(&SERVICE_ENUMERATION, TYPE_PTR, false, SERVICE_TTL, |r| {
+                    r.name(&service_type)
+                })?;
+            }
+            if records.ptr {
+                self.record(&service_type, TYPE_PTR, false, SERVICE_TTL, |r| r.name(&instance))?;
+            }
+            if records.srv {
+                self.record(&instance, TYPE_SRV, true, HOST_TTL, |r| {
+                    // Priority and weight.
+                    r.push(&[0; 4])?;
+                    r.push(&HTTP_PORT.to_be_bytes())?;
+                    r.name(&host.host_name())
+                })?;
+            }
+            if records.txt {
+                self.record(&instance, TYPE_TXT, true, SERVICE_TTL, |r| r.txt(host))?;
+            }
+        }
+
+        if let (true, Some(ipv4)) = (selection.a, host.ipv4) {
+            self.record(&host.host_name(), TYPE_A, true, HOST_TTL, |r| r.push(&ipv4.octets()))?;
+        }
+        if let (true, Some(ipv6)) = (selection.aaaa, host.ipv6) {
+            self.record(&host.host_name(), TYPE_AAAA, true, HOST_TTL, |r| {
+                r.push(&ipv6.octets())
+            })?;
+        }
+
+        Ok(())
+    }
+
+    fn finish(mut self) -> Vec<u8, MDNS_PACKET_LENGTH> {
+        self.packet[6..8].copy_from_slice(&self.answers.to_be_bytes());
+        self.packet
+    }
+}
+
+/// Decodes the name at `offset`, following compression pointers, into dotted labels.
+/// Returns the offset right after the name.
+fn read_name(
+    packet: &[u8],
+    mut offset: usize,
+    name: &mut String<MAX_NAME_LENGTH>,
+) -> Option<usize> {
+    let mut end = None;
+    let mut jumps = 0;
+
+    loop {
+        let length = *packet.get(offset)? as usize;
+
+        match length {
+            0 => return Some(end.unwrap_or(offset + 1)),
+            length if length & 0xC0 == 0xC0 => {
+                jumps += 1;
+                if jumps > 8 {
+                    return None;
+                }
+                end.get_or_insert(offset + 2);
+                offset = (length & 0x3F) << 8 | *packet.get(offset + 1)? as usize;
+            }
+            length if length < 64 => {
+                let label = packet.get(offset + 1..offset + 1 + length)?;
+                if !name.is_empty() {
+                    name.push('.').ok()?;
+                }
+                name.push_str(core::str::from_utf8(label).ok()?).ok()?;
+                offset += 1 + length;
+            }
+            _ => return None,
+        }
+    }
+}
+
+/// Builds the answer to a query and where it goes, `None` if there is nothing to answer.
+fn answer(
+    host: &Host,
+    query: &[u8],
+    source: IpEndpoint,
+) -> Option<(Vec<u8, MDNS_PACKET_LENGTH>, IpEndpoint)> {
+    let header = query.get(..12)?;
+    let id = u16::from_be_bytes([header[0], header[1]]);
+    let flags = u16::from_be_bytes([header[2], header[3]]);
+    let question_count = u16::from_be_bytes([header[4], header[5]]);
+
+    // Responses and other opcodes than a standard query.
+    if flags & 0xF800 != 0 {
+        return None;
+    }
+
+    let mut selection = Selection::default();
+    let mut unicast = false;
+    let mut offset = 12;
+
+    for _ in 0..question_count {
+        let mut name = String::new();
+        offset = read_name(query, offset, &mut name)?;
+        let fields = query.get(offset..offset + 4)?;
+        offset += 4;
+
+        let qtype = u16::from_be_bytes([fields[0], fields[1]]);
+        let qclass = u16::from_be_bytes([fields[2], fields[3]]);
+        unicast |= qclass & CLASS_FLAG != 0;
+
+        selection.add(host, &name, qtype);
+    }
+
+    if selection.is_empty() {
+        return None;
+    }
+
+    // Legacy resolvers query from another port and expect a plain DNS answer.
+    let legacy = source.port != MDNS_PORT;
+    let response = if legacy {
+        Response::new(id, &query[12..offset], question_count)
+    } else {
+        Response::new(0, &[], 0)
+    };
+
+    let mut response = response.ok()?;
+    if let Err(e) = response.add(host, &selection) {
+        println!("mDNS answer does not fit: {:?}", e);
+        return None;
+    }
+
+    let destination = if legacy || unicast {
+        source
+    } else {
+        IpEndpoint::new(MDNS_GROUP.into(), MDNS_PORT)
+    };
+
+    Some((response.finish(), destination))
+}
+
+/// Sends every record unsolicited, twice a second apart as RFC 6762 asks.
+async fn announce(stack: Stack<'_>, socket: &UdpSocket<'_>) {
+    for _ in 0..2 {
+        let host = Host::current(stack).await;
+        let mut response = match Response::new(0, &[], 0) {
+            Ok(response) => response,
+            Err(_) => return,
+        };
+
+        if let Err(e) = response.add(&host, &Selection::all()) {
+            println!("mDNS announcement does not fit: {:?}", e);
+            return;
+        }
+
+        let destination = IpEndpoint::new(MDNS_GROUP.into(), MDNS_PORT);
+        if let Err(e) = socket.send_to(&response.finish(), destination).await {
+            println!("Failed to send mDNS announcement: {:?}", e);
+        }
+
+        Timer::after(Duration::from_secs(1)).await;
+    }
+}
+
+#[embassy_executor::task]
+pub async fn mdns_task(stack: Stack<'static>) {
+    let mut rx_meta = [PacketMetadata::EMPTY; 4];
+    let mut rx_buffer = [0; 1024];
+    let mut tx_meta = [PacketMetadata::EMPTY; 4];
+    let mut tx_buffer = [0; 1024];
+    let mut packet = [0; MDNS_PACKET_LENGTH];
+
+    let mut socket = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+
+    if let Err(e) = socket.bind(MDNS_PORT) {
+        println!("Failed to bind mDNS socket: {:?}", e);
+        return;
+    }
+
+    loop {
+        stack.wait_config_up().await;
+
+        if let Err(e) = stack.join_multicast_group(MDNS_GROUP) {
+            println!("Failed to join mDNS group: {:?}", e);
+        }
+
+        let name = device::device_name().await;
+        println!("Announcing {}.local over mDNS", name.as_str());
+        announce(stack, &socket).await;
+
+        loop {
+            match select3(
+                socket.recv_from(&mut packet),
+                stack.wait_config_down(),
+                NAME_CHANGED.wait(),
+            )
+            .await
+            {
+                Either3::First(Ok((length, meta))) => {
+                    let host = Host::current(stack).await;
+                    let answer = answer(&host, &packet[..length], meta.endpoint);
+                    let Some((response, destination)) = answer else {
+                        continue;
+                    };
+
+                    if let Err(e) = socket.send_to(&response, destination).await {
+                        println!("Failed to send mDNS answer: {:?}", e);
+                    }
+                }
+                // Truncated, longer than anything worth answering.
+                Either3::First(Err(_)) => {}
+                Either3::Second(()) => break,
+                Either3::Third(()) => announce(stack, &socket).await,
+            }
+        }
+    }
+}
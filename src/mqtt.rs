@@ -1,138 +1,767 @@
 use crate::{
-    app::{Config, CONFIG},
-    peripheral_tasks::{RISK_SIGNAL, SENSOR_VALS_SIGNAL},
+    app::{ConfigError, ConfigPatch, Risk, SensorValues, WireFormat, CONFIG},
+    clock::Timestamp,
+    commands::{self, Command},
+    history::{history_bytes_length, HistoryQuery, VALUE_HISTORY},
+    history_log::{self, log_bytes_length, log_to_bytes, LogQuery},
+    mqtt_cert::{TrustAnchor, MAX_CA_LENGTH},
+    mqtt_commands::{
+        self, COMMAND_TOPIC, MAX_REPLY_TOPIC_LENGTH, MAX_REQUEST_LENGTH, RESPONSE_LENGTH,
+    },
+    mqtt_limits::{MqttBuffers, PacketLimits, Rejection, PUBLISH_OVERHEAD, REJECTED_TOPIC},
+    mqtt_status::{self, Backoff, FailureReason, MqttState},
+    mqtt_tls::{self, HardwareRng, MqttTransport, TLS_RECORD_BUFFER_LENGTH},
+    offline_queue::{self, Message},
+    protocol::{self, ConfigSet, MessageType, EVENT_SENSOR_STATUS, FLAG_BACKFILL, FLAG_MORE},
+    settings::{self, SettingsError},
+    telemetry::{self, TelemetryFilter},
 };
-use core::net::Ipv4Addr;
-use embassy_futures::select::{select3, Either3};
-use embassy_net::{tcp::TcpSocket, Stack};
-use embassy_time::Duration;
+use base64::engine::{general_purpose::STANDARD, Engine};
+use embassy_futures::select::{select, select4, Either4};
+use embassy_net::{dns::DnsQueryType, tcp::TcpSocket, Stack};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
+use embassy_time::{Duration, Timer};
+use esp_hal::rng::Rng;
 use esp_println::println;
+use heapless::{String, Vec};
 use rust_mqtt::{
     client::{client::MqttClient, client_config::ClientConfig},
     packet::v5::{publish_packet::QualityOfService, reason_codes::ReasonCode},
-    utils::rng_generator::CountingRng,
 };
+use serde::{Deserialize, Serialize};
+use static_cell::ConstStaticCell;
+use ufmt::uwrite;
+
+pub const MAX_HOST_LENGTH: usize = 64;
+/// Smallest broker packet limit that still fits a history point or a command result.
+pub const MIN_BROKER_PACKET_SIZE: usize = 256;
+
+/// Where the MQTT client connects to.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BrokerSettings {
+    /// IP address or DNS name, also sent as the TLS server name.
+    pub host: String<MAX_HOST_LENGTH>,
+    pub port: u16,
+    /// TLS is used when set, usually on port 8883.
+    pub tls: Option<TrustAnchor>,
+    pub enabled: bool,
+    /// Largest packet the broker accepts, if below the write buffer. rust-mqtt does not
+    /// expose the limit the broker sends in CONNACK.
+    pub max_packet_size: Option<u32>,
+}
+
+impl Default for BrokerSettings {
+    fn default() -> Self {
+        Self {
+            host: String::try_from("192.168.101.7").unwrap(),
+            port: 1883,
+            tls: None,
+            enabled: true,
+            max_packet_size: None,
+        }
+    }
+}
+
+/// Body of `POST /mqtt`, fields left out keep their value.
+#[derive(Debug, Deserialize)]
+pub struct BrokerUpdate {
+    pub host: Option<String<MAX_HOST_LENGTH>>,
+    pub port: Option<u16>,
+    /// Base64 DER certificate of the CA, enables TLS.
+    pub ca: Option<String<{ MAX_CA_LENGTH.div_ceil(3) * 4 }>>,
+    /// Base64 SHA-256 of the broker public key, enables TLS.
+    pub pinned_key: Option<String<44>>,
+    /// `false` switches back to plain TCP.
+    pub tls: Option<bool>,
+    pub enabled: Option<bool>,
+    /// `0` removes the limit.
+    pub max_packet_size: Option<u32>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BrokerUpdateError {
+    InvalidCa,
+    InvalidPinnedKey,
+    /// Below [`MIN_BROKER_PACKET_SIZE`].
+    InvalidMaxPacketSize,
+    Settings(SettingsError),
+}
+
+/// Reconnects the client after the broker settings changed.
+static BROKER_CHANGED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Latest readings and risk to publish, apart from the signals of the alarm path, as a
+/// signal only wakes one waiter.
+pub static READINGS_SIGNAL: Signal<CriticalSectionRawMutex, (Timestamp, SensorValues)> =
+    Signal::new();
+pub static RISK_SIGNAL: Signal<CriticalSectionRawMutex, (Timestamp, Risk)> = Signal::new();
+
+/// Applies and persists new broker settings, which take effect right away.
+pub async fn update_broker(update: BrokerUpdate) -> Result<(), BrokerUpdateError> {
+    if update
+        .max_packet_size
+        .is_some_and(|size| size > 0 && (size as usize) < MIN_BROKER_PACKET_SIZE)
+    {
+        return Err(BrokerUpdateError::InvalidMaxPacketSize);
+    }
+
+    let mut tls = None;
+
+    if let Some(ca) = update.ca {
+        let mut der = [0; MAX_CA_LENGTH];
+        let length = STANDARD
+            .decode_slice(ca.as_str(), &mut der)
+            .map_err(|_| BrokerUpdateError::InvalidCa)?;
+        tls = Some(TrustAnchor::ca(&der[..length]).ok_or(BrokerUpdateError::InvalidCa)?);
+    }
+
+    if let Some(pinned_key) = update.pinned_key {
+        let mut hash = [0; 32];
+        let length = STANDARD
+            .decode_slice(pinned_key.as_str(), &mut hash)
+            .map_err(|_| BrokerUpdateError::InvalidPinnedKey)?;
+        tls = Some(
+            TrustAnchor::pinned_key(&hash[..length])
+                .ok_or(BrokerUpdateError::InvalidPinnedKey)?,
+        );
+    }
+
+    settings::update(|settings| {
+        let broker = &mut settings.broker;
+
+        if let Some(host) = update.host {
+            broker.host = host;
+        }
+        if let Some(port) = update.port {
+            broker.port = port;
+        }
+        if let Some(enabled) = update.enabled {
+            broker.enabled = enabled;
+        }
+        if let Some(size) = update.max_packet_size {
+            broker.max_packet_size = (size > 0).then_some(size);
+        }
+        if tls.is_some() {
+            broker.tls = tls;
+        } else if update.tls == Some(false) {
+            broker.tls = None;
+        }
+    })
+    .await
+    .map_err(BrokerUpdateError::Settings)?;
+
+    BROKER_CHANGED.signal(());
+
+    Ok(())
+}
+
+/// Too large for the task future, and zero initialized in place rather than on the stack.
+static TLS_READ_BUFFER: ConstStaticCell<[u8; TLS_RECORD_BUFFER_LENGTH]> =
+    ConstStaticCell::new([0; TLS_RECORD_BUFFER_LENGTH]);
+static TLS_WRITE_BUFFER: ConstStaticCell<[u8; TLS_RECORD_BUFFER_LENGTH]> =
+    ConstStaticCell::new([0; TLS_RECORD_BUFFER_LENGTH]);
+
+static MQTT_BUFFERS: ConstStaticCell<
+    MqttBuffers<MQTT_RECV_BUFFER_LENGTH, MQTT_WRITE_BUFFER_LENGTH>,
+> = ConstStaticCell::new(MqttBuffers::new());
+
+type Client<'a, 'b> = MqttClient<'b, MqttTransport<'a>, 5, HardwareRng>;
+
+/// Records the failure and waits out the backoff, cut short by new broker settings.
+async fn back_off(backoff: &mut Backoff, reason: FailureReason) {
+    let delay = backoff.next_delay();
+    println!("Retrying MQTT in {}ms", delay.as_millis());
+    mqtt_status::record_failure(reason, delay);
+
+    select(Timer::after(delay), BROKER_CHANGED.wait()).await;
+}
 
 #[embassy_executor::task]
-pub async fn mqtt_task(stack: Stack<'static>) {
-    loop {
-        let rng = CountingRng(20000);
+pub async fn mqtt_task(stack: Stack<'static>, rng: Rng) {
+    let tls_read_buffer = TLS_READ_BUFFER.take();
+    let tls_write_buffer = TLS_WRITE_BUFFER.take();
+    let buffers = MQTT_BUFFERS.take();
+    let mut backoff = Backoff::new(rng);
 
+    'connection: loop {
         let mut rx_buffer = [0; 4096];
         let mut tx_buffer = [0; 4096];
-        let mut mqtt_recv_buffer = [0; 80];
-        let mut mqtt_write_buffer = [0; 80];
 
         let mut socket = TcpSocket::new(stack, &mut rx_buffer, &mut tx_buffer);
         socket.set_timeout(Some(Duration::from_secs(10)));
 
-        //192.168.0.117
-        //192.168.101.12
-        //172.20.10.3
+        BROKER_CHANGED.reset();
+        let broker = settings::read(|settings| settings.broker.clone())
+            .await
+            .unwrap_or_default();
+
+        if !broker.enabled {
+            mqtt_status::set_state(MqttState::Disabled);
+            BROKER_CHANGED.wait().await;
+            backoff.reset();
+            continue;
+        }
+
+        mqtt_status::set_state(MqttState::Connecting);
+
+        let address = match stack.dns_query(&broker.host, DnsQueryType::A).await {
+            Ok(addresses) if !addresses.is_empty() => addresses[0],
+            result => {
+                println!("Failed to resolve MQTT broker {}: {:?}", broker.host.as_str(), result);
+                back_off(&mut backoff, FailureReason::Dns).await;
+                continue;
+            }
+        };
 
-        let address = Ipv4Addr::new(192, 168, 101, 7);
-        if let Err(e) = socket.connect((address, 1883)).await {
+        if let Err(e) = socket.connect((address, broker.port)).await {
             println!("Failed to connect to MQTT broker: {:?}", e);
-            embassy_time::Timer::after(Duration::from_secs(5)).await;
+            back_off(&mut backoff, FailureReason::Tcp).await;
             continue;
         }
 
-        let mut config: ClientConfig<'_, 5, CountingRng> =
-            ClientConfig::new(rust_mqtt::client::client_config::MqttVersion::MQTTv5, rng);
+        let transport = match &broker.tls {
+            None => MqttTransport::Plain(socket),
+            Some(trust) => {
+                match mqtt_tls::open(
+                    socket,
+                    &broker.host,
+                    trust,
+                    &mut tls_read_buffer[..],
+                    &mut tls_write_buffer[..],
+                    rng,
+                )
+                .await
+                {
+                    Ok(transport) => transport,
+                    Err(e) => {
+                        println!("TLS handshake with MQTT broker failed: {:?}", e);
+                        back_off(&mut backoff, FailureReason::Tls).await;
+                        continue;
+                    }
+                }
+            }
+        };
+
+        let mut config: ClientConfig<'_, 5, HardwareRng> = ClientConfig::new(
+            rust_mqtt::client::client_config::MqttVersion::MQTTv5,
+            HardwareRng(rng),
+        );
 
         config.add_client_id("mydevice-client");
-        config.max_packet_size = 256;
+        // The broker drops messages above this for us instead of sending them.
+        config.max_packet_size = MQTT_RECV_BUFFER_LENGTH as u32;
         config.add_max_subscribe_qos(QualityOfService::QoS1);
 
+        let limits = buffers.limits(broker.max_packet_size);
         let mut client = MqttClient::new(
-            socket,
-            &mut mqtt_write_buffer,
-            80,
-            &mut mqtt_recv_buffer,
-            80,
+            transport,
+            &mut buffers.write,
+            MQTT_WRITE_BUFFER_LENGTH,
+            &mut buffers.recv,
+            MQTT_RECV_BUFFER_LENGTH,
             config,
         );
 
         if let Err(e) = client.connect_to_broker().await {
             println!("Failed to connect to MQTT broker: {:?}", e);
-            embassy_time::Timer::after(Duration::from_secs(5)).await;
+            back_off(&mut backoff, FailureReason::Broker).await;
             continue;
         }
 
-        if let Err(e) = client.subscribe_to_topic("config/set").await {
-            println!("Failed to subscribe: {:?}", e);
+        for topic in ["config/set", "history/get", "history/log/get", COMMAND_TOPIC] {
+            if let Err(e) = client.subscribe_to_topic(topic).await {
+                println!("Failed to subscribe to {}: {:?}", topic, e);
+                back_off(&mut backoff, FailureReason::Subscribe).await;
+                continue 'connection;
+            }
+        }
+
+        offline_queue::begin_replay();
+        mqtt_status::set_state(MqttState::Connected);
+        backoff.reset();
+
+        if let Err(e) = replay_offline(&mut client).await {
+            println!("Failed to replay buffered messages: {:?}", e);
+            back_off(&mut backoff, FailureReason::ConnectionLost).await;
             continue;
         }
 
-        loop {
-            match select3(
-                SENSOR_VALS_SIGNAL.wait(),
+        let mut last_status_flags = None;
+        let mut filter = TelemetryFilter::default();
+
+        'session: loop {
+            match select4(
+                READINGS_SIGNAL.wait(),
                 RISK_SIGNAL.wait(),
                 client.receive_message(),
+                BROKER_CHANGED.wait(),
             )
             .await
             {
-                Either3::First(sensor_values) => {
-                    println!("Sending sensor values");
-                    let bytes = sensor_values.to_bytes();
-                    if let Err(e) = client
-                        .send_message("sensors", &bytes, QualityOfService::QoS1, true)
+                Either4::First((timestamp, sensor_values)) => {
+                    let wire_format = CONFIG.lock().await.wire_format;
+
+                    let status = sensor_values.status_bytes();
+                    if last_status_flags != Some(status[0]) {
+                        println!("Sending sensor status");
+                        let result = match wire_format {
+                            WireFormat::Legacy => publish_raw(&mut client, "status", &status).await,
+                            WireFormat::Framed => {
+                                let mut event: Vec<u8, 4> = Vec::new();
+                                event.push(EVENT_SENSOR_STATUS).unwrap();
+                                event.extend_from_slice(&status).unwrap();
+                                publish_framed(&mut client, "status", MessageType::Event, 0, &event)
+                                    .await
+                            }
+                        };
+                        if let Err(e) = result {
+                            println!("Failed to send status: {:?}", e);
+                            break;
+                        }
+                        last_status_flags = Some(status[0]);
+                    }
+
+                    let policy = telemetry::policy().await;
+
+                    if filter.readings_due(&policy, &sensor_values) {
+                        println!("Sending sensor values");
+                        let bytes = sensor_values.to_payload(wire_format);
+                        if let Err(e) = publish(
+                            &mut client,
+                            wire_format,
+                            "sensors",
+                            MessageType::Readings,
+                            &bytes,
+                        )
                         .await
-                    {
-                        if e == ReasonCode::NoMatchingSubscribers {
-                            println!("No subscribers for sensors topic, message retained");
-                        } else {
+                        {
                             println!("Failed to send sensor values: {:?}", e);
+                            offline_queue::requeue(Message::Readings(timestamp, sensor_values));
                             break;
                         }
                     }
+
+                    for reading in sensor_values.extra.iter() {
+                        if !filter.channel_due(&policy, reading) {
+                            continue;
+                        }
+
+                        let mut topic: String<32> = String::new();
+                        uwrite!(
+                            &mut topic,
+                            "sensors/{}/{}",
+                            reading.kind.name(),
+                            reading.channel
+                        )
+                        .unwrap();
+                        let value_bytes = reading.value.to_le_bytes();
+                        if let Err(e) = publish_raw(&mut client, &topic, &value_bytes).await {
+                            println!("Failed to send channel {}: {:?}", reading.channel, e);
+                            break 'session;
+                        }
+                    }
                 }
-                Either3::Second(risk) => {
+                Either4::Second((timestamp, risk)) => {
+                    if !filter.risk_due(&telemetry::policy().await, risk) {
+                        continue;
+                    }
+
                     println!("Sending risk values");
+                    let wire_format = CONFIG.lock().await.wire_format;
                     let risk_byte = risk.to_byte();
-                    if let Err(e) = client
-                        .send_message("risk", &[risk_byte], QualityOfService::QoS1, true)
-                        .await
+                    if let Err(e) = publish(
+                        &mut client,
+                        wire_format,
+                        "risk",
+                        MessageType::Risk,
+                        &[risk_byte],
+                    )
+                    .await
+                    {
+                        println!("Failed to send risk: {:?}", e);
+                        offline_queue::requeue(Message::Risk(timestamp, risk));
+                        break;
+                    }
+                }
+                Either4::Third(Ok((topic, payload))) if payload.len() > inbound_limit(topic) => {
+                    println!("Rejected {} byte message on {}", payload.len(), topic);
+                    let max = inbound_limit(topic);
+                    let rejection = Rejection::too_large(topic, payload.len(), max);
+                    let Ok(rejection) = serde_json_core::to_string::<_, 128>(&rejection) else {
+                        continue;
+                    };
+                    if let Err(e) =
+                        publish_transient(&mut client, REJECTED_TOPIC, rejection.as_bytes()).await
                     {
-                        if e == ReasonCode::NoMatchingSubscribers {
-                            println!("No subscribers for risk topic, message retained");
-                        } else {
-                            println!("Failed to send risk: {:?}", e);
+                        println!("Failed to send rejection: {:?}", e);
+                        break;
+                    }
+                }
+                Either4::Third(Ok(("history/get", payload))) => {
+                    println!("History requested");
+                    let Some(query) = HistoryQuery::from_bytes(payload) else {
+                        println!("Invalid history query");
+                        continue;
+                    };
+
+                    if let Err(e) = publish_history(&mut client, limits, query).await {
+                        println!("Failed to send history: {:?}", e);
+                        break;
+                    }
+                }
+                Either4::Third(Ok(("history/log/get", payload))) => {
+                    println!("History log requested");
+                    let query = LogQuery::from_bytes(payload);
+                    if let Err(e) = publish_log(&mut client, limits, query).await {
+                        println!("Failed to send history log: {:?}", e);
+                        break;
+                    }
+                }
+                Either4::Third(Ok((COMMAND_TOPIC, payload))) => {
+                    let reply = mqtt_commands::handle(payload, limits).await;
+
+                    if let Err(e) =
+                        publish_transient(&mut client, &reply.topic, reply.payload.as_bytes()).await
+                    {
+                        println!("Failed to send command result: {:?}", e);
+                        break;
+                    }
+
+                    if reply.config_changed {
+                        if let Err(e) = publish_config(&mut client).await {
+                            println!("Config update publish failed: {:?}", e);
                             break;
                         }
                     }
                 }
-                Either3::Third(Ok((topic, payload))) => {
+                Either4::Third(Ok((topic, payload))) => {
                     println!("Config received");
                     if topic == "config/set" {
-                        if payload.len() == 6 {
-                            let new_config = Config::from_bytes(payload.try_into().unwrap());
-                            *CONFIG.lock().await = new_config.clone();
-                            let bytes = new_config.to_bytes();
-                            println!("Updating config");
-                            if let Err(e) = client
-                                .send_message("config", &bytes, QualityOfService::QoS1, true)
-                                .await
+                        let result = match parse_config_patch(payload) {
+                            Ok(patch) => commands::dispatch(Command::SetConfig(patch)).await,
+                            Err(e) => Err(e),
+                        };
+
+                        if let Err(e) = result {
+                            println!("Invalid config: {:?}", e);
+                            let error = e.to_json();
+                            if let Err(e) =
+                                publish_transient(&mut client, "config/error", error.as_bytes())
+                                    .await
                             {
-                                if e == ReasonCode::NoMatchingSubscribers {
-                                    println!("No subscribers for config topic, message retained");
-                                } else {
-                                    println!("Config update publish failed: {:?}", e);
-                                    break;
-                                }
+                                println!("Failed to send config error: {:?}", e);
+                                break;
                             }
-                        } else {
-                            println!("Invalid config payload length");
+                            continue;
+                        }
+
+                        println!("Updating config");
+                        if let Err(e) = publish_config(&mut client).await {
+                            println!("Config update publish failed: {:?}", e);
+                            break;
                         }
                     }
                 }
-                Either3::Third(Err(e)) => {
+                Either4::Third(Err(e)) => {
                     println!("MQTT receive error: {:?}", e);
                     break;
                 }
+                Either4::Fourth(()) => {
+                    println!("MQTT broker changed, reconnecting");
+                    continue 'connection;
+                }
             }
         }
 
-        embassy_time::Timer::after(Duration::from_secs(1)).await;
+        back_off(&mut backoff, FailureReason::ConnectionLost).await;
+    }
+}
+
+/// Parses a `config/set` payload: a framed config, the legacy 6 byte blob, which keeps the
+/// wire format, or a JSON [`ConfigPatch`].
+fn parse_config_patch(payload: &[u8]) -> Result<ConfigPatch, ConfigError> {
+    match protocol::decode_config_set(payload) {
+        ConfigSet::Legacy(blob) => Ok(ConfigPatch::from_bytes(blob)),
+        ConfigSet::Json(json) => serde_json_core::from_slice(json)
+            .map(|(patch, _)| patch)
+            .map_err(|_| ConfigError::Malformed),
+        ConfigSet::Framed(frame) if frame.kind() == Some(MessageType::Config) => {
+            ConfigPatch::from_payload(frame.payload)
+        }
+        ConfigSet::Framed(_) => Err(ConfigError::Malformed),
+        ConfigSet::Invalid(length) => Err(ConfigError::InvalidLength { length }),
+    }
+}
+
+/// Points or records of one `history/get` or `history/log/get` answer, larger ranges are
+/// paged through with `from`.
+const MAX_CHUNKED_ITEMS: usize = 60;
+/// Most points of a history chunk, fewer if the packet size does not allow them.
+const MQTT_HISTORY_POINTS: usize = 10;
+const HISTORY_PAYLOAD_LENGTH: usize = history_bytes_length(MQTT_HISTORY_POINTS);
+/// Most records of a log chunk.
+const MQTT_LOG_RECORDS: usize = 10;
+const LOG_PAYLOAD_LENGTH: usize = log_bytes_length(MQTT_LOG_RECORDS);
+/// Largest message published, a framed history or log payload or a command result.
+const MESSAGE_LENGTH: usize = max(
+    max(HISTORY_PAYLOAD_LENGTH, LOG_PAYLOAD_LENGTH) + protocol::FRAME_OVERHEAD,
+    RESPONSE_LENGTH,
+);
+/// Fits the largest message with its topic, at most a reply topic long, and publish
+/// packet header.
+const MQTT_WRITE_BUFFER_LENGTH: usize =
+    MESSAGE_LENGTH + MAX_REPLY_TOPIC_LENGTH + PUBLISH_OVERHEAD;
+/// Fits the largest command request with its topic, announced to the broker as the
+/// maximum packet size.
+const MQTT_RECV_BUFFER_LENGTH: usize = 2048;
+/// Largest `config/set` payload, a JSON patch with every field.
+const MAX_CONFIG_PATCH_LENGTH: usize = 256;
+/// Largest `history/get` and `history/log/get` payload.
+const MAX_QUERY_LENGTH: usize = 16;
+
+/// Largest payload handled on a subscribed topic.
+fn inbound_limit(topic: &str) -> usize {
+    match topic {
+        "config/set" => MAX_CONFIG_PATCH_LENGTH,
+        "history/get" | "history/log/get" => MAX_QUERY_LENGTH,
+        COMMAND_TOPIC => MAX_REQUEST_LENGTH,
+        _ => MQTT_RECV_BUFFER_LENGTH,
     }
 }
+
+const fn max(a: usize, b: usize) -> usize {
+    if a > b {
+        a
+    } else {
+        b
+    }
+}
+
+/// Publishes the current config, retained, in the configured wire format.
+async fn publish_config(client: &mut Client<'_, '_>) -> Result<(), ReasonCode> {
+    let config = CONFIG.lock().await.clone();
+
+    match config.wire_format {
+        WireFormat::Legacy => publish_raw(client, "config", &config.to_bytes()).await,
+        WireFormat::Framed => {
+            publish_framed(
+                client,
+                "config",
+                MessageType::Config,
+                0,
+                &config.to_payload(),
+            )
+            .await
+        }
+    }
+}
+
+/// Publishes `payload` as is for [`WireFormat::Legacy`] and framed otherwise.
+async fn publish(
+    client: &mut Client<'_, '_>,
+    wire_format: WireFormat,
+    topic: &str,
+    message_type: MessageType,
+    payload: &[u8],
+) -> Result<(), ReasonCode> {
+    match wire_format {
+        WireFormat::Legacy => publish_raw(client, topic, payload).await,
+        WireFormat::Framed => publish_framed(client, topic, message_type, 0, payload).await,
+    }
+}
+
+async fn publish_framed(
+    client: &mut Client<'_, '_>,
+    topic: &str,
+    message_type: MessageType,
+    flags: u8,
+    payload: &[u8],
+) -> Result<(), ReasonCode> {
+    let frame: Vec<u8, MESSAGE_LENGTH> = protocol::encode_vec(message_type, flags, payload)
+        .map_err(|_| ReasonCode::PayloadFormatInvalid)?;
+
+    publish_raw(client, topic, &frame).await
+}
+
+/// Publishes a retained QoS1 message, a missing subscriber is not an error since the
+/// broker keeps the message for the next one.
+async fn publish_raw(
+    client: &mut Client<'_, '_>,
+    topic: &str,
+    payload: &[u8],
+) -> Result<(), ReasonCode> {
+    match client
+        .send_message(topic, payload, QualityOfService::QoS1, true)
+        .await
+    {
+        Err(ReasonCode::NoMatchingSubscribers) => {
+            println!("No subscribers for {} topic, message retained", topic);
+            Ok(())
+        }
+        result => result,
+    }
+}
+
+/// Publishes a QoS1 message that is not retained, for answers and replays that only
+/// concern whoever is listening right now.
+async fn publish_transient(
+    client: &mut Client<'_, '_>,
+    topic: &str,
+    payload: &[u8],
+) -> Result<(), ReasonCode> {
+    match client
+        .send_message(topic, payload, QualityOfService::QoS1, false)
+        .await
+    {
+        Err(ReasonCode::NoMatchingSubscribers) => Ok(()),
+        result => result,
+    }
+}
+
+/// [`publish_transient`] of `payload`, framed with `flags` unless the wire format is
+/// [`WireFormat::Legacy`].
+async fn publish_transient_message(
+    client: &mut Client<'_, '_>,
+    wire_format: WireFormat,
+    topic: &str,
+    message_type: MessageType,
+    flags: u8,
+    payload: &[u8],
+) -> Result<(), ReasonCode> {
+    match wire_format {
+        WireFormat::Legacy => publish_transient(client, topic, payload).await,
+        WireFormat::Framed => {
+            let frame: Vec<u8, MESSAGE_LENGTH> = protocol::encode_vec(message_type, flags, payload)
+                .map_err(|_| ReasonCode::PayloadFormatInvalid)?;
+            publish_transient(client, topic, &frame).await
+        }
+    }
+}
+
+/// Bytes the wire format adds around a payload.
+fn frame_overhead(wire_format: WireFormat) -> usize {
+    match wire_format {
+        WireFormat::Legacy => 0,
+        WireFormat::Framed => protocol::FRAME_OVERHEAD,
+    }
+}
+
+/// Answers a `history/get` with up to [`MAX_CHUNKED_ITEMS`] points, in chunks that fit
+/// the outbound packet size. Every chunk but the last carries [`FLAG_MORE`].
+async fn publish_history(
+    client: &mut Client<'_, '_>,
+    limits: PacketLimits,
+    mut query: HistoryQuery,
+) -> Result<(), ReasonCode> {
+    let wire_format = CONFIG.lock().await.wire_format;
+    let per_chunk = limits.items_per_chunk(
+        "history",
+        history_bytes_length(0) + frame_overhead(wire_format),
+        history_bytes_length(1) - history_bytes_length(0),
+        MQTT_HISTORY_POINTS,
+    );
+    if per_chunk == 0 {
+        println!("MQTT packet size too small for a history point");
+        return Ok(());
+    }
+
+    let mut sent = 0;
+    loop {
+        // One point more than a chunk holds tells whether another chunk follows.
+        let mut page = VALUE_HISTORY
+            .lock()
+            .await
+            .query::<{ MQTT_HISTORY_POINTS + 1 }>(&query);
+        let count = per_chunk.min(MAX_CHUNKED_ITEMS - sent);
+        let more = page.points.len() > count && sent + count < MAX_CHUNKED_ITEMS;
+        page.points.truncate(count);
+
+        publish_transient_message(
+            client,
+            wire_format,
+            "history",
+            MessageType::History,
+            if more { FLAG_MORE } else { 0 },
+            &page.to_bytes::<HISTORY_PAYLOAD_LENGTH>(),
+        )
+        .await?;
+        sent += page.points.len();
+
+        match page.points.last() {
+            Some(last) if more => query.from = Some(last.timestamp.secs + 1),
+            _ => return Ok(()),
+        }
+    }
+}
+
+/// Answers a `history/log/get` like [`publish_history`].
+async fn publish_log(
+    client: &mut Client<'_, '_>,
+    limits: PacketLimits,
+    mut query: LogQuery,
+) -> Result<(), ReasonCode> {
+    let wire_format = CONFIG.lock().await.wire_format;
+    let per_chunk = limits.items_per_chunk(
+        "history/log",
+        log_bytes_length(0) + frame_overhead(wire_format),
+        log_bytes_length(1) - log_bytes_length(0),
+        MQTT_LOG_RECORDS,
+    );
+    if per_chunk == 0 {
+        println!("MQTT packet size too small for a log record");
+        return Ok(());
+    }
+
+    let mut sent = 0;
+    loop {
+        let mut entries = history_log::query::<{ MQTT_LOG_RECORDS + 1 }>(&query).await;
+        let count = per_chunk.min(MAX_CHUNKED_ITEMS - sent);
+        let more = entries.len() > count && sent + count < MAX_CHUNKED_ITEMS;
+        entries.truncate(count);
+
+        publish_transient_message(
+            client,
+            wire_format,
+            "history/log",
+            MessageType::Log,
+            if more { FLAG_MORE } else { 0 },
+            &log_to_bytes::<LOG_PAYLOAD_LENGTH>(&entries),
+        )
+        .await?;
+        sent += entries.len();
+
+        match entries.last() {
+            Some(last) if more => query.from = Some(last.seq + 1),
+            _ => return Ok(()),
+        }
+    }
+}
+
+/// Publishes the messages buffered while offline, oldest first, on the backfill topics.
+/// Framed messages carry [`FLAG_BACKFILL`].
+async fn replay_offline(client: &mut Client<'_, '_>) -> Result<(), ReasonCode> {
+    let dropped = offline_queue::take_dropped();
+    if dropped > 0 {
+        println!("{} buffered messages were dropped while offline", dropped);
+    }
+
+    while let Some(queued) = offline_queue::pop() {
+        let wire_format = CONFIG.lock().await.wire_format;
+        let result = publish_transient_message(
+            client,
+            wire_format,
+            queued.topic(),
+            queued.message_type(),
+            FLAG_BACKFILL,
+            &queued.to_bytes(wire_format),
+        )
+        .await;
+
+        if let Err(e) = result {
+            offline_queue::unpop(queued);
+            return Err(e);
+        }
+    }
+
+    Ok(())
+}
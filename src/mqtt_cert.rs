@@ -0,0 +1,551 @@
+//! Checks of the broker certificate, kept apart from the TLS stack so they run on the host
+//! as well, against the certificates in `host-tests/certs`.
+//!
+//! Only ECDSA P-256 keys are supported, as there is no RSA code on the device. With a CA the
+//! certificate has to be signed by it, name the broker host in its subject alternative names,
+//! or in its common name if it has none, and be valid once the clock is synced. A pinned key
+//! is trusted whatever the certificate around it says.
+
+use core::net::IpAddr;
+
+use heapless::Vec;
+use p256::ecdsa::{signature::Verifier, Signature, VerifyingKey};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// Largest DER CA certificate that can be stored.
+pub const MAX_CA_LENGTH: usize = 1024;
+
+/// What the broker certificate is checked against.
+// Kept inline like the rest of the settings, which do not allocate.
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum TrustAnchor {
+    /// DER certificate of the CA that signed the broker certificate.
+    Ca(Vec<u8, MAX_CA_LENGTH>),
+    /// SHA-256 of the DER SubjectPublicKeyInfo of the broker certificate.
+    PinnedKey([u8; 32]),
+}
+
+impl TrustAnchor {
+    /// Checks that `der` is a certificate with a P-256 key before trusting it.
+    pub fn ca(der: &[u8]) -> Option<Self> {
+        let certificate = Certificate::parse(der)?;
+        VerifyingKey::from_sec1_bytes(certificate.public_key).ok()?;
+
+        Vec::from_slice(der).ok().map(TrustAnchor::Ca)
+    }
+
+    pub fn pinned_key(hash: &[u8]) -> Option<Self> {
+        hash.try_into().ok().map(TrustAnchor::PinnedKey)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CertificateError {
+    /// Not a DER certificate with a P-256 key.
+    Malformed,
+    /// Not signed by the CA, or not the pinned key.
+    Untrusted,
+    /// Issued for another host.
+    HostMismatch,
+    /// Not yet or no longer valid, or the CA is not.
+    Expired,
+    /// The handshake is not signed by the certificate key.
+    BadSignature,
+}
+
+/// Checks the broker certificate `der` for `host`, returning the key that has to sign the
+/// handshake. The validity period is only checked with the unix time `now`.
+pub fn verify_certificate(
+    trust: &TrustAnchor,
+    der: &[u8],
+    host: &str,
+    now: Option<u64>,
+) -> Result<VerifyingKey, CertificateError> {
+    let certificate = Certificate::parse(der).ok_or(CertificateError::Malformed)?;
+
+    match trust {
+        TrustAnchor::PinnedKey(pin) => {
+            let hash: [u8; 32] = Sha256::digest(certificate.spki).into();
+            if hash != *pin {
+                return Err(CertificateError::Untrusted);
+            }
+        }
+        TrustAnchor::Ca(ca) => {
+            let ca = Certificate::parse(ca).ok_or(CertificateError::Malformed)?;
+            let ca_key = VerifyingKey::from_sec1_bytes(ca.public_key)
+                .map_err(|_| CertificateError::Malformed)?;
+            let signature = Signature::from_der(certificate.signature)
+                .map_err(|_| CertificateError::Malformed)?;
+            ca_key
+                .verify(certificate.tbs, &signature)
+                .map_err(|_| CertificateError::Untrusted)?;
+
+            if !certificate.matches_host(host) {
+                return Err(CertificateError::HostMismatch);
+            }
+            if now.is_some_and(|now| !certificate.is_valid_at(now) || !ca.is_valid_at(now)) {
+                return Err(CertificateError::Expired);
+            }
+        }
+    }
+
+    VerifyingKey::from_sec1_bytes(certificate.public_key).map_err(|_| CertificateError::Malformed)
+}
+
+/// Checks the CertificateVerify `signature` over `transcript_hash`, the transcript up to the
+/// certificate, as in RFC 8446 section 4.4.3.
+pub fn verify_handshake(
+    key: &VerifyingKey,
+    transcript_hash: &[u8; 32],
+    signature: &[u8],
+) -> Result<(), CertificateError> {
+    let signature = Signature::from_der(signature).map_err(|_| CertificateError::BadSignature)?;
+
+    key.verify(&handshake_message(transcript_hash), &signature)
+        .map_err(|_| CertificateError::BadSignature)
+}
+
+const HANDSHAKE_CONTEXT: &[u8] = b"TLS 1.3, server CertificateVerify\0";
+
+fn handshake_message(transcript_hash: &[u8; 32]) -> Vec<u8, { 64 + HANDSHAKE_CONTEXT.len() + 32 }> {
+    let mut message = Vec::new();
+    message.resize(64, b' ').unwrap();
+    message.extend_from_slice(HANDSHAKE_CONTEXT).unwrap();
+    message.extend_from_slice(transcript_hash).unwrap();
+
+    message
+}
+
+/// The parts of an X.509 certificate needed to check it.
+struct Certificate<'a> {
+    /// The encoded TBSCertificate, which the issuer signs.
+    tbs: &'a [u8],
+    /// Unix times the certificate is valid from and until.
+    not_before: u64,
+    not_after: u64,
+    /// The common name of the subject.
+    common_name: Option<&'a [u8]>,
+    /// The encoded SubjectPublicKeyInfo.
+    spki: &'a [u8],
+    /// The SEC1 point of an EC key.
+    public_key: &'a [u8],
+    /// The GeneralNames of the subject alternative name extension.
+    subject_alt_names: Option<&'a [u8]>,
+    /// The DER encoded ECDSA signature of the issuer.
+    signature: &'a [u8],
+}
+
+impl<'a> Certificate<'a> {
+    fn parse(der: &'a [u8]) -> Option<Self> {
+        let (certificate, _) = DerElement::parse(der, TAG_SEQUENCE)?;
+        let (tbs, rest) = DerElement::parse(certificate.contents, TAG_SEQUENCE)?;
+        let (_algorithm, rest) = DerElement::parse(rest, TAG_SEQUENCE)?;
+        let (signature, _) = DerElement::parse(rest, TAG_BIT_STRING)?;
+
+        // Optional version, serial, signature algorithm and issuer.
+        let mut fields = tbs.contents;
+        if fields.first() == Some(&TAG_VERSION) {
+            fields = DerElement::parse(fields, TAG_VERSION)?.1;
+        }
+        for tag in [TAG_INTEGER, TAG_SEQUENCE, TAG_SEQUENCE] {
+            fields = DerElement::parse(fields, tag)?.1;
+        }
+
+        let (validity, fields) = DerElement::parse(fields, TAG_SEQUENCE)?;
+        let (not_before, rest) = DerElement::read(validity.contents)?;
+        let (not_after, _) = DerElement::read(rest)?;
+
+        let (subject, fields) = DerElement::parse(fields, TAG_SEQUENCE)?;
+
+        let (spki, mut fields) = DerElement::parse(fields, TAG_SEQUENCE)?;
+        let (_algorithm, rest) = DerElement::parse(spki.contents, TAG_SEQUENCE)?;
+        let (public_key, _) = DerElement::parse(rest, TAG_BIT_STRING)?;
+
+        // Optional issuer and subject unique IDs, then the extensions.
+        let mut subject_alt_names = None;
+        while let Some((field, rest)) = DerElement::read(fields) {
+            if field.tag == TAG_EXTENSIONS {
+                subject_alt_names = find_extension(field.contents, OID_SUBJECT_ALT_NAME)?;
+            }
+            fields = rest;
+        }
+
+        Some(Self {
+            tbs: tbs.encoded,
+            not_before: parse_time(&not_before)?,
+            not_after: parse_time(&not_after)?,
+            common_name: find_common_name(subject.contents),
+            spki: spki.encoded,
+            // Bit strings start with the count of unused bits, always 0 here.
+            public_key: public_key.contents.get(1..)?,
+            subject_alt_names,
+            signature: signature.contents.get(1..)?,
+        })
+    }
+
+    fn is_valid_at(&self, now: u64) -> bool {
+        (self.not_before..=self.not_after).contains(&now)
+    }
+
+    /// Matches `host` against the DNS names and addresses of the subject alternative names,
+    /// or against the common name if there are none, as in RFC 6125.
+    fn matches_host(&self, host: &str) -> bool {
+        let address = host.parse::<IpAddr>().ok();
+
+        let Some(mut names) = self.subject_alt_names else {
+            return address.is_none()
+                && self
+                    .common_name
+                    .is_some_and(|name| matches_dns_name(name, host));
+        };
+
+        while let Some((name, rest)) = DerElement::read(names) {
+            let matches = match (name.tag, address) {
+                (TAG_DNS_NAME, None) => matches_dns_name(name.contents, host),
+                (TAG_IP_ADDRESS, Some(IpAddr::V4(address))) => name.contents == address.octets(),
+                (TAG_IP_ADDRESS, Some(IpAddr::V6(address))) => name.contents == address.octets(),
+                _ => false,
+            };
+            if matches {
+                return true;
+            }
+            names = rest;
+        }
+
+        false
+    }
+}
+
+/// Compares case insensitively, a leading `*` stands for exactly one label.
+fn matches_dns_name(pattern: &[u8], host: &str) -> bool {
+    let host = host.trim_end_matches('.').as_bytes();
+
+    match pattern.strip_prefix(b"*.") {
+        Some(suffix) => host
+            .iter()
+            .position(|&byte| byte == b'.')
+            .is_some_and(|dot| dot > 0 && host[dot + 1..].eq_ignore_ascii_case(suffix)),
+        None => pattern.eq_ignore_ascii_case(host),
+    }
+}
+
+/// The value of the last common name in the RDN sequence `name`.
+fn find_common_name(mut name: &[u8]) -> Option<&[u8]> {
+    let mut common_name = None;
+
+    while let Some((set, rest)) = DerElement::parse(name, TAG_SET) {
+        let mut attributes = set.contents;
+        while let Some((attribute, rest)) = DerElement::parse(attributes, TAG_SEQUENCE) {
+            let (oid, value) = DerElement::parse(attribute.contents, TAG_OID)?;
+            if oid.contents == OID_COMMON_NAME {
+                common_name = Some(DerElement::read(value)?.0.contents);
+            }
+            attributes = rest;
+        }
+        name = rest;
+    }
+
+    common_name
+}
+
+/// The contents of the extension with `id` in the `[3]` field, `None` inside if missing.
+fn find_extension<'a>(extensions: &'a [u8], id: &[u8]) -> Option<Option<&'a [u8]>> {
+    let (extensions, _) = DerElement::parse(extensions, TAG_SEQUENCE)?;
+
+    let mut rest = extensions.contents;
+    while let Some((extension, next)) = DerElement::parse(rest, TAG_SEQUENCE) {
+        let (oid, mut fields) = DerElement::parse(extension.contents, TAG_OID)?;
+        if fields.first() == Some(&TAG_BOOLEAN) {
+            fields = DerElement::parse(fields, TAG_BOOLEAN)?.1;
+        }
+        let (value, _) = DerElement::parse(fields, TAG_OCTET_STRING)?;
+
+        if oid.contents == id {
+            return Some(Some(
+                DerElement::parse(value.contents, TAG_SEQUENCE)?.0.contents,
+            ));
+        }
+        rest = next;
+    }
+
+    Some(None)
+}
+
+/// Unix time of a `YYMMDDHHMMSSZ` UTCTime or a `YYYYMMDDHHMMSSZ` GeneralizedTime.
+fn parse_time(time: &DerElement) -> Option<u64> {
+    let digits = time.contents.strip_suffix(b"Z")?;
+    let (year, digits) = match time.tag {
+        TAG_UTC_TIME => {
+            let (year, digits) = digits.split_at_checked(2)?;
+            // RFC 5280 section 4.1.2.5.1.
+            let year = parse_number(year)?;
+            (if year < 50 { 2000 + year } else { 1900 + year }, digits)
+        }
+        TAG_GENERALIZED_TIME => {
+            let (year, digits) = digits.split_at_checked(4)?;
+            (parse_number(year)?, digits)
+        }
+        _ => return None,
+    };
+
+    if digits.len() != 10 {
+        return None;
+    }
+    let field = |index: usize| parse_number(&digits[index * 2..index * 2 + 2]);
+    let (month, day) = (field(0)?, field(1)?);
+    if year < 1970 || !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+
+    Some(days_from_civil(year, month, day) * 86400 + field(2)? * 3600 + field(3)? * 60 + field(4)?)
+}
+
+fn parse_number(digits: &[u8]) -> Option<u64> {
+    digits.iter().try_fold(0, |number, digit| {
+        digit
+            .is_ascii_digit()
+            .then(|| number * 10 + (digit - b'0') as u64)
+    })
+}
+
+/// Days since 1970-01-01, from Howard Hinnant's `days_from_civil`.
+fn days_from_civil(year: u64, month: u64, day: u64) -> u64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year / 400;
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+
+    era * 146_097 + day_of_era - 719_468
+}
+
+const TAG_BOOLEAN: u8 = 0x01;
+const TAG_INTEGER: u8 = 0x02;
+const TAG_BIT_STRING: u8 = 0x03;
+const TAG_OCTET_STRING: u8 = 0x04;
+const TAG_OID: u8 = 0x06;
+const TAG_UTC_TIME: u8 = 0x17;
+const TAG_GENERALIZED_TIME: u8 = 0x18;
+const TAG_SEQUENCE: u8 = 0x30;
+const TAG_SET: u8 = 0x31;
+const TAG_DNS_NAME: u8 = 0x82;
+const TAG_IP_ADDRESS: u8 = 0x87;
+const TAG_VERSION: u8 = 0xA0;
+const TAG_EXTENSIONS: u8 = 0xA3;
+
+/// 2.5.4.3
+const OID_COMMON_NAME: &[u8] = &[0x55, 0x04, 0x03];
+/// 2.5.29.17
+const OID_SUBJECT_ALT_NAME: &[u8] = &[0x55, 0x1D, 0x11];
+
+struct DerElement<'a> {
+    tag: u8,
+    /// Tag, length and contents.
+    encoded: &'a [u8],
+    contents: &'a [u8],
+}
+
+impl<'a> DerElement<'a> {
+    /// Reads an element with the given tag, returning it and the bytes after it.
+    fn parse(bytes: &'a [u8], tag: u8) -> Option<(Self, &'a [u8])> {
+        DerElement::read(bytes).filter(|(element, _)| element.tag == tag)
+    }
+
+    /// Reads an element with any tag, returning it and the bytes after it.
+    fn read(bytes: &'a [u8]) -> Option<(Self, &'a [u8])> {
+        let (&tag, rest) = bytes.split_first()?;
+
+        let (&first, rest) = rest.split_first()?;
+        let (length, rest) = if first < 0x80 {
+            (first as usize, rest)
+        } else {
+            // Certificates are small, so the length takes at most two bytes.
+            let count = (first & 0x7F) as usize;
+            if count == 0 || count > 2 {
+                return None;
+            }
+            let (length, rest) = rest.split_at_checked(count)?;
+            let length = length
+                .iter()
+                .fold(0, |length, byte| length << 8 | *byte as usize);
+            (length, rest)
+        };
+
+        let (contents, rest) = rest.split_at_checked(length)?;
+        let element = Self {
+            tag,
+            encoded: &bytes[..bytes.len() - rest.len()],
+            contents,
+        };
+
+        Some((element, rest))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use p256::ecdsa::{signature::Signer, SigningKey};
+
+    use super::*;
+
+    const CA: &[u8] = include_bytes!("../host-tests/certs/ca.der");
+    const OTHER_CA: &[u8] = include_bytes!("../host-tests/certs/other_ca.der");
+    const BROKER: &[u8] = include_bytes!("../host-tests/certs/broker.der");
+    const EXPIRED: &[u8] = include_bytes!("../host-tests/certs/expired.der");
+    const CN_ONLY: &[u8] = include_bytes!("../host-tests/certs/cn_only.der");
+    const BROKER_KEY: &[u8] = include_bytes!("../host-tests/certs/broker_key.bin");
+
+    const HOST: &str = "broker.local";
+    /// 2030-01-01, when the test certificates except the expired one are valid.
+    const NOW: u64 = 1_893_456_000;
+
+    fn pin(der: &[u8]) -> TrustAnchor {
+        let spki = Certificate::parse(der).unwrap().spki;
+        TrustAnchor::PinnedKey(Sha256::digest(spki).into())
+    }
+
+    #[test]
+    fn accepts_certificate_signed_by_ca() {
+        let trust = TrustAnchor::ca(CA).unwrap();
+        let key = verify_certificate(&trust, BROKER, HOST, Some(NOW)).unwrap();
+
+        let broker = Certificate::parse(BROKER).unwrap();
+        assert_eq!(
+            key,
+            VerifyingKey::from_sec1_bytes(broker.public_key).unwrap()
+        );
+    }
+
+    #[test]
+    fn rejects_certificate_of_other_ca() {
+        let trust = TrustAnchor::ca(OTHER_CA).unwrap();
+
+        assert_eq!(
+            verify_certificate(&trust, BROKER, HOST, Some(NOW)),
+            Err(CertificateError::Untrusted)
+        );
+    }
+
+    #[test]
+    fn checks_pinned_key() {
+        assert!(verify_certificate(&pin(BROKER), BROKER, HOST, Some(NOW)).is_ok());
+        assert_eq!(
+            verify_certificate(&pin(CA), BROKER, HOST, Some(NOW)),
+            Err(CertificateError::Untrusted)
+        );
+    }
+
+    #[test]
+    fn pinned_key_ignores_host_and_validity() {
+        assert!(verify_certificate(&pin(EXPIRED), EXPIRED, "other.local", Some(NOW)).is_ok());
+    }
+
+    #[test]
+    fn rejects_truncated_certificate() {
+        let trust = TrustAnchor::ca(CA).unwrap();
+
+        for length in [0, 1, 10, BROKER.len() / 2, BROKER.len() - 1] {
+            assert!(Certificate::parse(&BROKER[..length]).is_none());
+            assert_eq!(
+                verify_certificate(&trust, &BROKER[..length], HOST, Some(NOW)),
+                Err(CertificateError::Malformed)
+            );
+        }
+        assert!(TrustAnchor::ca(&CA[..CA.len() - 1]).is_none());
+    }
+
+    #[test]
+    fn matches_host_against_subject_alt_names() {
+        let trust = TrustAnchor::ca(CA).unwrap();
+
+        for host in [HOST, "BROKER.local", "broker.local.", "192.168.101.7"] {
+            assert!(
+                verify_certificate(&trust, BROKER, host, Some(NOW)).is_ok(),
+                "{host}"
+            );
+        }
+        for host in [
+            "other.local",
+            "broker.local.evil",
+            "192.168.101.8",
+            "::1",
+            "",
+        ] {
+            assert_eq!(
+                verify_certificate(&trust, BROKER, host, Some(NOW)),
+                Err(CertificateError::HostMismatch),
+                "{host}"
+            );
+        }
+    }
+
+    #[test]
+    fn falls_back_to_common_name_without_subject_alt_names() {
+        let trust = TrustAnchor::ca(CA).unwrap();
+
+        assert!(verify_certificate(&trust, CN_ONLY, HOST, Some(NOW)).is_ok());
+        assert_eq!(
+            verify_certificate(&trust, CN_ONLY, "other.local", Some(NOW)),
+            Err(CertificateError::HostMismatch)
+        );
+    }
+
+    #[test]
+    fn matches_wildcard_for_one_label() {
+        assert!(matches_dns_name(b"*.example.com", "mqtt.example.com"));
+        assert!(!matches_dns_name(b"*.example.com", "example.com"));
+        assert!(!matches_dns_name(b"*.example.com", "a.mqtt.example.com"));
+        assert!(!matches_dns_name(b"*.example.com", ".example.com"));
+    }
+
+    #[test]
+    fn checks_validity_once_the_clock_is_synced() {
+        let trust = TrustAnchor::ca(CA).unwrap();
+
+        assert_eq!(
+            verify_certificate(&trust, EXPIRED, HOST, Some(NOW)),
+            Err(CertificateError::Expired)
+        );
+        assert!(verify_certificate(&trust, EXPIRED, HOST, None).is_ok());
+        // 2024-12-31T23:59:59Z, a second before the broker certificate is valid.
+        assert_eq!(
+            verify_certificate(&trust, BROKER, HOST, Some(1_735_689_599)),
+            Err(CertificateError::Expired)
+        );
+    }
+
+    #[test]
+    fn parses_validity_period() {
+        let broker = Certificate::parse(BROKER).unwrap();
+
+        // 2025-01-01 and 2125-01-01, the latter a GeneralizedTime.
+        assert_eq!(broker.not_before, 1_735_689_600);
+        assert_eq!(broker.not_after, 4_891_363_200);
+    }
+
+    #[test]
+    fn verifies_handshake_signature() {
+        let trust = TrustAnchor::ca(CA).unwrap();
+        let key = verify_certificate(&trust, BROKER, HOST, Some(NOW)).unwrap();
+        let signing_key = SigningKey::from_slice(BROKER_KEY).unwrap();
+
+        let transcript_hash = [0x5A; 32];
+        let signature: Signature = signing_key.sign(&handshake_message(&transcript_hash));
+        let signature = signature.to_der();
+
+        assert_eq!(
+            verify_handshake(&key, &transcript_hash, signature.as_bytes()),
+            Ok(())
+        );
+        assert_eq!(
+            verify_handshake(&key, &[0xA5; 32], signature.as_bytes()),
+            Err(CertificateError::BadSignature)
+        );
+        assert_eq!(
+            verify_handshake(&key, &transcript_hash, &signature.as_bytes()[1..]),
+            Err(CertificateError::BadSignature)
+        );
+    }
+}
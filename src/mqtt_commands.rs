@@ -0,0 +1,246 @@
+//! Requests on the MQTT `commands` topic, answered on the topic the request names.
+//!
+//! rust-mqtt 0.3 neither reads nor writes the MQTT v5 response topic and correlation data
+//! properties, its `send_message` and `receive_message` only carry topic and payload, so
+//! they travel in the JSON envelope until the client supports them:
+//!
+//! ```text
+//! {"id": "42", "reply_to": "commands/result/app", "command": {"silence": 60}}
+//! {"id": "42", "command": "silence", "result": {"ok": null}}
+//! ```
+//!
+//! `id` is echoed back as is. `reply_to` defaults to `commands/result` and has to be below
+//! it, so requests can not make the device publish on its own or other clients' topics.
+//! Requests naming another topic are answered on the default one with
+//! `invalid_reply_topic`.
+
+use heapless::String;
+use serde::{Deserialize, Serialize};
+
+use crate::app::{device_status, ConfigError, ConfigPatch, CONFIG};
+use crate::commands::{self, Command};
+use crate::history::{HistoryQuery, VALUE_HISTORY};
+use crate::mqtt_limits::PacketLimits;
+use crate::telemetry::{self, TelemetryPolicy, TelemetryUpdateError};
+
+pub const COMMAND_TOPIC: &str = "commands";
+pub const DEFAULT_REPLY_TOPIC: &str = "commands/result";
+/// Fits a status or a history page of [`COMMAND_HISTORY_POINTS`].
+pub const RESPONSE_LENGTH: usize = 768;
+/// Points of a `get_history` answer, larger ranges are paged through with `from`.
+const COMMAND_HISTORY_POINTS: usize = 3;
+
+const MAX_ID_LENGTH: usize = 32;
+pub const MAX_REPLY_TOPIC_LENGTH: usize = 64;
+/// Largest envelope, one with `set_telemetry` and every channel overridden.
+pub const MAX_REQUEST_LENGTH: usize = 1536;
+
+/// Everything the command topic accepts: the dashboard [`Command`]s and the reads.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Request {
+    GetConfig,
+    SetConfig(ConfigPatch),
+    AckAlarm,
+    Silence(u16),
+    SelfTest,
+    Reboot,
+    GetHistory(HistoryQuery),
+    GetStatus,
+    GetTelemetry,
+    SetTelemetry(TelemetryPolicy),
+}
+
+impl Request {
+    fn name(&self) -> &'static str {
+        match self {
+            Request::GetConfig => "get_config",
+            Request::SetConfig(_) => "set_config",
+            Request::AckAlarm => "ack_alarm",
+            Request::Silence(_) => "silence",
+            Request::SelfTest => "self_test",
+            Request::Reboot => "reboot",
+            Request::GetHistory(_) => "get_history",
+            Request::GetStatus => "get_status",
+            Request::GetTelemetry => "get_telemetry",
+            Request::SetTelemetry(_) => "set_telemetry",
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct Envelope {
+    id: Option<String<MAX_ID_LENGTH>>,
+    reply_to: Option<String<MAX_REPLY_TOPIC_LENGTH>>,
+    command: Request,
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum CommandError {
+    /// Not an envelope with a known command.
+    InvalidRequest,
+    /// `reply_to` is not `commands/result` or a topic below it.
+    InvalidReplyTopic,
+    InvalidConfig(ConfigError),
+    /// A heartbeat shorter than the minimum interval.
+    InvalidTelemetry,
+    /// The settings could not be saved.
+    StorageFailed,
+    /// The result does not fit in a message, or exceeds the packet size of the broker.
+    ResponseTooLarge,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "snake_case")]
+enum Outcome<T> {
+    Ok(T),
+    Error(CommandError),
+}
+
+#[derive(Serialize)]
+struct Response<'a, T> {
+    id: &'a str,
+    command: &'a str,
+    result: Outcome<T>,
+}
+
+pub struct Reply {
+    pub topic: String<MAX_REPLY_TOPIC_LENGTH>,
+    pub payload: String<RESPONSE_LENGTH>,
+    /// Set after a successful `set_config`, so the retained config gets republished.
+    pub config_changed: bool,
+}
+
+/// Runs a request and builds its answer, which fits in `limits`.
+pub async fn handle(payload: &[u8], limits: PacketLimits) -> Reply {
+    let Ok((envelope, _)) = serde_json_core::from_slice::<Envelope>(payload) else {
+        return Reply {
+            topic: String::try_from(DEFAULT_REPLY_TOPIC).unwrap(),
+            payload: respond::<()>("", "", Err(CommandError::InvalidRequest), usize::MAX),
+            config_changed: false,
+        };
+    };
+
+    let id = envelope.id.unwrap_or_default();
+    let name = envelope.command.name();
+    let topic = match envelope.reply_to {
+        None => String::try_from(DEFAULT_REPLY_TOPIC).unwrap(),
+        Some(topic) if is_valid_reply_topic(&topic) => topic,
+        Some(_) => {
+            return Reply {
+                topic: String::try_from(DEFAULT_REPLY_TOPIC).unwrap(),
+                payload: respond::<()>(&id, name, Err(CommandError::InvalidReplyTopic), usize::MAX),
+                config_changed: false,
+            };
+        }
+    };
+    let max = limits.payload_limit(&topic);
+    let mut config_changed = false;
+
+    let command = match envelope.command {
+        Request::GetConfig => {
+            let config = CONFIG.lock().await.clone();
+            return Reply {
+                topic,
+                payload: respond(&id, name, Ok(config), max),
+                config_changed,
+            };
+        }
+        Request::GetHistory(query) => {
+            let page = VALUE_HISTORY
+                .lock()
+                .await
+                .query::<COMMAND_HISTORY_POINTS>(&query);
+            return Reply {
+                topic,
+                payload: respond(&id, name, Ok(page), max),
+                config_changed,
+            };
+        }
+        Request::GetStatus => {
+            return Reply {
+                topic,
+                payload: respond(&id, name, Ok(device_status()), max),
+                config_changed,
+            };
+        }
+        Request::GetTelemetry => {
+            return Reply {
+                topic,
+                payload: respond(&id, name, Ok(telemetry::policy().await), max),
+                config_changed,
+            };
+        }
+        Request::SetTelemetry(policy) => {
+            let result = telemetry::update(policy).await.map_err(|e| match e {
+                TelemetryUpdateError::InvalidPolicy => CommandError::InvalidTelemetry,
+                TelemetryUpdateError::Settings(_) => CommandError::StorageFailed,
+            });
+            return Reply {
+                topic,
+                payload: respond(&id, name, result, max),
+                config_changed,
+            };
+        }
+        Request::SetConfig(patch) => {
+            config_changed = true;
+            Command::SetConfig(patch)
+        }
+        Request::AckAlarm => Command::AckAlarm,
+        Request::Silence(secs) => Command::Silence(secs),
+        Request::SelfTest => Command::SelfTest,
+        Request::Reboot => Command::Reboot,
+    };
+
+    let result = commands::dispatch(command)
+        .await
+        .map_err(CommandError::InvalidConfig);
+
+    Reply {
+        topic,
+        payload: respond(&id, name, result, max),
+        config_changed: config_changed && result.is_ok(),
+    }
+}
+
+/// `commands/result` or a topic below it, without the wildcards a publish can not carry.
+fn is_valid_reply_topic(topic: &str) -> bool {
+    let below = match topic.strip_prefix(DEFAULT_REPLY_TOPIC) {
+        Some("") => return true,
+        Some(rest) => rest.strip_prefix('/'),
+        None => None,
+    };
+
+    below.is_some_and(|below| !below.is_empty() && !below.contains(['+', '#', '\0']))
+}
+
+/// Serializes the answer, replaced by [`CommandError::ResponseTooLarge`] if it is longer
+/// than `max`.
+fn respond<T: Serialize>(
+    id: &str,
+    command: &str,
+    result: Result<T, CommandError>,
+    max: usize,
+) -> String<RESPONSE_LENGTH> {
+    let result = match result {
+        Ok(value) => Outcome::Ok(value),
+        Err(e) => Outcome::Error(e),
+    };
+
+    serde_json_core::to_string(&Response {
+        id,
+        command,
+        result,
+    })
+    .ok()
+    .filter(|response: &String<RESPONSE_LENGTH>| response.len() <= max)
+    .unwrap_or_else(|| {
+        serde_json_core::to_string(&Response::<()> {
+            id,
+            command,
+            result: Outcome::Error(CommandError::ResponseTooLarge),
+        })
+        .unwrap()
+    })
+}
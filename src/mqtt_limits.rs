@@ -0,0 +1,101 @@
+//! Buffers of the MQTT client and the packet sizes they allow in both directions.
+//!
+//! The receive buffer is announced to the broker as the maximum packet size in CONNECT, so
+//! a compliant broker drops larger messages instead of breaking the session. Outbound
+//! messages are kept within the write buffer, or the broker limit if one is configured,
+//! and answers larger than that are split into chunks.
+
+use serde::Serialize;
+
+/// Fixed header with the longest remaining length, topic length, packet id and empty
+/// properties of a publish packet.
+pub const PUBLISH_OVERHEAD: usize = 5 + 2 + 2 + 1;
+
+/// Receive and write buffers of the client, kept in a static so their size does not
+/// weigh on the task future.
+pub struct MqttBuffers<const RECV: usize, const WRITE: usize> {
+    pub recv: [u8; RECV],
+    pub write: [u8; WRITE],
+}
+
+impl<const RECV: usize, const WRITE: usize> MqttBuffers<RECV, WRITE> {
+    pub const fn new() -> Self {
+        Self {
+            recv: [0; RECV],
+            write: [0; WRITE],
+        }
+    }
+
+    /// Limits of a session with a broker accepting at most `broker_max_packet_size`.
+    pub fn limits(&self, broker_max_packet_size: Option<u32>) -> PacketLimits {
+        let outbound = broker_max_packet_size.map_or(WRITE, |size| WRITE.min(size as usize));
+
+        PacketLimits {
+            inbound: RECV,
+            outbound,
+        }
+    }
+}
+
+impl<const RECV: usize, const WRITE: usize> Default for MqttBuffers<RECV, WRITE> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Largest packets of a session, in bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PacketLimits {
+    pub inbound: usize,
+    pub outbound: usize,
+}
+
+impl PacketLimits {
+    /// Largest payload that can be published on `topic`.
+    pub fn payload_limit(&self, topic: &str) -> usize {
+        self.outbound.saturating_sub(PUBLISH_OVERHEAD + topic.len())
+    }
+
+    /// How many items of `item_length` fit in a payload on `topic` after `header_length`
+    /// bytes, at most `max`.
+    pub fn items_per_chunk(
+        &self,
+        topic: &str,
+        header_length: usize,
+        item_length: usize,
+        max: usize,
+    ) -> usize {
+        let available = self.payload_limit(topic).saturating_sub(header_length);
+
+        (available / item_length).min(max)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RejectReason {
+    /// Larger than the topic accepts.
+    TooLarge,
+}
+
+/// Published on [`REJECTED_TOPIC`] for an inbound message that was not handled.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct Rejection<'a> {
+    pub topic: &'a str,
+    pub reason: RejectReason,
+    pub length: usize,
+    pub max: usize,
+}
+
+pub const REJECTED_TOPIC: &str = "rejected";
+
+impl<'a> Rejection<'a> {
+    pub fn too_large(topic: &'a str, length: usize, max: usize) -> Self {
+        Self {
+            topic,
+            reason: RejectReason::TooLarge,
+            length,
+            max,
+        }
+    }
+}
@@ -0,0 +1,142 @@
+use core::cell::Cell;
+
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_time::Duration;
+use esp_hal::rng::Rng;
+use serde::Serialize;
+
+/// Connection state of the MQTT client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MqttState {
+    Connecting,
+    Connected,
+    /// Waiting before the next attempt after a failure.
+    Backoff,
+    /// Turned off in the broker settings.
+    Disabled,
+}
+
+/// Where a connection attempt, or an established session, failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailureReason {
+    Dns,
+    Tcp,
+    Tls,
+    /// The broker refused the MQTT connect.
+    Broker,
+    Subscribe,
+    /// A session that was up broke down.
+    ConnectionLost,
+}
+
+#[derive(Debug, Default, Clone, Copy, Serialize)]
+pub struct FailureCounters {
+    pub dns: u32,
+    pub tcp: u32,
+    pub tls: u32,
+    pub broker: u32,
+    pub subscribe: u32,
+    pub connection_lost: u32,
+}
+
+impl FailureCounters {
+    fn record(&mut self, reason: FailureReason) {
+        let counter = match reason {
+            FailureReason::Dns => &mut self.dns,
+            FailureReason::Tcp => &mut self.tcp,
+            FailureReason::Tls => &mut self.tls,
+            FailureReason::Broker => &mut self.broker,
+            FailureReason::Subscribe => &mut self.subscribe,
+            FailureReason::ConnectionLost => &mut self.connection_lost,
+        };
+        *counter = counter.saturating_add(1);
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct MqttStatus {
+    pub state: MqttState,
+    /// Delay of the current, or last, backoff.
+    pub backoff_ms: u64,
+    /// Sessions established since boot.
+    pub connections: u32,
+    pub failures: FailureCounters,
+}
+
+static MQTT_STATUS: Mutex<CriticalSectionRawMutex, Cell<MqttStatus>> =
+    Mutex::new(Cell::new(MqttStatus {
+        state: MqttState::Connecting,
+        backoff_ms: 0,
+        connections: 0,
+        failures: FailureCounters {
+            dns: 0,
+            tcp: 0,
+            tls: 0,
+            broker: 0,
+            subscribe: 0,
+            connection_lost: 0,
+        },
+    }));
+
+pub fn mqtt_status() -> MqttStatus {
+    MQTT_STATUS.lock(|status| status.get())
+}
+
+fn update_status(update: impl FnOnce(&mut MqttStatus)) {
+    MQTT_STATUS.lock(|status| {
+        let mut value = status.get();
+        update(&mut value);
+        status.set(value);
+    });
+}
+
+pub fn set_state(state: MqttState) {
+    update_status(|status| {
+        status.state = state;
+        if state == MqttState::Connected {
+            status.connections = status.connections.saturating_add(1);
+        }
+    });
+}
+
+/// Counts the failure and enters the backoff state for `delay`.
+pub fn record_failure(reason: FailureReason, delay: Duration) {
+    update_status(|status| {
+        status.failures.record(reason);
+        status.state = MqttState::Backoff;
+        status.backoff_ms = delay.as_millis();
+    });
+}
+
+/// Exponential backoff with equal jitter: the delay is at least half of the current
+/// ceiling, so retries neither hammer the broker nor line up across devices.
+pub struct Backoff {
+    rng: Rng,
+    attempt: u32,
+}
+
+impl Backoff {
+    const INITIAL: Duration = Duration::from_secs(1);
+    const MAX: Duration = Duration::from_secs(300);
+
+    pub fn new(rng: Rng) -> Self {
+        Self { rng, attempt: 0 }
+    }
+
+    /// Back to the initial delay, after a successful connection.
+    pub fn reset(&mut self) {
+        self.attempt = 0;
+    }
+
+    pub fn next_delay(&mut self) -> Duration {
+        let ceiling =
+            (Self::INITIAL.as_millis() << self.attempt.min(16)).min(Self::MAX.as_millis());
+        self.attempt = self.attempt.saturating_add(1);
+
+        let half = ceiling / 2;
+        let jitter = self.rng.random() as u64 % (half + 1);
+
+        Duration::from_millis(half + jitter)
+    }
+}
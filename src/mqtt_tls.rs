@@ -0,0 +1,223 @@
+//! TLS 1.3 transport for the MQTT client.
+//!
+//! The broker certificate is checked against a [`TrustAnchor`] from the settings: either
+//! the certificate of the CA that signed it, or a pin of its public key. The checks are in
+//! [`crate::mqtt_cert`], with CA certificates also matched against the broker host and,
+//! once SNTP set the clock, their validity period.
+//!
+//! A Mosquitto stand-in for testing needs an EC certificate and TLS 1.3, the ones made by
+//! `host-tests/certs/generate.sh` are issued for `broker.local` and 192.168.101.7:
+//!
+//! ```text
+//! listener 8883
+//! tls_version tlsv1.3
+//! certfile broker.crt
+//! keyfile broker.key
+//! ```
+//!
+//! The pin for it is the SHA-256 of the DER public key:
+//! `openssl x509 -in broker.crt -pubkey -noout | openssl pkey -pubin -outform der |
+//! openssl dgst -sha256 -binary | base64`.
+
+use embassy_net::tcp::{self, TcpSocket};
+use embedded_io_async::{ErrorKind, ErrorType, Read, Write};
+use embedded_tls::{
+    Aes128GcmSha256, CertificateEntryRef, CertificateRef, CryptoProvider, HandshakeVerifyRef,
+    SignatureScheme, TlsCipherSuite, TlsConfig, TlsConnection, TlsContext, TlsError,
+    TlsVerifier,
+};
+use esp_hal::rng::Rng;
+use esp_println::println;
+use heapless::String;
+use p256::ecdsa::VerifyingKey;
+use rand_core::{CryptoRng, RngCore};
+use sha2::Digest;
+
+use crate::clock;
+use crate::mqtt::MAX_HOST_LENGTH;
+use crate::mqtt_cert::{self, CertificateError, TrustAnchor};
+
+/// A full 16 KiB TLS record with its header and tag, brokers do not negotiate smaller ones.
+pub const TLS_RECORD_BUFFER_LENGTH: usize = 16 * 1024 + 256;
+
+/// The connection to the broker, plain TCP or TLS over it.
+pub enum MqttTransport<'a> {
+    Plain(TcpSocket<'a>),
+    Tls(TlsConnection<'a, TcpSocket<'a>, Aes128GcmSha256>),
+}
+
+#[derive(Debug)]
+pub enum TransportError {
+    Tcp(tcp::Error),
+    Tls(TlsError),
+}
+
+impl embedded_io_async::Error for TransportError {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            TransportError::Tcp(e) => e.kind(),
+            TransportError::Tls(e) => e.kind(),
+        }
+    }
+}
+
+impl ErrorType for MqttTransport<'_> {
+    type Error = TransportError;
+}
+
+impl Read for MqttTransport<'_> {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        match self {
+            MqttTransport::Plain(socket) => socket.read(buf).await.map_err(TransportError::Tcp),
+            MqttTransport::Tls(tls) => tls.read(buf).await.map_err(TransportError::Tls),
+        }
+    }
+}
+
+impl Write for MqttTransport<'_> {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        match self {
+            MqttTransport::Plain(socket) => socket.write(buf).await.map_err(TransportError::Tcp),
+            MqttTransport::Tls(tls) => tls.write(buf).await.map_err(TransportError::Tls),
+        }
+    }
+
+    async fn flush(&mut self) -> Result<(), Self::Error> {
+        match self {
+            MqttTransport::Plain(socket) => socket.flush().await.map_err(TransportError::Tcp),
+            MqttTransport::Tls(tls) => tls.flush().await.map_err(TransportError::Tls),
+        }
+    }
+}
+
+/// Runs the TLS handshake over a connected socket.
+pub async fn open<'a>(
+    socket: TcpSocket<'a>,
+    server_name: &str,
+    trust: &TrustAnchor,
+    read_buffer: &'a mut [u8],
+    write_buffer: &'a mut [u8],
+    rng: Rng,
+) -> Result<MqttTransport<'a>, TlsError> {
+    let config = TlsConfig::new().with_server_name(server_name);
+    let mut tls = TlsConnection::new(socket, read_buffer, write_buffer);
+
+    // Unknown before SNTP answered, the validity period is not checked until then.
+    let now = clock::now();
+    let provider = BrokerProvider {
+        rng: HardwareRng(rng),
+        verifier: BrokerVerifier::new(trust, server_name, now.utc.then_some(now.secs as u64)),
+    };
+    tls.open(TlsContext::new(&config, provider)).await?;
+
+    Ok(MqttTransport::Tls(tls))
+}
+
+/// The ESP32 RNG, which mixes in RF noise and is cryptographically secure while the radio
+/// is on, which it is whenever there is a broker connection.
+pub struct HardwareRng(pub Rng);
+
+impl RngCore for HardwareRng {
+    fn next_u32(&mut self) -> u32 {
+        self.0.random()
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        (self.0.random() as u64) << 32 | self.0.random() as u64
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        self.0.read(dest);
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand_core::Error> {
+        self.0.read(dest);
+        Ok(())
+    }
+}
+
+impl CryptoRng for HardwareRng {}
+
+struct BrokerProvider<'t> {
+    rng: HardwareRng,
+    verifier: BrokerVerifier<'t>,
+}
+
+impl CryptoProvider for BrokerProvider<'_> {
+    type CipherSuite = Aes128GcmSha256;
+    type Signature = &'static [u8];
+
+    fn rng(&mut self) -> impl rand_core::CryptoRngCore {
+        &mut self.rng
+    }
+
+    fn verifier(&mut self) -> Result<&mut impl TlsVerifier<Self::CipherSuite>, TlsError> {
+        Ok(&mut self.verifier)
+    }
+}
+
+struct BrokerVerifier<'t> {
+    trust: &'t TrustAnchor,
+    /// Host the certificate has to be issued for.
+    host: String<MAX_HOST_LENGTH>,
+    /// Unix time to check the validity period at.
+    now: Option<u64>,
+    /// Key of the accepted broker certificate, which has to sign the handshake.
+    key: Option<VerifyingKey>,
+    /// Transcript up to the certificate, the data the handshake signature covers.
+    transcript_hash: [u8; 32],
+}
+
+impl<'t> BrokerVerifier<'t> {
+    fn new(trust: &'t TrustAnchor, host: &str, now: Option<u64>) -> Self {
+        Self {
+            trust,
+            host: String::try_from(host).unwrap_or_default(),
+            now,
+            key: None,
+            transcript_hash: [0; 32],
+        }
+    }
+}
+
+fn tls_error(error: CertificateError) -> TlsError {
+    println!("Broker certificate rejected: {:?}", error);
+
+    match error {
+        CertificateError::BadSignature => TlsError::InvalidSignature,
+        _ => TlsError::InvalidCertificate,
+    }
+}
+
+impl TlsVerifier<Aes128GcmSha256> for BrokerVerifier<'_> {
+    fn set_hostname_verification(&mut self, hostname: &str) -> Result<(), TlsError> {
+        self.host = String::try_from(hostname).map_err(|_| TlsError::InvalidCertificate)?;
+        Ok(())
+    }
+
+    fn verify_certificate(
+        &mut self,
+        transcript: &<Aes128GcmSha256 as TlsCipherSuite>::Hash,
+        cert: CertificateRef,
+    ) -> Result<(), TlsError> {
+        let Some(CertificateEntryRef::X509(der)) = cert.entries.first() else {
+            return Err(TlsError::InvalidCertificate);
+        };
+
+        let key = mqtt_cert::verify_certificate(self.trust, der, &self.host, self.now)
+            .map_err(tls_error)?;
+        self.key = Some(key);
+        self.transcript_hash = transcript.clone().finalize().into();
+
+        Ok(())
+    }
+
+    fn verify_signature(&mut self, verify: HandshakeVerifyRef) -> Result<(), TlsError> {
+        if verify.signature_scheme != SignatureScheme::EcdsaSecp256r1Sha256 {
+            return Err(TlsError::InvalidSignatureScheme);
+        }
+        let key = self.key.as_ref().ok_or(TlsError::InvalidCertificate)?;
+
+        mqtt_cert::verify_handshake(key, &self.transcript_hash, verify.signature).map_err(tls_error)
+    }
+}
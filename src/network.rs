@@ -0,0 +1,273 @@
+//! IP configuration of the Wi-Fi interface, persisted in the settings.
+//!
+//! IPv4 uses DHCP unless a static address is stored. DHCP also takes over if the stored
+//! one is invalid, or if its gateway keeps not answering once the link is up, as long as
+//! DHCP gets a lease. Without one the static address is used again.
+//!
+//! IPv6 is optional and static. The address is the stored /64 prefix, or the link-local
+//! one without it, followed by the modified EUI-64 of the MAC. Router advertisements are
+//! not processed, so there is no SLAAC and a gateway has to be stored as well.
+
+use core::cell::Cell;
+
+use embassy_net::{
+    tcp::{ConnectError, TcpSocket},
+    ConfigV4, ConfigV6, DhcpConfig, Ipv4Address, Ipv4Cidr, Ipv6Address, Ipv6Cidr, Stack,
+    StaticConfigV4, StaticConfigV6,
+};
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_time::{with_timeout, Duration, Timer};
+use esp_println::println;
+use heapless::Vec;
+use serde::{Deserialize, Serialize};
+
+use crate::settings::{self, SettingsError};
+
+/// As many as embassy-net keeps.
+pub const MAX_DNS_SERVERS: usize = 3;
+
+const LINK_LOCAL_PREFIX: [u8; 8] = [0xFE, 0x80, 0, 0, 0, 0, 0, 0];
+
+/// Ports the gateway of a static IPv4 config is probed on, the DNS and web interface most
+/// routers have. A reset counts as an answer, so the ports do not have to be open.
+const GATEWAY_PROBE_PORTS: [u16; 2] = [53, 80];
+const GATEWAY_PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+/// Probes before DHCP is tried, doubling the pause in between, which gives a router that
+/// boots slower than the device after a power outage several minutes.
+const GATEWAY_PROBE_ATTEMPTS: u32 = 6;
+const FIRST_PROBE_PAUSE: Duration = Duration::from_secs(10);
+const MAX_PROBE_PAUSE: Duration = Duration::from_secs(120);
+/// Time DHCP gets to hand out a lease before the static config is used again.
+const DHCP_LEASE_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StaticIpv4 {
+    pub address: [u8; 4],
+    pub prefix_len: u8,
+    pub gateway: Option<[u8; 4]>,
+    pub dns_servers: Vec<[u8; 4], MAX_DNS_SERVERS>,
+}
+
+impl StaticIpv4 {
+    /// A unicast address in a subnet that contains the gateway.
+    pub fn is_valid(&self) -> bool {
+        let address = Ipv4Address::from(self.address);
+
+        if !(1..=32).contains(&self.prefix_len)
+            || address.is_unspecified()
+            || address.is_broadcast()
+            || address.is_multicast()
+        {
+            return false;
+        }
+
+        let subnet = Ipv4Cidr::new(address, self.prefix_len);
+        self.gateway
+            .is_none_or(|gateway| subnet.contains_addr(&Ipv4Address::from(gateway)))
+    }
+
+    fn cidr(&self) -> Ipv4Cidr {
+        Ipv4Cidr::new(Ipv4Address::from(self.address), self.prefix_len)
+    }
+
+    fn to_config(&self) -> ConfigV4 {
+        ConfigV4::Static(StaticConfigV4 {
+            address: self.cidr(),
+            gateway: self.gateway.map(Ipv4Address::from),
+            dns_servers: self
+                .dns_servers
+                .iter()
+                .copied()
+                .map(Ipv4Address::from)
+                .collect(),
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Ipv6Settings {
+    /// Upper half of the global address, the link-local prefix is used without one.
+    pub prefix: Option<[u8; 8]>,
+    pub gateway: Option<[u8; 16]>,
+}
+
+impl Ipv6Settings {
+    fn to_config(&self, mac: [u8; 6]) -> ConfigV6 {
+        let mut address = [0; 16];
+        address[..8].copy_from_slice(&self.prefix.unwrap_or(LINK_LOCAL_PREFIX));
+        address[8..].copy_from_slice(&interface_id(mac));
+
+        ConfigV6::Static(StaticConfigV6 {
+            address: Ipv6Cidr::new(Ipv6Address::from(address), 64),
+            gateway: self.gateway.map(Ipv6Address::from),
+            dns_servers: Vec::new(),
+        })
+    }
+}
+
+/// Modified EUI-64 interface identifier of `mac`.
+fn interface_id(mac: [u8; 6]) -> [u8; 8] {
+    [mac[0] ^ 0x02, mac[1], mac[2], 0xFF, 0xFE, mac[3], mac[4], mac[5]]
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NetworkSettings {
+    /// DHCP is used without one.
+    pub ipv4: Option<StaticIpv4>,
+    /// IPv6 is off without one.
+    pub ipv6: Option<Ipv6Settings>,
+}
+
+impl NetworkSettings {
+    pub fn is_valid(&self) -> bool {
+        self.ipv4.as_ref().is_none_or(StaticIpv4::is_valid)
+    }
+
+    fn config_v4(&self) -> ConfigV4 {
+        match &self.ipv4 {
+            Some(ipv4) if ipv4.is_valid() => ipv4.to_config(),
+            _ => ConfigV4::Dhcp(DhcpConfig::default()),
+        }
+    }
+
+    fn config_v6(&self, mac: [u8; 6]) -> ConfigV6 {
+        self.ipv6
+            .as_ref()
+            .map_or(ConfigV6::None, |ipv6| ipv6.to_config(mac))
+    }
+
+    /// Stack config of the interface with `mac`.
+    pub fn to_config(&self, mac: [u8; 6]) -> embassy_net::Config {
+        let mut config = embassy_net::Config::default();
+        config.ipv4 = self.config_v4();
+        config.ipv6 = self.config_v6(mac);
+
+        config
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NetworkUpdateError {
+    /// Not a unicast address, or a gateway outside its subnet.
+    InvalidIpv4,
+    Settings(SettingsError),
+}
+
+/// The stack and its MAC, once the interface is up.
+static STACK: Mutex<CriticalSectionRawMutex, Cell<Option<(Stack<'static>, [u8; 6])>>> =
+    Mutex::new(Cell::new(None));
+
+/// The stored settings, or DHCP only if the settings are unavailable.
+pub async fn network_settings() -> NetworkSettings {
+    settings::read(|settings| settings.network.clone())
+        .await
+        .unwrap_or_default()
+}
+
+/// Makes [`update`] apply new settings to `stack` right away.
+pub fn register(stack: Stack<'static>, mac: [u8; 6]) {
+    STACK.lock(|cell| cell.set(Some((stack, mac))));
+}
+
+/// MAC of the interface, once it is up.
+pub fn mac() -> Option<[u8; 6]> {
+    STACK.lock(Cell::get).map(|(_, mac)| mac)
+}
+
+/// Persists new settings and applies them, which drops the current addresses.
+pub async fn update(network: NetworkSettings) -> Result<(), NetworkUpdateError> {
+    if !network.is_valid() {
+        return Err(NetworkUpdateError::InvalidIpv4);
+    }
+
+    settings::update(|settings| settings.network = network.clone())
+        .await
+        .map_err(NetworkUpdateError::Settings)?;
+
+    if let Some((stack, mac)) = STACK.lock(Cell::get) {
+        stack.set_config_v4(network.config_v4());
+        stack.set_config_v6(network.config_v6(mac));
+    }
+
+    Ok(())
+}
+
+/// Falls back to DHCP if the stack uses the stored static IPv4 config and its gateway does
+/// not answer a few probes, which catches an address or gateway of another network. The
+/// static config comes back if DHCP gets no lease either, and is probed again.
+pub async fn check_static_ipv4(stack: Stack<'_>) {
+    let Some(ipv4) = network_settings().await.ipv4.filter(StaticIpv4::is_valid) else {
+        return;
+    };
+    // Without a gateway there is nothing to ask, the subnet has to be right.
+    let Some(gateway) = ipv4.gateway.map(Ipv4Address::from) else {
+        return;
+    };
+
+    loop {
+        let mut pause = FIRST_PROBE_PAUSE;
+
+        for _ in 0..GATEWAY_PROBE_ATTEMPTS {
+            stack.wait_link_up().await;
+
+            // Replaced by an update in the meantime.
+            if !stack
+                .config_v4()
+                .is_some_and(|config| config.address == ipv4.cidr())
+            {
+                return;
+            }
+
+            if gateway_answers(stack, gateway).await {
+                return;
+            }
+
+            Timer::after(pause).await;
+            pause = (pause * 2).min(MAX_PROBE_PAUSE);
+        }
+
+        println!("Gateway of the static IPv4 config does not answer, trying DHCP");
+        stack.set_config_v4(ConfigV4::Dhcp(DhcpConfig::default()));
+
+        let lease = with_timeout(DHCP_LEASE_TIMEOUT, async {
+            loop {
+                match stack.config_v4() {
+                    Some(config) => break config.address,
+                    None => Timer::after_secs(1).await,
+                }
+            }
+        })
+        .await;
+        if let Ok(address) = lease {
+            println!("Got IP from DHCP: {}", address);
+            return;
+        }
+        if network_settings().await.ipv4.as_ref() != Some(&ipv4) {
+            return;
+        }
+
+        println!("DHCP got no lease, going back to the static IPv4 config");
+        stack.set_config_v4(ipv4.to_config());
+    }
+}
+
+async fn gateway_answers(stack: Stack<'_>, gateway: Ipv4Address) -> bool {
+    let mut rx_buffer = [0; 64];
+    let mut tx_buffer = [0; 64];
+
+    for port in GATEWAY_PROBE_PORTS {
+        let mut socket = TcpSocket::new(stack, &mut rx_buffer, &mut tx_buffer);
+
+        // A timed out SYN also ends in a reset, so the timeout is kept out of the socket.
+        match with_timeout(GATEWAY_PROBE_TIMEOUT, socket.connect((gateway, port))).await {
+            Ok(Ok(())) => {
+                socket.abort();
+                return true;
+            }
+            Ok(Err(ConnectError::ConnectionReset)) => return true,
+            Ok(Err(_)) | Err(_) => {}
+        }
+    }
+
+    false
+}
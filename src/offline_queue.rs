@@ -0,0 +1,150 @@
+//! Store-and-forward of data points and risk changes while the broker is unreachable.
+//!
+//! The queue lives in RAM and drops its oldest messages once full. Longer outages are
+//! covered by the flash history log, which can be read back over `history/log/get`.
+
+use core::cell::RefCell;
+
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use heapless::{Deque, Vec};
+
+use crate::app::{Risk, SensorValues, WireFormat, SENSOR_BYTES_LENGTH};
+use crate::clock::Timestamp;
+use crate::mqtt_status::{mqtt_status, MqttState};
+use crate::protocol::MessageType;
+
+/// About five minutes of data points at the default interval.
+pub const OFFLINE_QUEUE_LENGTH: usize = 64;
+/// Capacity of [`Message::to_bytes`].
+pub const QUEUED_BYTES_LENGTH: usize = 5 + SENSOR_BYTES_LENGTH;
+
+/// A data point or risk change with the time it was taken, which stays the same however
+/// often it is queued.
+#[derive(Debug, Clone, Copy)]
+pub enum Message {
+    Readings(Timestamp, SensorValues),
+    Risk(Timestamp, Risk),
+}
+
+impl Message {
+    pub fn timestamp(&self) -> Timestamp {
+        match *self {
+            Message::Readings(timestamp, _) | Message::Risk(timestamp, _) => timestamp,
+        }
+    }
+
+    /// Topic of the replay, kept apart from the live topics so the retained values there
+    /// are not replaced by old ones.
+    pub fn topic(&self) -> &'static str {
+        match self {
+            Message::Readings(..) => "sensors/backfill",
+            Message::Risk(..) => "risk/backfill",
+        }
+    }
+
+    pub fn message_type(&self) -> MessageType {
+        match self {
+            Message::Readings(..) => MessageType::Readings,
+            Message::Risk(..) => MessageType::Risk,
+        }
+    }
+
+    /// The timestamp, resolved to UTC if the clock got synced since, followed by the
+    /// payload of the live message in `wire_format`.
+    pub fn to_bytes(&self, wire_format: WireFormat) -> Vec<u8, QUEUED_BYTES_LENGTH> {
+        let mut bytes = Vec::new();
+        bytes
+            .extend_from_slice(&self.timestamp().resolve().to_bytes())
+            .unwrap();
+
+        match self {
+            Message::Readings(_, values) => bytes
+                .extend_from_slice(&values.to_payload(wire_format))
+                .unwrap(),
+            Message::Risk(_, risk) => bytes.push(risk.to_byte()).unwrap(),
+        }
+
+        bytes
+    }
+}
+
+struct OfflineQueue {
+    messages: Deque<Message, OFFLINE_QUEUE_LENGTH>,
+    /// Messages dropped since the last replay because the queue was full.
+    dropped: u32,
+    /// Set from before the client reports connected until the queue ran empty, as the
+    /// client only publishes live messages once the replay is done.
+    replaying: bool,
+}
+
+impl OfflineQueue {
+    fn push(&mut self, message: Message) {
+        if self.messages.is_full() {
+            self.messages.pop_front();
+            self.dropped = self.dropped.saturating_add(1);
+        }
+
+        self.messages.push_back(message).unwrap();
+    }
+}
+
+static OFFLINE_QUEUE: Mutex<CriticalSectionRawMutex, RefCell<OfflineQueue>> =
+    Mutex::new(RefCell::new(OfflineQueue {
+        messages: Deque::new(),
+        dropped: 0,
+        replaying: false,
+    }));
+
+/// Queues a message, unless the client is connected and publishes it live or
+/// MQTT is disabled.
+pub fn push_if_offline(message: Message) {
+    OFFLINE_QUEUE.lock(|queue| {
+        let mut queue = queue.borrow_mut();
+
+        match mqtt_status().state {
+            MqttState::Disabled => {}
+            MqttState::Connected if !queue.replaying => {}
+            _ => queue.push(message),
+        }
+    });
+}
+
+/// Queues a live message whose publish failed, so it goes out with the next replay at the
+/// time it was taken.
+pub fn requeue(message: Message) {
+    OFFLINE_QUEUE.lock(|queue| queue.borrow_mut().push(message));
+}
+
+/// Keeps messages queued until [`pop`] emptied the queue, has to be called before the
+/// client reports connected.
+pub fn begin_replay() {
+    OFFLINE_QUEUE.lock(|queue| queue.borrow_mut().replaying = true);
+}
+
+/// Takes the oldest message for replay. Once there is none the replay is over, and new
+/// messages are left to the client.
+pub fn pop() -> Option<Message> {
+    OFFLINE_QUEUE.lock(|queue| {
+        let mut queue = queue.borrow_mut();
+
+        let message = queue.messages.pop_front();
+        queue.replaying &= message.is_some();
+        message
+    })
+}
+
+/// Puts back a message whose replay failed, so it goes out first next time.
+pub fn unpop(message: Message) {
+    OFFLINE_QUEUE.lock(|queue| {
+        let mut queue = queue.borrow_mut();
+
+        if queue.messages.push_front(message).is_err() {
+            queue.dropped = queue.dropped.saturating_add(1);
+        }
+    });
+}
+
+/// Count of messages dropped since the last call.
+pub fn take_dropped() -> u32 {
+    OFFLINE_QUEUE.lock(|queue| core::mem::take(&mut queue.borrow_mut().dropped))
+}
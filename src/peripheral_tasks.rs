@@ -1,25 +1,48 @@
 use super::app::{Risk, SensorValues};
-use crate::app::{CONFIG, VALUE_HISTORY};
+use crate::adc::SharedAdc;
+use crate::app::CONFIG;
+use crate::clock;
+use crate::commands::{self, AlarmRequest, ALARM_CONTROL_SIGNAL};
+use crate::events::{self, Event};
+use crate::flame_sensor::{FlameConfig, FlameInput, FlameSensor};
 use crate::gas_sensor::GasSensor;
+use crate::history::VALUE_HISTORY;
+use crate::history_log::{self, LogEntry};
 use crate::lcd_display;
+use crate::mqtt;
+use crate::mqtt_status::mqtt_status;
+use crate::offline_queue;
+use crate::readiness::{self, SensorState};
+use crate::sensor_registry::{
+    Reading, Readings, SensorKind, SensorRegistry, FLAME_INTENSITY_CHANNEL,
+};
 use crate::temp_sensor::TemperatureSensor;
+use crate::udp;
+use crate::utils::Temperature;
+use embassy_futures::select::{select, Either};
 use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
 use embassy_sync::signal::Signal;
-use embassy_time::Timer;
-use esp_hal::gpio::{Flex, GpioPin, Input, InputConfig, Level, Output, OutputConfig};
+use embassy_time::{Duration, Timer};
+use esp_hal::analog::adc::AdcPin;
+use esp_hal::gpio::{Flex, GpioPin, Level, Output, OutputConfig};
 use esp_hal::i2c::master::AnyI2c;
 use esp_hal::peripherals::ADC1;
 use esp_println::println;
 
-pub static SENSOR_VALS_SIGNAL: Signal<CriticalSectionRawMutex, SensorValues> = Signal::new();
-pub static RISK_SIGNAL: Signal<CriticalSectionRawMutex, Risk> = Signal::new();
+/// Readings for the display task, which computes the risk, and the risk for the alarms
+/// task. A signal only wakes one waiter, so the network side gets its own in
+/// [`crate::mqtt`].
+static SENSOR_VALS_SIGNAL: Signal<CriticalSectionRawMutex, SensorValues> = Signal::new();
+static RISK_SIGNAL: Signal<CriticalSectionRawMutex, Risk> = Signal::new();
 
 #[embassy_executor::task]
 pub async fn test_load() {
     let mut last_values = SensorValues {
-        temp: 0.,
+        temp: Temperature(0),
         gas: 0,
         flame: false,
+        gas_warmup: 0,
+        extra: Readings::new(),
     };
 
     let mut save_counter = 0;
@@ -40,46 +63,55 @@ pub async fn test_load() {
             temp: last_values.temp,
             gas: last_values.gas,
             flame: !last_values.flame,
+            gas_warmup: 0,
+            extra: Readings::new(),
         };
 
         match state {
             State::Decrease => {
-                sensor_values.temp -= 0.01;
+                sensor_values.temp -= Temperature(1);
                 sensor_values.gas -= 1
             }
             State::Increase => {
-                sensor_values.temp += 0.01;
+                sensor_values.temp += Temperature(1);
                 sensor_values.gas += 1
             }
         }
 
-        if sensor_values.temp > 90. || sensor_values.gas > 9000 {
+        if sensor_values.temp > Temperature::from_degrees(90) || sensor_values.gas > 9000 {
             state = State::Decrease;
         }
 
-        if sensor_values.temp < 5. || sensor_values.gas < 50 {
+        if sensor_values.temp < Temperature::from_degrees(5) || sensor_values.gas < 50 {
             state = State::Increase;
         }
 
-        last_values = sensor_values.clone();
+        last_values = sensor_values;
 
         save_counter += 1;
 
-        SENSOR_VALS_SIGNAL.signal(sensor_values.clone());
+        let timestamp = clock::now();
+        SENSOR_VALS_SIGNAL.signal(sensor_values);
+        mqtt::READINGS_SIGNAL.signal((timestamp, sensor_values));
+
+        let mut value_history = VALUE_HISTORY.lock().await;
+        value_history.aggregate(timestamp, &sensor_values);
 
         if save_counter > config.data_point_interval {
-            let mut value_history = VALUE_HISTORY.lock().await;
-            value_history.push_values(sensor_values);
+            value_history.push_values(timestamp, sensor_values);
             save_counter = 0;
         }
 
+        drop(value_history);
+
         risk = match risk {
             Risk::Low => Risk::Moderate,
             Risk::Moderate => Risk::High,
             Risk::High => Risk::Low,
         };
 
-        RISK_SIGNAL.signal(risk.clone());
+        RISK_SIGNAL.signal(risk);
+        mqtt::RISK_SIGNAL.signal((timestamp, risk));
 
         Timer::after_millis(1000).await;
     }
@@ -88,19 +120,24 @@ pub async fn test_load() {
 #[embassy_executor::task]
 pub async fn sensor_reader_task(
     temperature_pin: GpioPin<15>,
-    adc: ADC1,
-    pin: GpioPin<34>,
-    flame_pin: GpioPin<19>,
+    adc: &'static SharedAdc,
+    gas_pin: AdcPin<GpioPin<34>, ADC1>,
+    flame_input: FlameInput,
+    flame_config: FlameConfig,
+    registry: &'static mut SensorRegistry<'static>,
 ) {
     let mut wire_pin = Flex::new(temperature_pin);
     wire_pin.set_as_open_drain(esp_hal::gpio::Pull::Up);
     wire_pin.set_as_output();
 
-    let mut gas_sensor = GasSensor::new(adc, pin);
+    let mut gas_sensor = GasSensor::new(adc, gas_pin);
     let mut temperature_sensor = TemperatureSensor::new(&mut wire_pin).await;
 
-    let input_config = InputConfig::default();
-    let flame_sensor = Input::new(flame_pin, input_config);
+    let mut flame_sensor = match flame_input {
+        FlameInput::Polled(flame_pin) => FlameSensor::polled(flame_pin, flame_config),
+        FlameInput::EdgeCaptured => FlameSensor::edge_captured(flame_config),
+        FlameInput::Analog(flame_pin) => FlameSensor::analog(adc, flame_pin, flame_config),
+    };
 
     loop {
         let Ok(temp) = temperature_sensor.read_temperature() else {
@@ -109,12 +146,39 @@ pub async fn sensor_reader_task(
 
         let gas_value = gas_sensor.get_value().await;
 
-        let flame_value = flame_sensor.is_low();
+        let flame = flame_sensor.read().await;
+
+        // Read every time, so a changed warm-up applies right away.
+        let warmup = Duration::from_secs(CONFIG.lock().await.gas_warmup.into());
+        let gas_warmup = gas_sensor
+            .warmup_remaining(warmup)
+            .map(|remaining| remaining.as_secs().max(1) as u16)
+            .unwrap_or(0);
+
+        let mut extra = registry.read_all().await;
+        if let Some(intensity) = flame.intensity {
+            let _ = extra.push(Reading {
+                channel: FLAME_INTENSITY_CHANNEL,
+                kind: SensorKind::FlameIntensity,
+                value: intensity as i32,
+            });
+        }
 
-        SENSOR_VALS_SIGNAL.signal(SensorValues {
+        let values = SensorValues {
             temp,
             gas: gas_value,
-            flame: flame_value,
+            flame: flame.detected,
+            gas_warmup,
+            extra,
+        };
+
+        SENSOR_VALS_SIGNAL.signal(values);
+        mqtt::READINGS_SIGNAL.signal((clock::now(), values));
+        events::publish(Event::Readings(values));
+        readiness::set_sensors(if gas_warmup > 0 {
+            SensorState::WarmingUp
+        } else {
+            SensorState::Ready
         });
         Timer::after_millis(200).await;
     }
@@ -132,12 +196,19 @@ pub async fn display_task(i2c: AnyI2c, scl: GpioPin<18>, sda: GpioPin<23>) {
 
     let mut temp_alarm = TempAlarm::Disabled;
 
+    let mut last_risk = None;
+
     loop {
         let values = SENSOR_VALS_SIGNAL.wait().await;
         let config = CONFIG.lock().await.clone();
 
         display.display_temperature(values.temp);
-        display.display_gas(values.gas);
+        display.display_mqtt_state(mqtt_status().state);
+        if values.gas_ready() {
+            display.display_gas(values.gas);
+        } else {
+            display.display_gas_warmup(values.gas_warmup);
+        }
 
         let prev_temp = match &mut queue {
             Some(queue) => queue.push(values.temp),
@@ -155,18 +226,42 @@ pub async fn display_task(i2c: AnyI2c, scl: GpioPin<18>, sda: GpioPin<23>) {
             prev_temp,
         );
 
+        // Every reading is broadcast, only data points are stored and queued.
+        udp::send_readings(values);
+
+        let timestamp = clock::now();
+        let mut value_history = VALUE_HISTORY.lock().await;
+        value_history.aggregate(timestamp, &values);
+
         if save_counter > config.data_point_interval {
-            let mut value_history = VALUE_HISTORY.lock().await;
-            value_history.push_values(values);
+            value_history.push_values(timestamp, values);
+            history_log::log(LogEntry {
+                timestamp: timestamp.resolve(),
+                risk,
+                values,
+            });
+            offline_queue::push_if_offline(offline_queue::Message::Readings(timestamp, values));
             save_counter = 0;
         }
 
+        drop(value_history);
+
         save_counter += 1;
 
-        if config.alarms_enabled {
-            RISK_SIGNAL.signal(risk);
+        let alarm_risk = if config.alarms_enabled {
+            risk
         } else {
-            RISK_SIGNAL.signal(Risk::Low);
+            Risk::Low
+        };
+
+        RISK_SIGNAL.signal(alarm_risk);
+        mqtt::RISK_SIGNAL.signal((timestamp, alarm_risk));
+
+        if last_risk != Some(alarm_risk) {
+            events::publish(Event::Risk(alarm_risk));
+            offline_queue::push_if_offline(offline_queue::Message::Risk(timestamp, alarm_risk));
+            udp::send_risk(alarm_risk);
+            last_risk = Some(alarm_risk);
         }
     }
 }
@@ -174,17 +269,17 @@ pub async fn display_task(i2c: AnyI2c, scl: GpioPin<18>, sda: GpioPin<23>) {
 #[derive(Clone)]
 enum TempAlarm {
     Disabled,
-    Enabled { temp: f64 },
+    Enabled { temp: Temperature },
 }
 
 fn is_temp_alarm(
     temp_alarm: &mut TempAlarm,
-    temp_delta_threshold: f64,
-    current_temp: f64,
-    prev_temp: f64,
+    temp_delta_threshold: Temperature,
+    current_temp: Temperature,
+    prev_temp: Temperature,
 ) -> bool {
     if let TempAlarm::Enabled { temp } = temp_alarm.clone() {
-        if current_temp >= temp - 1. {
+        if current_temp >= temp - Temperature::from_degrees(1) {
             return true;
         } else {
             *temp_alarm = TempAlarm::Disabled;
@@ -205,17 +300,20 @@ fn is_temp_alarm(
 fn get_risk(
     sensor_values: &SensorValues,
     gas_threshold: u16,
-    temp_delta_threshold: f64,
+    temp_delta_threshold: Temperature,
     temp_alarm: &mut TempAlarm,
-    prev_temp: f64,
+    prev_temp: Temperature,
 ) -> Risk {
     if sensor_values.flame {
         return Risk::High;
     }
 
+    // Readings taken while the heater warms up are meaningless, keep them out of the risk.
+    let gas_alarm = sensor_values.gas_ready() && sensor_values.gas > gas_threshold;
+
     let delta_temperature = sensor_values.temp - prev_temp;
     println!("delta temperature: {delta_temperature}");
-    if sensor_values.gas > gas_threshold
+    if gas_alarm
         && is_temp_alarm(
             temp_alarm,
             temp_delta_threshold,
@@ -226,7 +324,7 @@ fn get_risk(
         return Risk::High;
     }
 
-    if sensor_values.gas > gas_threshold || delta_temperature > temp_delta_threshold {
+    if gas_alarm || delta_temperature > temp_delta_threshold {
         return Risk::Moderate;
     }
 
@@ -244,8 +342,27 @@ pub async fn alarms_task(
     let mut g = Output::new(green, Level::Low, OutputConfig::default());
     let mut b = Output::new(blue, Level::Low, OutputConfig::default());
     let mut piezzo_buzzer = Output::new(buzzer, Level::Low, OutputConfig::default());
+    let mut risk = Risk::Low;
+    readiness::set_alarms_armed();
     loop {
-        let risk = RISK_SIGNAL.wait().await;
+        match select(RISK_SIGNAL.wait(), ALARM_CONTROL_SIGNAL.wait()).await {
+            Either::First(new_risk) => risk = new_risk,
+            Either::Second(AlarmRequest::Refresh) => {}
+            Either::Second(AlarmRequest::SelfTest) => {
+                println!("Alarm self-test");
+                self_test(&mut r, &mut g, &mut b, &mut piezzo_buzzer).await;
+            }
+        }
+
+        if risk != Risk::High {
+            commands::clear_acknowledgement();
+        }
+
+        let buzzer_level = if commands::alarm_control().buzzer_muted() {
+            Level::Low
+        } else {
+            Level::High
+        };
 
         match risk {
             Risk::Low => {
@@ -267,25 +384,42 @@ pub async fn alarms_task(
                 r.set_level(Level::High); // Rojo
                 g.set_level(Level::Low);
                 b.set_level(Level::Low);
-                piezzo_buzzer.set_level(Level::High);
+                piezzo_buzzer.set_level(buzzer_level);
             }
         }
     }
 }
 
+/// Lights every color and sounds the buzzer once, the next risk restores the outputs.
+async fn self_test(
+    r: &mut Output<'_>,
+    g: &mut Output<'_>,
+    b: &mut Output<'_>,
+    piezzo_buzzer: &mut Output<'_>,
+) {
+    for led in [r, g, b] {
+        led.set_level(Level::High);
+        piezzo_buzzer.set_level(Level::High);
+        Timer::after_millis(300).await;
+        led.set_level(Level::Low);
+        piezzo_buzzer.set_level(Level::Low);
+        Timer::after_millis(200).await;
+    }
+}
+
 struct Queue<const N: usize> {
     pointer: usize,
-    array: [f64; N],
+    array: [Temperature; N],
 }
 
 impl<const N: usize> Queue<N> {
-    fn new(default: f64) -> Queue<N> {
+    fn new(default: Temperature) -> Queue<N> {
         let array = [default; N];
 
         Queue { pointer: 0, array }
     }
 
-    fn push(&mut self, temp: f64) -> f64 {
+    fn push(&mut self, temp: Temperature) -> Temperature {
         let last_temp = self.array[self.pointer];
         self.array[self.pointer] = temp;
 
@@ -0,0 +1,513 @@
+//! Versioned binary wire protocol used on MQTT (and any other transport) when the
+//! configured wire format is [`crate::app::WireFormat::Framed`].
+//!
+//! This module only depends on `core` and `heapless`, so host tools can include it with
+//! `#[path = "src/protocol.rs"] mod protocol;` to decode what the device sends. The
+//! `host-tests` crate does so to run its tests with `cargo test`.
+//!
+//! # Frame
+//!
+//! All integers are little endian.
+//!
+//! | offset | size | field                                               |
+//! |--------|------|-----------------------------------------------------|
+//! | 0      | 1    | magic, always `0xE5`                                |
+//! | 1      | 1    | protocol version, currently `1`                     |
+//! | 2      | 1    | message type                                        |
+//! | 3      | 1    | flags, see below                                    |
+//! | 4      | 2    | payload length `n`                                  |
+//! | 6      | n    | payload                                             |
+//! | 6 + n  | 2    | CRC-16/CCITT-FALSE of bytes `0..6 + n`              |
+//!
+//! Flag bit 0 is set on replayed (backfill) messages, bit 1 on every chunk of an answer
+//! but the last.
+//!
+//! The version is only bumped for incompatible changes. New fields are appended to the
+//! end of a payload, so decoders must ignore payload bytes past the fields they know and
+//! skip message types they do not know.
+//!
+//! # Payloads
+//!
+//! * `0x01` readings: `i16` temperature in hundredths of °C, `u16` gas, `u8` flame, then
+//!   for every additional channel `u8` channel id, `u8` sensor kind and `i32` value.
+//! * `0x02` history: `u8` resolution (`0` raw, `1` minute, `2` hour), `u8` point count,
+//!   then every point as `u8` length followed by, oldest first:
+//!   * the timestamp, `u8` clock (`0` seconds since boot or `1` UTC) and `u32` seconds,
+//!     the start of the bucket for aggregated points,
+//!   * `i16` temperature min, avg and max in hundredths of °C,
+//!   * `u16` gas min, avg and max,
+//!   * `u16` samples with a flame and `u16` sample count, `1` for raw points,
+//!   * the additional channels as in the readings payload, raw points only.
+//! * `0x03` config: `i16` temperature delta threshold in hundredths of °C, `u16` gas
+//!   threshold, `u8` alarms enabled, `u8` data point interval, `u8` wire format
+//!   (`0` legacy, `1` framed), `u16` gas warm-up seconds. Set config payloads may end
+//!   before the warm-up, which keeps it unchanged.
+//! * `0x04` risk: `u8` risk, `0` low, `1` moderate, `2` high.
+//! * `0x05` event: `u8` event code followed by its data.
+//!   * `0x01` sensor status: `u8` flags (bit 0 set while the gas sensor warms up) and
+//!     `u16` remaining warm-up seconds.
+//! * `0x06` log: `u8` record count, then every record of the flash history log as `u8`
+//!   length followed by the `u32` sequence number, the timestamp, `u8` risk and a
+//!   readings payload, oldest first.
+//! * `0x07` discover: no payload, see below.
+//! * `0x08` announce: the 6 byte MAC, which is the device ID, the `[u8; 4]` IPv4 address,
+//!   `u16` HTTP port, `u16` UDP telemetry port (`0` while off), `u8` capabilities (bit 0
+//!   HTTP API, bit 1 MQTT, bit 2 UDP telemetry, bit 3 mDNS), then the device name and the
+//!   firmware version, each as `u8` length followed by UTF-8.
+//!
+//! # Backfill
+//!
+//! Data points and risk changes taken while the broker was unreachable are published
+//! once the session is back, oldest first, on `sensors/backfill` and `risk/backfill`.
+//! Their payload is the timestamp they were taken at, as in the history payload,
+//! followed by the usual readings or risk payload, and framed ones have the backfill flag
+//! set.
+//!
+//! # Chunks
+//!
+//! Answers to `history/get` and `history/log/get` are split into as many messages as the
+//! packet size allows, each a complete history or log payload, up to 60 points or
+//! records per request. Larger ranges are paged through with `from` as before. The chunks
+//! are not retained.
+//!
+//! # UDP
+//!
+//! Without a broker, collectors find devices by broadcasting a discover frame to UDP port
+//! 4210. Every device answers the sender with an announce frame. Devices with UDP
+//! telemetry on also broadcast a readings frame at every data point and a risk frame at
+//! every risk change to the configured port, from port 4210. The source address tells the
+//! devices apart, discovery maps it to the device ID.
+//!
+//! # Commands
+//!
+//! Binary dashboard commands, see [`crate::commands::Command::from_bytes`], are a `u8`
+//! opcode followed by its arguments, without frame:
+//!
+//! * `0x01` set config: a config payload.
+//! * `0x02` acknowledge alarm.
+//! * `0x03` silence: `u16` seconds.
+//! * `0x04` self-test.
+//! * `0x05` reboot.
+//!
+//! # Config errors
+//!
+//! A rejected `config/set` is answered on `config/error` with a JSON object, see
+//! [`crate::app::ConfigError`], in both wire formats.
+//!
+//! # Rejected messages
+//!
+//! An inbound message larger than its topic accepts is answered on `rejected` with
+//! `{"topic": "config/set", "reason": "too_large", "length": 900, "max": 512}`.
+//! Packets larger than the receive buffer never reach the device, it announces the
+//! buffer as its maximum packet size and the broker drops them.
+//!
+//! # Legacy blobs
+//!
+//! In [`crate::app::WireFormat::Legacy`] mode the device keeps publishing the bare
+//! payloads without frame: the 5 byte readings (without the additional channels, also
+//! after the timestamp of a backfill), the 6 byte config (without the wire format and
+//! the warm-up), the 1 byte risk and the 3 byte status. `config/set` takes the 6 byte
+//! config in both wire formats, see [`decode_config_set`].
+
+use heapless::Vec;
+
+pub const MAGIC: u8 = 0xE5;
+pub const VERSION: u8 = 1;
+pub const HEADER_LENGTH: usize = 6;
+pub const CRC_LENGTH: usize = 2;
+/// Bytes a frame adds around its payload.
+pub const FRAME_OVERHEAD: usize = HEADER_LENGTH + CRC_LENGTH;
+
+/// Flag set on messages replayed after they were buffered.
+pub const FLAG_BACKFILL: u8 = 0x01;
+/// Flag set on every chunk of an answer but the last.
+pub const FLAG_MORE: u8 = 0x02;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageType {
+    Readings,
+    History,
+    Config,
+    Risk,
+    Event,
+    Log,
+    Discover,
+    Announce,
+}
+
+impl MessageType {
+    pub fn to_byte(&self) -> u8 {
+        match self {
+            MessageType::Readings => 0x01,
+            MessageType::History => 0x02,
+            MessageType::Config => 0x03,
+            MessageType::Risk => 0x04,
+            MessageType::Event => 0x05,
+            MessageType::Log => 0x06,
+            MessageType::Discover => 0x07,
+            MessageType::Announce => 0x08,
+        }
+    }
+
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0x01 => Some(MessageType::Readings),
+            0x02 => Some(MessageType::History),
+            0x03 => Some(MessageType::Config),
+            0x04 => Some(MessageType::Risk),
+            0x05 => Some(MessageType::Event),
+            0x06 => Some(MessageType::Log),
+            0x07 => Some(MessageType::Discover),
+            0x08 => Some(MessageType::Announce),
+            _ => None,
+        }
+    }
+}
+
+/// Codes of the `0x05` event messages.
+pub const EVENT_SENSOR_STATUS: u8 = 0x01;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Frame<'a> {
+    /// Raw type byte, so that messages added by newer firmware can be skipped.
+    pub message_type: u8,
+    pub flags: u8,
+    pub payload: &'a [u8],
+}
+
+impl Frame<'_> {
+    pub fn kind(&self) -> Option<MessageType> {
+        MessageType::from_byte(self.message_type)
+    }
+
+    pub fn is_backfill(&self) -> bool {
+        self.flags & FLAG_BACKFILL != 0
+    }
+
+    /// Whether more chunks of the same answer follow.
+    pub fn has_more(&self) -> bool {
+        self.flags & FLAG_MORE != 0
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeError {
+    TooShort,
+    BadMagic(u8),
+    UnsupportedVersion(u8),
+    LengthMismatch { declared: usize, available: usize },
+    BadCrc { expected: u16, actual: u16 },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BufferTooSmall;
+
+/// Length of the legacy readings blob, the start of a readings payload.
+pub const LEGACY_READINGS_LENGTH: usize = 5;
+/// Length of the legacy config blob.
+pub const LEGACY_CONFIG_LENGTH: usize = 6;
+
+/// The legacy blob of a readings payload, without the additional channels deployed
+/// consumers do not expect.
+pub fn legacy_readings(payload: &[u8]) -> &[u8] {
+    &payload[..payload.len().min(LEGACY_READINGS_LENGTH)]
+}
+
+/// Encoding of a `config/set` payload.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigSet<'a> {
+    Legacy([u8; LEGACY_CONFIG_LENGTH]),
+    /// A JSON patch.
+    Json(&'a [u8]),
+    /// A valid frame, which still has to be a config.
+    Framed(Frame<'a>),
+    /// Neither, with the length of the payload.
+    Invalid(usize),
+}
+
+/// Tells the encodings of a `config/set` payload apart. 6 bytes are the legacy blob even if
+/// they start with `{`, the low byte of a temperature threshold of 1.23 °C, as a JSON
+/// patch setting any field is longer.
+pub fn decode_config_set(payload: &[u8]) -> ConfigSet<'_> {
+    if let Ok(blob) = payload.try_into() {
+        return ConfigSet::Legacy(blob);
+    }
+
+    if payload.first() == Some(&b'{') {
+        return ConfigSet::Json(payload);
+    }
+
+    match decode(payload) {
+        Ok(frame) => ConfigSet::Framed(frame),
+        Err(_) => ConfigSet::Invalid(payload.len()),
+    }
+}
+
+/// Writes a frame into `out`, returning the number of bytes used.
+pub fn encode(
+    message_type: MessageType,
+    flags: u8,
+    payload: &[u8],
+    out: &mut [u8],
+) -> Result<usize, BufferTooSmall> {
+    let length = payload.len() + FRAME_OVERHEAD;
+
+    if out.len() < length || payload.len() > u16::MAX as usize {
+        return Err(BufferTooSmall);
+    }
+
+    let payload_length = (payload.len() as u16).to_le_bytes();
+    out[..HEADER_LENGTH].copy_from_slice(&[
+        MAGIC,
+        VERSION,
+        message_type.to_byte(),
+        flags,
+        payload_length[0],
+        payload_length[1],
+    ]);
+    out[HEADER_LENGTH..HEADER_LENGTH + payload.len()].copy_from_slice(payload);
+
+    let crc = crc16(&out[..HEADER_LENGTH + payload.len()]);
+    out[HEADER_LENGTH + payload.len()..length].copy_from_slice(&crc.to_le_bytes());
+
+    Ok(length)
+}
+
+/// [`encode`] into a `heapless::Vec` of capacity `N`.
+pub fn encode_vec<const N: usize>(
+    message_type: MessageType,
+    flags: u8,
+    payload: &[u8],
+) -> Result<Vec<u8, N>, BufferTooSmall> {
+    let mut out = Vec::new();
+    out.resize(N, 0).map_err(|_| BufferTooSmall)?;
+
+    let length = encode(message_type, flags, payload, &mut out)?;
+    out.truncate(length);
+
+    Ok(out)
+}
+
+/// Validates the frame at the start of `bytes`, trailing bytes are ignored.
+pub fn decode(bytes: &[u8]) -> Result<Frame<'_>, DecodeError> {
+    if bytes.len() < FRAME_OVERHEAD {
+        return Err(DecodeError::TooShort);
+    }
+
+    if bytes[0] != MAGIC {
+        return Err(DecodeError::BadMagic(bytes[0]));
+    }
+
+    if bytes[1] != VERSION {
+        return Err(DecodeError::UnsupportedVersion(bytes[1]));
+    }
+
+    let payload_length = u16::from_le_bytes([bytes[4], bytes[5]]) as usize;
+    let length = payload_length + FRAME_OVERHEAD;
+
+    if bytes.len() < length {
+        return Err(DecodeError::LengthMismatch {
+            declared: payload_length,
+            available: bytes.len() - FRAME_OVERHEAD,
+        });
+    }
+
+    let crc_offset = HEADER_LENGTH + payload_length;
+    let expected = u16::from_le_bytes([bytes[crc_offset], bytes[crc_offset + 1]]);
+    let actual = crc16(&bytes[..crc_offset]);
+
+    if expected != actual {
+        return Err(DecodeError::BadCrc { expected, actual });
+    }
+
+    Ok(Frame {
+        message_type: bytes[2],
+        flags: bytes[3],
+        payload: &bytes[HEADER_LENGTH..crc_offset],
+    })
+}
+
+/// CRC-16/CCITT-FALSE, polynomial 0x1021 and initial value 0xFFFF.
+pub fn crc16(bytes: &[u8]) -> u16 {
+    let mut crc = 0xFFFF_u16;
+
+    for byte in bytes {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PAYLOAD: [u8; 5] = [0x10, 0x09, 0x2C, 0x01, 0x00];
+
+    fn frame() -> Vec<u8, 32> {
+        encode_vec(MessageType::Readings, FLAG_BACKFILL, &PAYLOAD).unwrap()
+    }
+
+    #[test]
+    fn crc16_matches_check_value() {
+        assert_eq!(crc16(b"123456789"), 0x29B1);
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut out = [0; 32];
+        let length = encode(MessageType::Config, FLAG_MORE, &PAYLOAD, &mut out).unwrap();
+
+        assert_eq!(length, PAYLOAD.len() + FRAME_OVERHEAD);
+
+        let frame = decode(&out[..length]).unwrap();
+        assert_eq!(frame.kind(), Some(MessageType::Config));
+        assert!(frame.has_more());
+        assert!(!frame.is_backfill());
+        assert_eq!(frame.payload, PAYLOAD);
+    }
+
+    #[test]
+    fn round_trip_every_message_type() {
+        for byte in 0x01..=0x08 {
+            let kind = MessageType::from_byte(byte).unwrap();
+            let encoded: Vec<u8, 32> = encode_vec(kind, 0, &[byte]).unwrap();
+
+            let frame = decode(&encoded).unwrap();
+            assert_eq!(frame.kind(), Some(kind));
+            assert_eq!(frame.payload, [byte]);
+        }
+    }
+
+    #[test]
+    fn round_trip_empty_payload() {
+        let encoded: Vec<u8, 8> = encode_vec(MessageType::Discover, 0, &[]).unwrap();
+
+        assert_eq!(encoded.len(), FRAME_OVERHEAD);
+        assert_eq!(decode(&encoded).unwrap().payload, []);
+    }
+
+    #[test]
+    fn ignores_trailing_bytes() {
+        let mut bytes = frame();
+        bytes.extend_from_slice(&[0xAA, 0xBB]).unwrap();
+
+        let frame = decode(&bytes).unwrap();
+        assert!(frame.is_backfill());
+        assert_eq!(frame.payload, PAYLOAD);
+    }
+
+    #[test]
+    fn keeps_unknown_message_types() {
+        let mut bytes = frame();
+        bytes[2] = 0x7F;
+        let crc_offset = bytes.len() - CRC_LENGTH;
+        let crc = crc16(&bytes[..crc_offset]).to_le_bytes();
+        bytes[crc_offset..].copy_from_slice(&crc);
+
+        let frame = decode(&bytes).unwrap();
+        assert_eq!(frame.message_type, 0x7F);
+        assert_eq!(frame.kind(), None);
+    }
+
+    #[test]
+    fn rejects_short_input() {
+        assert_eq!(
+            decode(&frame()[..FRAME_OVERHEAD - 1]),
+            Err(DecodeError::TooShort)
+        );
+    }
+
+    #[test]
+    fn rejects_bad_magic() {
+        let mut bytes = frame();
+        bytes[0] = 0x42;
+
+        assert_eq!(decode(&bytes), Err(DecodeError::BadMagic(0x42)));
+    }
+
+    #[test]
+    fn rejects_bad_version() {
+        let mut bytes = frame();
+        bytes[1] = VERSION + 1;
+
+        assert_eq!(
+            decode(&bytes),
+            Err(DecodeError::UnsupportedVersion(VERSION + 1))
+        );
+    }
+
+    #[test]
+    fn rejects_bad_length() {
+        let bytes = frame();
+
+        assert_eq!(
+            decode(&bytes[..bytes.len() - 1]),
+            Err(DecodeError::LengthMismatch {
+                declared: PAYLOAD.len(),
+                available: PAYLOAD.len() - 1,
+            })
+        );
+    }
+
+    #[test]
+    fn rejects_bad_crc() {
+        let mut bytes = frame();
+        bytes[HEADER_LENGTH] ^= 0x01;
+
+        assert!(matches!(decode(&bytes), Err(DecodeError::BadCrc { .. })));
+    }
+
+    #[test]
+    fn leaves_additional_channels_out_of_legacy_readings() {
+        // Humidity of 45.00 % on channel 16 after the readings of `PAYLOAD`.
+        let payload = [0x10, 0x09, 0x2C, 0x01, 0x00, 16, 1, 0x94, 0x11, 0x00, 0x00];
+
+        assert_eq!(legacy_readings(&payload), PAYLOAD);
+        assert_eq!(legacy_readings(&PAYLOAD), PAYLOAD);
+    }
+
+    #[test]
+    fn decodes_legacy_config_starting_with_brace() {
+        // 1.23 °C, 1500, alarms on, every 3 readings.
+        let blob = [0x7B, 0x00, 0xDC, 0x05, 0x01, 0x03];
+
+        assert_eq!(decode_config_set(&blob), ConfigSet::Legacy(blob));
+    }
+
+    #[test]
+    fn decodes_config_set_encodings() {
+        let json = br#"{"gas_threshold":1200}"#;
+        assert_eq!(decode_config_set(json), ConfigSet::Json(json));
+
+        let framed: Vec<u8, 32> = encode_vec(MessageType::Config, 0, &[0; 7]).unwrap();
+        assert!(matches!(
+            decode_config_set(&framed),
+            ConfigSet::Framed(frame) if frame.kind() == Some(MessageType::Config)
+        ));
+
+        assert_eq!(decode_config_set(b"{}"), ConfigSet::Json(b"{}"));
+        assert_eq!(decode_config_set(&[0; 5]), ConfigSet::Invalid(5));
+    }
+
+    #[test]
+    fn rejects_small_buffer() {
+        let mut out = [0; PAYLOAD.len() + FRAME_OVERHEAD - 1];
+
+        assert_eq!(
+            encode(MessageType::Readings, 0, &PAYLOAD, &mut out),
+            Err(BufferTooSmall)
+        );
+        assert_eq!(
+            encode_vec::<12>(MessageType::Readings, 0, &PAYLOAD),
+            Err(BufferTooSmall)
+        );
+    }
+}
@@ -0,0 +1,99 @@
+//! Readiness of the device. The sensing and alarm pipeline starts first and works on its
+//! own, the network comes up after it and may never do so.
+
+use core::cell::Cell;
+
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use esp_println::println;
+use serde::Serialize;
+
+/// Overall state, from the parts in [`ReadinessStatus`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Readiness {
+    /// No reading taken or alarms not armed yet.
+    Starting,
+    /// Alarms work locally, but the gas sensor warms up or the network is not up.
+    Degraded,
+    Ready,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SensorState {
+    Starting,
+    /// Readings come in, the gas sensor is left out of the risk until it is warm.
+    WarmingUp,
+    Ready,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NetworkState {
+    Down,
+    /// Associated with the access point, waiting for an address.
+    LinkUp,
+    Configured,
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct ReadinessStatus {
+    pub state: Readiness,
+    pub sensors: SensorState,
+    pub alarms_armed: bool,
+    pub network: NetworkState,
+}
+
+impl ReadinessStatus {
+    const fn new() -> Self {
+        Self {
+            state: Readiness::Starting,
+            sensors: SensorState::Starting,
+            alarms_armed: false,
+            network: NetworkState::Down,
+        }
+    }
+
+    fn update_state(&mut self) {
+        self.state = match (self.sensors, self.alarms_armed, self.network) {
+            (SensorState::Starting, _, _) | (_, false, _) => Readiness::Starting,
+            (SensorState::Ready, true, NetworkState::Configured) => Readiness::Ready,
+            _ => Readiness::Degraded,
+        };
+    }
+}
+
+static READINESS: Mutex<CriticalSectionRawMutex, Cell<ReadinessStatus>> =
+    Mutex::new(Cell::new(ReadinessStatus::new()));
+
+pub fn readiness() -> ReadinessStatus {
+    READINESS.lock(Cell::get)
+}
+
+fn modify(modify: impl FnOnce(&mut ReadinessStatus)) {
+    let (previous, current) = READINESS.lock(|cell| {
+        let mut status = cell.get();
+        let previous = status.state;
+        modify(&mut status);
+        status.update_state();
+        cell.set(status);
+
+        (previous, status.state)
+    });
+
+    if current != previous {
+        println!("Device readiness: {:?}", current);
+    }
+}
+
+pub fn set_sensors(sensors: SensorState) {
+    modify(|status| status.sensors = sensors);
+}
+
+pub fn set_alarms_armed() {
+    modify(|status| status.alarms_armed = true);
+}
+
+pub fn set_network(network: NetworkState) {
+    modify(|status| status.network = network);
+}
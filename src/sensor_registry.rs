@@ -0,0 +1,292 @@
+use anyhow::Result;
+use embassy_futures::yield_now;
+use embassy_time::{Duration, Timer};
+use esp_hal::{
+    analog::adc::{AdcChannel, AdcPin},
+    gpio::Input,
+    peripherals::ADC1,
+};
+use esp_println::println;
+use heapless::Vec;
+use serde::{Serialize, Serializer};
+
+use crate::adc::{self, SharedAdc};
+
+/// Number of additional channels a reading can carry besides temperature, gas and flame.
+pub const MAX_CHANNELS: usize = 4;
+
+/// Channel ids below this value are reserved for the built-in sensors.
+pub const FIRST_USER_CHANNEL: u8 = 16;
+
+/// Channel of the flame intensity reported by analog flame modules.
+pub const FLAME_INTENSITY_CHANNEL: u8 = 3;
+
+/// Times a measurement still in progress is polled, yielding in between, before giving up.
+const MAX_POLLS: usize = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum SensorKind {
+    FlameIntensity,
+    Humidity,
+    CarbonMonoxide,
+    Smoke,
+    Motion,
+}
+
+impl SensorKind {
+    /// Name used in MQTT topics and textual encodings.
+    pub fn name(&self) -> &'static str {
+        match self {
+            SensorKind::FlameIntensity => "flame_intensity",
+            SensorKind::Humidity => "humidity",
+            SensorKind::CarbonMonoxide => "co",
+            SensorKind::Smoke => "smoke",
+            SensorKind::Motion => "motion",
+        }
+    }
+
+    pub fn unit(&self) -> Unit {
+        match self {
+            SensorKind::FlameIntensity | SensorKind::CarbonMonoxide | SensorKind::Smoke => {
+                Unit::Raw
+            }
+            SensorKind::Humidity => Unit::CentiPercent,
+            SensorKind::Motion => Unit::Boolean,
+        }
+    }
+
+    pub fn to_byte(&self) -> u8 {
+        match self {
+            SensorKind::FlameIntensity => 0,
+            SensorKind::Humidity => 1,
+            SensorKind::CarbonMonoxide => 2,
+            SensorKind::Smoke => 3,
+            SensorKind::Motion => 4,
+        }
+    }
+
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0 => Some(SensorKind::FlameIntensity),
+            1 => Some(SensorKind::Humidity),
+            2 => Some(SensorKind::CarbonMonoxide),
+            3 => Some(SensorKind::Smoke),
+            4 => Some(SensorKind::Motion),
+            _ => None,
+        }
+    }
+}
+
+/// How the integer value of a [`Reading`] has to be interpreted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum Unit {
+    /// Hundredths of a percent, e.g. relative humidity.
+    CentiPercent,
+    /// Uncalibrated 12 bit ADC value, higher means more.
+    Raw,
+    /// 0 or 1.
+    Boolean,
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct Reading {
+    pub channel: u8,
+    pub kind: SensorKind,
+    pub value: i32,
+}
+
+impl Reading {
+    pub fn unit(&self) -> Unit {
+        self.kind.unit()
+    }
+}
+
+/// Readings of the additional channels taken in one sensor loop iteration.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Readings([Option<Reading>; MAX_CHANNELS]);
+
+impl Readings {
+    pub const fn new() -> Self {
+        Self([None; MAX_CHANNELS])
+    }
+
+    /// Adds a reading, returning it back when every slot is taken.
+    pub fn push(&mut self, reading: Reading) -> Result<(), Reading> {
+        match self.0.iter_mut().find(|slot| slot.is_none()) {
+            Some(slot) => {
+                *slot = Some(reading);
+                Ok(())
+            }
+            None => Err(reading),
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Reading> {
+        self.0.iter().flatten()
+    }
+
+    pub fn get(&self, channel: u8) -> Option<&Reading> {
+        self.iter().find(|reading| reading.channel == channel)
+    }
+}
+
+impl Serialize for Readings {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.iter())
+    }
+}
+
+/// A sensor producing one channel, readings are integers in the unit of its [`SensorKind`].
+///
+/// A measurement is started first and read once it had time to complete, so drivers never
+/// wait themselves and the sensor task keeps the executor free in between.
+pub trait SensorDriver {
+    fn kind(&self) -> SensorKind;
+
+    /// Starts a measurement, returning how long it takes.
+    fn start(&mut self) -> Result<Duration> {
+        Ok(Duration::from_millis(0))
+    }
+
+    /// Reads the measurement, `None` while it is still in progress.
+    fn read(&mut self) -> Result<Option<i32>>;
+}
+
+/// Reads `driver` until its measurement completes, yielding while it is in progress.
+async fn poll(driver: &mut dyn SensorDriver) -> Result<i32> {
+    for _ in 0..MAX_POLLS {
+        if let Some(value) = driver.read()? {
+            return Ok(value);
+        }
+
+        yield_now().await;
+    }
+
+    anyhow::bail!("Measurement did not complete")
+}
+
+/// The additional sensors read by the sensor task on every loop.
+///
+/// # Examples
+/// ```rust
+/// let registry = mk_static!(SensorRegistry<'static>, SensorRegistry::new());
+/// let humidity = mk_static!(
+///     Sht3x<'static>,
+///     Sht3x::new(peripherals.I2C1.into(), scl.into(), sda.into(), SHT3X_ADDRESS)
+/// );
+/// registry.register(FIRST_USER_CHANNEL, humidity).unwrap();
+/// ```
+pub struct SensorRegistry<'a> {
+    drivers: Vec<(u8, &'a mut dyn SensorDriver), MAX_CHANNELS>,
+}
+
+impl<'a> SensorRegistry<'a> {
+    pub const fn new() -> Self {
+        Self { drivers: Vec::new() }
+    }
+
+    pub fn register(&mut self, channel: u8, driver: &'a mut dyn SensorDriver) -> Result<()> {
+        if channel < FIRST_USER_CHANNEL {
+            anyhow::bail!("Channel {channel} is reserved for the built-in sensors");
+        }
+
+        if self.drivers.iter().any(|(id, _)| *id == channel) {
+            anyhow::bail!("Channel {channel} is already registered");
+        }
+
+        self.drivers
+            .push((channel, driver))
+            .map_err(|_| anyhow::anyhow!("Sensor registry is full"))
+    }
+
+    /// Starts every registered driver, waits for the slowest one and reads them, channels
+    /// failing this time are left out.
+    pub async fn read_all(&mut self) -> Readings {
+        let mut started = [false; MAX_CHANNELS];
+        let mut conversion_time = Duration::from_millis(0);
+
+        for (index, (channel, driver)) in self.drivers.iter_mut().enumerate() {
+            match driver.start() {
+                Ok(time) => {
+                    started[index] = true;
+                    conversion_time = conversion_time.max(time);
+                }
+                Err(err) => println!("Error starting channel {}: {}", channel, err),
+            }
+        }
+
+        Timer::after(conversion_time).await;
+
+        let mut readings = Readings::new();
+
+        for (index, (channel, driver)) in self.drivers.iter_mut().enumerate() {
+            if !started[index] {
+                continue;
+            }
+
+            match poll(&mut **driver).await {
+                Ok(value) => {
+                    let _ = readings.push(Reading {
+                        channel: *channel,
+                        kind: driver.kind(),
+                        value,
+                    });
+                }
+                Err(err) => println!("Error reading channel {}: {}", channel, err),
+            }
+        }
+
+        readings
+    }
+}
+
+/// Sensor modules with a digital output, e.g. PIR motion or smoke detectors.
+pub struct DigitalSensor<'a> {
+    input: Input<'a>,
+    kind: SensorKind,
+    active_low: bool,
+}
+
+impl<'a> DigitalSensor<'a> {
+    pub fn new(input: Input<'a>, kind: SensorKind, active_low: bool) -> Self {
+        Self {
+            input,
+            kind,
+            active_low,
+        }
+    }
+}
+
+impl SensorDriver for DigitalSensor<'_> {
+    fn kind(&self) -> SensorKind {
+        self.kind
+    }
+
+    fn read(&mut self) -> Result<Option<i32>> {
+        Ok(Some((self.input.is_low() == self.active_low) as i32))
+    }
+}
+
+/// Sensor modules with an analog output on ADC1, e.g. MQ-7 (CO) or MQ-2 (smoke).
+pub struct AnalogSensor<'a, PIN> {
+    adc: &'a SharedAdc,
+    pin: AdcPin<PIN, ADC1>,
+    kind: SensorKind,
+}
+
+impl<'a, PIN: AdcChannel> AnalogSensor<'a, PIN> {
+    /// `pin` must have been enabled on the config the shared ADC was created from.
+    pub fn new(adc: &'a SharedAdc, pin: AdcPin<PIN, ADC1>, kind: SensorKind) -> Self {
+        Self { adc, pin, kind }
+    }
+}
+
+impl<PIN: AdcChannel> SensorDriver for AnalogSensor<'_, PIN> {
+    fn kind(&self) -> SensorKind {
+        self.kind
+    }
+
+    fn read(&mut self) -> Result<Option<i32>> {
+        Ok(adc::try_read_oneshot(self.adc, &mut self.pin).map(|raw| (4095 - raw) as i32))
+    }
+}
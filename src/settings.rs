@@ -0,0 +1,327 @@
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::mutex::Mutex;
+use embedded_storage::nor_flash::{NorFlash, NorFlashError, NorFlashErrorKind};
+use esp_hal::rng::Rng;
+use esp_println::println;
+use esp_storage::FlashStorage;
+use serde::{Deserialize, Serialize};
+
+use crate::app::{Config, CONFIG};
+use crate::auth::{AuthSettings, DEFAULT_PASSWORD, DEFAULT_USERNAME};
+use crate::cors::CorsSettings;
+use crate::device::DeviceName;
+use crate::mqtt::BrokerSettings;
+use crate::network::NetworkSettings;
+use crate::protocol::crc16;
+use crate::telemetry::TelemetryPolicy;
+use crate::udp::UdpSettings;
+
+/// Two sectors right below the history log, also left unused by the partition table. They
+/// are written alternately so a reset during a save leaves the previous copy intact.
+pub const SETTINGS_OFFSET: u32 = 0x3B_E000;
+const SETTINGS_SECTORS: u32 = 2;
+/// Generation, length, version, postcard encoded settings and CRC, with room for a CA
+/// certificate.
+const SETTINGS_BUFFER_LENGTH: usize = 1536;
+/// Records written before the version byte held only the config, credentials and CORS
+/// settings, in a smaller buffer.
+const LEGACY_BUFFER_LENGTH: usize = 1024;
+const LEGACY_HEADER_LENGTH: usize = 6;
+const SETTINGS_HEADER_LENGTH: usize = 7;
+/// Set in the length of records with a version byte, lengths never come close.
+const VERSIONED_FLAG: u16 = 0x8000;
+
+/// Layouts the settings were stored in, oldest first, the discriminant is the version
+/// byte. postcard keeps no field names, so a field added to [`Settings`] needs a new
+/// layout and a line in [`decode_layout`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Layout {
+    /// Config, credentials and CORS settings, all records without a version byte have it.
+    Auth = 1,
+    Broker,
+    BrokerEnabled,
+    Telemetry,
+    MaxPacketSize,
+    Network,
+    DeviceName,
+    Udp,
+}
+
+impl Layout {
+    const CURRENT: Layout = Layout::Udp;
+    const ALL: [Layout; 8] = [
+        Layout::Udp,
+        Layout::DeviceName,
+        Layout::Network,
+        Layout::MaxPacketSize,
+        Layout::Telemetry,
+        Layout::BrokerEnabled,
+        Layout::Broker,
+        Layout::Auth,
+    ];
+
+    fn from_version(version: u8) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|layout| *layout as u8 == version)
+    }
+}
+
+/// Everything that survives a reboot besides the history.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Settings {
+    pub config: Config,
+    pub auth: AuthSettings,
+    pub cors: CorsSettings,
+    pub broker: BrokerSettings,
+    pub telemetry: TelemetryPolicy,
+    pub network: NetworkSettings,
+    /// Name on the network, derived from the MAC without one.
+    pub device_name: Option<DeviceName>,
+    pub udp: UdpSettings,
+}
+
+impl Settings {
+    /// `config` and `auth` with everything else at its default.
+    fn new(config: Config, auth: AuthSettings) -> Self {
+        Self {
+            config,
+            auth,
+            cors: CorsSettings::default(),
+            broker: BrokerSettings::default(),
+            telemetry: TelemetryPolicy::default(),
+            network: NetworkSettings::default(),
+            device_name: None,
+            udp: UdpSettings::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SettingsError {
+    Flash(NorFlashErrorKind),
+    Encode,
+    /// Settings were not loaded, e.g. because the flash failed on boot.
+    Unavailable,
+}
+
+pub struct SettingsStore<F> {
+    flash: F,
+    offset: u32,
+    generation: u32,
+    /// Sector of the current copy, the next save goes to the other one.
+    sector: u32,
+    settings: Settings,
+}
+
+impl<F: NorFlash> SettingsStore<F> {
+    /// Loads the newest valid copy, falling back to `defaults` if there is none.
+    pub fn open(mut flash: F, offset: u32, defaults: Settings) -> Result<Self, SettingsError> {
+        let mut newest: Option<(u32, u32, Settings)> = None;
+
+        for sector in 0..SETTINGS_SECTORS {
+            let mut bytes = [0; SETTINGS_BUFFER_LENGTH];
+            flash
+                .read(offset + sector * F::ERASE_SIZE as u32, &mut bytes)
+                .map_err(flash_error)?;
+
+            let Some((generation, settings)) = decode(&bytes) else {
+                continue;
+            };
+
+            if newest.as_ref().is_none_or(|(newest, _, _)| generation > *newest) {
+                newest = Some((generation, sector, settings));
+            }
+        }
+
+        let (generation, sector, settings) = newest.unwrap_or((0, SETTINGS_SECTORS - 1, defaults));
+
+        Ok(Self {
+            flash,
+            offset,
+            generation,
+            sector,
+            settings,
+        })
+    }
+
+    pub fn settings(&self) -> &Settings {
+        &self.settings
+    }
+
+    /// Applies `update` and writes the result into the other sector.
+    pub fn update(&mut self, update: impl FnOnce(&mut Settings)) -> Result<(), SettingsError> {
+        let mut settings = self.settings.clone();
+        update(&mut settings);
+
+        let generation = self.generation + 1;
+        let sector = (self.sector + 1) % SETTINGS_SECTORS;
+
+        let mut bytes = [0xFF; SETTINGS_BUFFER_LENGTH];
+        let length = postcard::to_slice(
+            &settings,
+            &mut bytes[SETTINGS_HEADER_LENGTH..SETTINGS_BUFFER_LENGTH - 2],
+        )
+        .map_err(|_| SettingsError::Encode)?
+        .len();
+
+        bytes[..4].copy_from_slice(&generation.to_le_bytes());
+        bytes[4..6].copy_from_slice(&(length as u16 | VERSIONED_FLAG).to_le_bytes());
+        bytes[6] = Layout::CURRENT as u8;
+        let crc = crc16(&bytes[..SETTINGS_HEADER_LENGTH + length]);
+        bytes[SETTINGS_BUFFER_LENGTH - 2..].copy_from_slice(&crc.to_le_bytes());
+
+        let address = self.offset + sector * F::ERASE_SIZE as u32;
+        self.flash
+            .erase(address, address + F::ERASE_SIZE as u32)
+            .map_err(flash_error)?;
+        self.flash.write(address, &bytes).map_err(flash_error)?;
+
+        self.generation = generation;
+        self.sector = sector;
+        self.settings = settings;
+
+        Ok(())
+    }
+}
+
+fn decode(bytes: &[u8; SETTINGS_BUFFER_LENGTH]) -> Option<(u32, Settings)> {
+    let generation = u32::from_le_bytes(bytes[..4].try_into().unwrap());
+    let length = u16::from_le_bytes([bytes[4], bytes[5]]);
+
+    let (buffer_length, header_length, length) = match length & VERSIONED_FLAG {
+        0 => (LEGACY_BUFFER_LENGTH, LEGACY_HEADER_LENGTH, length as usize),
+        _ => (
+            SETTINGS_BUFFER_LENGTH,
+            SETTINGS_HEADER_LENGTH,
+            (length & !VERSIONED_FLAG) as usize,
+        ),
+    };
+
+    if length > buffer_length - header_length - 2 {
+        return None;
+    }
+
+    let crc = u16::from_le_bytes([bytes[buffer_length - 2], bytes[buffer_length - 1]]);
+    if crc != crc16(&bytes[..header_length + length]) {
+        return None;
+    }
+
+    let payload = &bytes[header_length..header_length + length];
+
+    let settings = if header_length == LEGACY_HEADER_LENGTH {
+        decode_layout(Layout::Auth, payload)
+    } else if bytes[6] == Layout::CURRENT as u8 {
+        postcard::from_bytes(payload).ok()
+    } else {
+        Layout::from_version(bytes[6]).and_then(|layout| decode_layout(layout, payload))
+    };
+
+    // Every layout starts with the config and the credentials, which are kept even if
+    // the rest can not be read, e.g. after a downgrade.
+    let settings = settings.or_else(|| {
+        println!("Unknown settings layout, keeping only the config and credentials");
+
+        let mut payload = payload;
+        Some(Settings::new(take(&mut payload)?, take(&mut payload)?))
+    })?;
+
+    Some((generation, settings))
+}
+
+/// Decodes `payload` as stored in `layout`, fields it did not have keep their default.
+fn decode_layout(layout: Layout, mut payload: &[u8]) -> Option<Settings> {
+    let bytes = &mut payload;
+    let mut settings = Settings::new(take(bytes)?, take(bytes)?);
+    settings.cors = take(bytes)?;
+
+    if layout >= Layout::Broker {
+        settings.broker.host = take(bytes)?;
+        settings.broker.port = take(bytes)?;
+        settings.broker.tls = take(bytes)?;
+    }
+    if layout >= Layout::BrokerEnabled {
+        settings.broker.enabled = take(bytes)?;
+    }
+    if layout >= Layout::MaxPacketSize {
+        settings.broker.max_packet_size = take(bytes)?;
+    }
+    if layout >= Layout::Telemetry {
+        settings.telemetry = take(bytes)?;
+    }
+    if layout >= Layout::Network {
+        settings.network = take(bytes)?;
+    }
+    if layout >= Layout::DeviceName {
+        settings.device_name = take(bytes)?;
+    }
+    if layout >= Layout::Udp {
+        settings.udp = take(bytes)?;
+    }
+
+    // A layout only matches if it accounts for every byte.
+    bytes.is_empty().then_some(settings)
+}
+
+/// Decodes the next field from the front of `bytes`.
+fn take<'a, T: Deserialize<'a>>(bytes: &mut &'a [u8]) -> Option<T> {
+    let (value, rest) = postcard::take_from_bytes(*bytes).ok()?;
+    *bytes = rest;
+
+    Some(value)
+}
+
+fn flash_error<E: NorFlashError>(error: E) -> SettingsError {
+    SettingsError::Flash(error.kind())
+}
+
+static SETTINGS: Mutex<CriticalSectionRawMutex, Option<SettingsStore<FlashStorage>>> =
+    Mutex::new(None);
+
+/// Loads the settings and applies the stored config, has to run before anything reads
+/// [`CONFIG`].
+pub async fn init(flash: FlashStorage, mut rng: Rng) {
+    let mut salt = [0; 16];
+    rng.read(&mut salt);
+
+    let defaults = Settings::new(
+        CONFIG.lock().await.clone(),
+        AuthSettings::new(DEFAULT_USERNAME, DEFAULT_PASSWORD, salt).unwrap(),
+    );
+
+    let store = match SettingsStore::open(flash, SETTINGS_OFFSET, defaults) {
+        Ok(store) => store,
+        Err(e) => {
+            println!("Failed to load settings: {:?}", e);
+            return;
+        }
+    };
+
+    if store.settings().auth.is_default() {
+        println!("Default HTTP credentials in use, writes are refused until POST /auth");
+    }
+
+    // Written by older firmware with looser checks, or corrupted in a way the CRC missed.
+    match store.settings().config.validate() {
+        Ok(()) => *CONFIG.lock().await = store.settings().config.clone(),
+        Err(e) => println!("Stored config is invalid, keeping the defaults: {:?}", e),
+    }
+    *SETTINGS.lock().await = Some(store);
+}
+
+/// Reads from the settings without copying all of them.
+pub async fn read<T>(read: impl FnOnce(&Settings) -> T) -> Option<T> {
+    SETTINGS
+        .lock()
+        .await
+        .as_ref()
+        .map(|store| read(store.settings()))
+}
+
+/// Changes and persists the settings.
+pub async fn update(update: impl FnOnce(&mut Settings)) -> Result<(), SettingsError> {
+    let mut store = SETTINGS.lock().await;
+    let store = store.as_mut().ok_or(SettingsError::Unavailable)?;
+
+    store.update(update)
+}
@@ -0,0 +1,105 @@
+use embassy_net::{
+    dns::DnsQueryType,
+    udp::{PacketMetadata, UdpSocket},
+    IpAddress, Stack,
+};
+use embassy_time::{with_timeout, Duration, Timer};
+use esp_println::println;
+
+use crate::clock;
+
+const NTP_SERVER: &str = "pool.ntp.org";
+const NTP_PORT: u16 = 123;
+/// Seconds between the NTP era (1900) and the unix epoch.
+const NTP_UNIX_OFFSET: u64 = 2_208_988_800;
+
+const RESYNC_INTERVAL: Duration = Duration::from_secs(60 * 60);
+const RETRY_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Keeps the wall clock in [`clock`] synced over SNTP.
+#[embassy_executor::task]
+pub async fn sntp_task(stack: Stack<'static>) {
+    let mut rx_meta = [PacketMetadata::EMPTY; 1];
+    let mut rx_buffer = [0; 64];
+    let mut tx_meta = [PacketMetadata::EMPTY; 1];
+    let mut tx_buffer = [0; 64];
+
+    loop {
+        stack.wait_config_up().await;
+
+        let mut socket = UdpSocket::new(
+            stack,
+            &mut rx_meta,
+            &mut rx_buffer,
+            &mut tx_meta,
+            &mut tx_buffer,
+        );
+
+        if let Err(e) = socket.bind(0) {
+            println!("Failed to bind SNTP socket: {:?}", e);
+            Timer::after(RETRY_INTERVAL).await;
+            continue;
+        }
+
+        match request_time(stack, &mut socket).await {
+            Some(unix_secs) => {
+                println!("Clock synced, unix time: {}", unix_secs);
+                clock::set_unix_time(unix_secs);
+                drop(socket);
+                Timer::after(RESYNC_INTERVAL).await;
+            }
+            None => {
+                drop(socket);
+                Timer::after(RETRY_INTERVAL).await;
+            }
+        }
+    }
+}
+
+async fn request_time(stack: Stack<'static>, socket: &mut UdpSocket<'_>) -> Option<u64> {
+    let address: IpAddress = match stack.dns_query(NTP_SERVER, DnsQueryType::A).await {
+        Ok(addresses) if !addresses.is_empty() => addresses[0],
+        Ok(_) => {
+            println!("No address for {}", NTP_SERVER);
+            return None;
+        }
+        Err(e) => {
+            println!("Failed to resolve {}: {:?}", NTP_SERVER, e);
+            return None;
+        }
+    };
+
+    // LI 0, version 4, mode 3 (client)
+    let mut request = [0; 48];
+    request[0] = 0x23;
+
+    if let Err(e) = socket.send_to(&request, (address, NTP_PORT)).await {
+        println!("Failed to send SNTP request: {:?}", e);
+        return None;
+    }
+
+    let mut response = [0; 48];
+    let (len, _) = match with_timeout(Duration::from_secs(5), socket.recv_from(&mut response)).await
+    {
+        Ok(Ok(received)) => received,
+        Ok(Err(e)) => {
+            println!("Failed to receive SNTP response: {:?}", e);
+            return None;
+        }
+        Err(_) => {
+            println!("SNTP request timed out");
+            return None;
+        }
+    };
+
+    let mode = response[0] & 0x07;
+    let stratum = response[1];
+    if len < 48 || mode != 4 || stratum == 0 {
+        println!("Invalid SNTP response");
+        return None;
+    }
+
+    let transmit_secs = u32::from_be_bytes(response[40..44].try_into().unwrap()) as u64;
+
+    transmit_secs.checked_sub(NTP_UNIX_OFFSET)
+}
@@ -0,0 +1,249 @@
+use embassy_time::{Duration, Instant};
+use heapless::Vec;
+use serde::{Deserialize, Serialize};
+
+use crate::app::{Risk, SensorValues};
+use crate::sensor_registry::{Reading, MAX_CHANNELS};
+use crate::settings::{self, SettingsError};
+
+/// When a channel is published to MQTT.
+///
+/// A value goes out once it moved past the deadband since the last published one, but
+/// not sooner than `min_interval_ms` after it, and at the latest `max_interval_ms` after
+/// it as a heartbeat. Without any deadband every change counts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PublishPolicy {
+    pub min_interval_ms: u32,
+    /// `0` disables the heartbeat.
+    pub max_interval_ms: u32,
+    /// Change in the unit of the channel, e.g. hundredths of °C.
+    pub deadband: u32,
+    /// Change relative to the last published value. Relative to `0` any change would be
+    /// enough, so there only `deadband` applies, or any change without it.
+    pub deadband_percent: u8,
+}
+
+impl PublishPolicy {
+    pub const fn new(min_interval_ms: u32, max_interval_ms: u32, deadband: u32) -> Self {
+        Self {
+            min_interval_ms,
+            max_interval_ms,
+            deadband,
+            deadband_percent: 0,
+        }
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.max_interval_ms == 0 || self.max_interval_ms >= self.min_interval_ms
+    }
+
+    fn exceeds_deadband(&self, last: i32, value: i32) -> bool {
+        let delta = value.abs_diff(last);
+
+        if delta == 0 {
+            return false;
+        }
+        if self.deadband == 0 && self.deadband_percent == 0 {
+            return true;
+        }
+
+        let absolute = self.deadband > 0 && delta >= self.deadband;
+        let relative = match (self.deadband_percent, last) {
+            (0, _) => false,
+            (_, 0) => self.deadband == 0,
+            (percent, last) => delta as u64 * 100 >= last.unsigned_abs() as u64 * percent as u64,
+        };
+
+        absolute || relative
+    }
+}
+
+/// Override of the policy of an additional channel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChannelPolicy {
+    pub channel: u8,
+    pub policy: PublishPolicy,
+}
+
+/// Publish policies of all telemetry.
+///
+/// Temperature, gas and flame share the `sensors` message, which goes out when any of
+/// them is due. Risk changes are published right away, together with the readings.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TelemetryPolicy {
+    pub temperature: PublishPolicy,
+    pub gas: PublishPolicy,
+    pub flame: PublishPolicy,
+    /// Additional channels without an entry in `channels`.
+    pub extra: PublishPolicy,
+    pub channels: Vec<ChannelPolicy, MAX_CHANNELS>,
+    /// Heartbeat of the risk, `0` to only publish changes.
+    pub risk_heartbeat_ms: u32,
+}
+
+impl Default for TelemetryPolicy {
+    fn default() -> Self {
+        Self {
+            temperature: PublishPolicy::new(1000, 60_000, 10),
+            gas: PublishPolicy::new(1000, 60_000, 20),
+            flame: PublishPolicy::new(0, 60_000, 0),
+            extra: PublishPolicy::new(1000, 60_000, 0),
+            channels: Vec::new(),
+            risk_heartbeat_ms: 60_000,
+        }
+    }
+}
+
+impl TelemetryPolicy {
+    pub fn is_valid(&self) -> bool {
+        [self.temperature, self.gas, self.flame, self.extra]
+            .iter()
+            .chain(self.channels.iter().map(|channel| &channel.policy))
+            .all(PublishPolicy::is_valid)
+    }
+
+    fn channel(&self, channel: u8) -> &PublishPolicy {
+        self.channels
+            .iter()
+            .find(|policy| policy.channel == channel)
+            .map_or(&self.extra, |policy| &policy.policy)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TelemetryUpdateError {
+    /// A heartbeat shorter than the minimum interval.
+    InvalidPolicy,
+    Settings(SettingsError),
+}
+
+/// The stored policy, or the default one if the settings are unavailable.
+pub async fn policy() -> TelemetryPolicy {
+    settings::read(|settings| settings.telemetry.clone())
+        .await
+        .unwrap_or_default()
+}
+
+/// Persists a new policy, which applies from the next reading on.
+pub async fn update(policy: TelemetryPolicy) -> Result<(), TelemetryUpdateError> {
+    if !policy.is_valid() {
+        return Err(TelemetryUpdateError::InvalidPolicy);
+    }
+
+    settings::update(|settings| settings.telemetry = policy)
+        .await
+        .map_err(TelemetryUpdateError::Settings)
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct ChannelState {
+    last: Option<(i32, Instant)>,
+}
+
+impl ChannelState {
+    fn is_due(&self, policy: &PublishPolicy, value: i32, now: Instant) -> bool {
+        let Some((last, at)) = self.last else {
+            return true;
+        };
+
+        let elapsed = now.saturating_duration_since(at);
+        if elapsed < Duration::from_millis(policy.min_interval_ms as u64) {
+            return false;
+        }
+        if policy.max_interval_ms > 0
+            && elapsed >= Duration::from_millis(policy.max_interval_ms as u64)
+        {
+            return true;
+        }
+
+        policy.exceeds_deadband(last, value)
+    }
+
+    fn published(&mut self, value: i32, now: Instant) {
+        self.last = Some((value, now));
+    }
+}
+
+/// What was last published in an MQTT session, to decide what is due next.
+#[derive(Debug, Default)]
+pub struct TelemetryFilter {
+    temperature: ChannelState,
+    gas: ChannelState,
+    flame: ChannelState,
+    extra: Vec<(u8, ChannelState), MAX_CHANNELS>,
+    risk: Option<(Risk, Instant)>,
+    /// Set by a risk transition, publishes the next readings regardless of the policies.
+    force_readings: bool,
+}
+
+impl TelemetryFilter {
+    /// Whether the `sensors` message is due, marking it published if so.
+    pub fn readings_due(&mut self, policy: &TelemetryPolicy, values: &SensorValues) -> bool {
+        let now = Instant::now();
+        let temperature = values.temp.0 as i32;
+        let gas = values.gas as i32;
+        let flame = values.flame as i32;
+
+        let due = core::mem::take(&mut self.force_readings)
+            || self.temperature.is_due(&policy.temperature, temperature, now)
+            || self.gas.is_due(&policy.gas, gas, now)
+            || self.flame.is_due(&policy.flame, flame, now);
+
+        if due {
+            self.temperature.published(temperature, now);
+            self.gas.published(gas, now);
+            self.flame.published(flame, now);
+        }
+
+        due
+    }
+
+    /// Whether an additional channel is due, marking it published if so.
+    pub fn channel_due(&mut self, policy: &TelemetryPolicy, reading: &Reading) -> bool {
+        let now = Instant::now();
+
+        let index = match self.extra.iter().position(|(channel, _)| *channel == reading.channel)
+        {
+            Some(index) => index,
+            None => {
+                if self.extra.push((reading.channel, ChannelState::default())).is_err() {
+                    return true;
+                }
+                self.extra.len() - 1
+            }
+        };
+        let state = &mut self.extra[index].1;
+
+        let due = state.is_due(policy.channel(reading.channel), reading.value, now);
+        if due {
+            state.published(reading.value, now);
+        }
+
+        due
+    }
+
+    /// Whether the risk is due, marking it published if so. A change is always due and
+    /// makes the next readings due as well.
+    pub fn risk_due(&mut self, policy: &TelemetryPolicy, risk: Risk) -> bool {
+        let now = Instant::now();
+
+        let due = match self.risk {
+            Some((last, _)) if last != risk => {
+                self.force_readings = true;
+                true
+            }
+            Some((_, at)) => {
+                policy.risk_heartbeat_ms > 0
+                    && now.saturating_duration_since(at)
+                        >= Duration::from_millis(policy.risk_heartbeat_ms as u64)
+            }
+            None => true,
+        };
+
+        if due {
+            self.risk = Some((risk, now));
+        }
+
+        due
+    }
+}
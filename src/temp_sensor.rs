@@ -4,6 +4,8 @@ use esp_hal::gpio::Flex;
 use esp_println::println;
 use onecable::{ds18b20::DS18B20, OneWire};
 
+use crate::utils::Temperature;
+
 pub struct TemperatureSensor<'a> {
     temp_sensor: DS18B20,
     wire: OneWire<'a, Flex<'a>>,
@@ -83,12 +85,12 @@ impl<'a> TemperatureSensor<'a> {
         Self { temp_sensor, wire }
     }
 
-    pub fn read_temperature(&mut self) -> Result<f64> {
-        let val = self
+    pub fn read_temperature(&mut self) -> Result<Temperature> {
+        let val: f64 = self
             .temp_sensor
             .read_temperature(&mut self.wire, &mut Delay, &mut Delay)?
             .into();
 
-        Ok(val)
+        Ok(Temperature::from_celsius(val))
     }
 }
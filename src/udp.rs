@@ -0,0 +1,225 @@
+//! UDP discovery and broadcast telemetry for networks without a broker, framed with
+//! [`crate::protocol`] whatever the configured wire format.
+
+use embassy_futures::select::{select3, Either3};
+use embassy_net::{
+    udp::{PacketMetadata, UdpSocket},
+    IpEndpoint, Ipv4Address, Stack,
+};
+use embassy_sync::{
+    blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel, signal::Signal,
+};
+use esp_println::println;
+use heapless::Vec;
+use serde::{Deserialize, Serialize};
+
+use crate::app::{Risk, SensorValues, SENSOR_BYTES_LENGTH};
+use crate::device::{self, FIRMWARE_VERSION, MAX_DEVICE_NAME_LENGTH};
+use crate::http::HTTP_PORT;
+use crate::network;
+use crate::protocol::{self, MessageType, FRAME_OVERHEAD};
+use crate::settings::{self, SettingsError};
+
+/// Port discover frames are broadcast to, and telemetry is sent from.
+pub const DISCOVERY_PORT: u16 = 4210;
+pub const DEFAULT_TELEMETRY_PORT: u16 = 4211;
+
+pub const CAPABILITY_HTTP: u8 = 0x01;
+pub const CAPABILITY_MQTT: u8 = 0x02;
+pub const CAPABILITY_UDP_TELEMETRY: u8 = 0x04;
+pub const CAPABILITY_MDNS: u8 = 0x08;
+
+/// MAC, address, ports, capabilities, name and firmware version.
+const ANNOUNCE_LENGTH: usize = 6 + 4 + 2 + 2 + 1 + 1 + MAX_DEVICE_NAME_LENGTH + 1 + 16;
+const FRAME_LENGTH: usize = max(ANNOUNCE_LENGTH, SENSOR_BYTES_LENGTH) + FRAME_OVERHEAD;
+
+const fn max(a: usize, b: usize) -> usize {
+    if a > b {
+        a
+    } else {
+        b
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UdpSettings {
+    /// Broadcasts readings and risk changes when set.
+    pub telemetry: bool,
+    pub telemetry_port: u16,
+}
+
+impl Default for UdpSettings {
+    fn default() -> Self {
+        Self {
+            telemetry: false,
+            telemetry_port: DEFAULT_TELEMETRY_PORT,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UdpUpdateError {
+    /// `0` or the discovery port.
+    InvalidPort,
+    Settings(SettingsError),
+}
+
+pub async fn udp_settings() -> UdpSettings {
+    settings::read(|settings| settings.udp.clone())
+        .await
+        .unwrap_or_default()
+}
+
+pub async fn update(udp: UdpSettings) -> Result<(), UdpUpdateError> {
+    if udp.telemetry_port == 0 || udp.telemetry_port == DISCOVERY_PORT {
+        return Err(UdpUpdateError::InvalidPort);
+    }
+
+    settings::update(|settings| settings.udp = udp)
+        .await
+        .map_err(UdpUpdateError::Settings)
+}
+
+/// Only the latest reading is broadcast while the task is behind.
+static READINGS: Signal<CriticalSectionRawMutex, SensorValues> = Signal::new();
+/// Risk changes waiting to be broadcast, each of them matters.
+static RISK_CHANGES: Channel<CriticalSectionRawMutex, Risk, 4> = Channel::new();
+
+/// Hands a reading to the UDP task, which drops it unless telemetry is on.
+pub fn send_readings(values: SensorValues) {
+    READINGS.signal(values);
+}
+
+/// Hands a risk change to the UDP task, which drops it unless telemetry is on.
+pub fn send_risk(risk: Risk) {
+    if RISK_CHANGES.try_send(risk).is_err() {
+        println!("UDP telemetry queue full, dropping risk change");
+    }
+}
+
+/// Payload of the announce frame, see [`crate::protocol`].
+async fn announce_payload(stack: Stack<'_>) -> Vec<u8, ANNOUNCE_LENGTH> {
+    let udp = udp_settings().await;
+    let mqtt_enabled = settings::read(|settings| settings.broker.enabled)
+        .await
+        .unwrap_or(false);
+    let address = stack
+        .config_v4()
+        .map_or(Ipv4Address::UNSPECIFIED, |config| config.address.address());
+
+    let mut capabilities = CAPABILITY_HTTP | CAPABILITY_MDNS;
+    if mqtt_enabled {
+        capabilities |= CAPABILITY_MQTT;
+    }
+    if udp.telemetry {
+        capabilities |= CAPABILITY_UDP_TELEMETRY;
+    }
+    let telemetry_port = if udp.telemetry { udp.telemetry_port } else { 0 };
+
+    let name = device::device_name().await;
+
+    let mut payload = Vec::new();
+    payload
+        .extend_from_slice(&network::mac().unwrap_or_default())
+        .unwrap();
+    payload.extend_from_slice(&address.octets()).unwrap();
+    payload.extend_from_slice(&HTTP_PORT.to_le_bytes()).unwrap();
+    payload
+        .extend_from_slice(&telemetry_port.to_le_bytes())
+        .unwrap();
+    payload.push(capabilities).unwrap();
+
+    for text in [name.as_str(), FIRMWARE_VERSION] {
+        let text = &text.as_bytes()[..text.len().min(payload.capacity() - payload.len() - 1)];
+        payload.push(text.len() as u8).unwrap();
+        payload.extend_from_slice(text).unwrap();
+    }
+
+    payload
+}
+
+/// Answers a discover frame from `source`, ignoring anything else.
+async fn answer(stack: Stack<'_>, socket: &UdpSocket<'_>, packet: &[u8], source: IpEndpoint) {
+    match protocol::decode(packet) {
+        Ok(frame) if frame.kind() == Some(MessageType::Discover) => {}
+        _ => return,
+    }
+
+    let payload = announce_payload(stack).await;
+    let Ok(frame) = protocol::encode_vec::<FRAME_LENGTH>(MessageType::Announce, 0, &payload) else {
+        return;
+    };
+
+    if let Err(e) = socket.send_to(&frame, source).await {
+        println!("Failed to answer discovery: {:?}", e);
+    }
+}
+
+/// Broadcasts `payload` to the telemetry port if telemetry is on and the stack has an
+/// address.
+async fn broadcast(
+    stack: Stack<'_>,
+    socket: &UdpSocket<'_>,
+    message_type: MessageType,
+    payload: &[u8],
+) {
+    let udp = udp_settings().await;
+    let Some(config) = stack.config_v4().filter(|_| udp.telemetry) else {
+        return;
+    };
+
+    let Ok(frame) = protocol::encode_vec::<FRAME_LENGTH>(message_type, 0, payload) else {
+        return;
+    };
+
+    let address = config.address.broadcast().unwrap_or(Ipv4Address::BROADCAST);
+    let destination = IpEndpoint::new(address.into(), udp.telemetry_port);
+    if let Err(e) = socket.send_to(&frame, destination).await {
+        println!("Failed to broadcast telemetry: {:?}", e);
+    }
+}
+
+#[embassy_executor::task]
+pub async fn udp_task(stack: Stack<'static>) {
+    let mut rx_meta = [PacketMetadata::EMPTY; 2];
+    let mut rx_buffer = [0; 256];
+    let mut tx_meta = [PacketMetadata::EMPTY; 4];
+    let mut tx_buffer = [0; 512];
+    let mut packet = [0; 64];
+
+    let mut socket = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+
+    if let Err(e) = socket.bind(DISCOVERY_PORT) {
+        println!("Failed to bind discovery socket: {:?}", e);
+        return;
+    }
+
+    loop {
+        // Risk changes go out before a reading that is waiting at the same time.
+        match select3(
+            socket.recv_from(&mut packet),
+            RISK_CHANGES.receive(),
+            READINGS.wait(),
+        )
+        .await
+        {
+            Either3::First(Ok((length, meta))) => {
+                answer(stack, &socket, &packet[..length], meta.endpoint).await
+            }
+            // Truncated, a discover frame is shorter.
+            Either3::First(Err(_)) => {}
+            Either3::Second(risk) => {
+                broadcast(stack, &socket, MessageType::Risk, &[risk.to_byte()]).await
+            }
+            Either3::Third(values) => {
+                broadcast(stack, &socket, MessageType::Readings, &values.to_bytes()).await
+            }
+        }
+    }
+}
@@ -1,14 +1,114 @@
-pub trait FloatRepresentation {
-    fn float_to_parts(self, decimals: u8) -> (u16, u16);
+use core::ops::{Add, AddAssign, Sub, SubAssign};
+
+use serde::{Deserialize, Serialize};
+use ufmt::{uDisplay, uWrite, Formatter};
+
+/// Temperature in hundredths of a degree Celsius, covering -327.68 to 327.67 °C.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct Temperature(pub i16);
+
+impl Temperature {
+    pub const fn from_degrees(degrees: i16) -> Self {
+        Self(degrees * 100)
+    }
+
+    /// Rounds to the nearest hundredth, saturating outside of the representable range.
+    pub fn from_celsius(celsius: f64) -> Self {
+        let centi = celsius * 100.0;
+        let rounded = if centi < 0.0 { centi - 0.5 } else { centi + 0.5 };
+
+        Self(rounded as i16)
+    }
+
+    pub fn to_le_bytes(self) -> [u8; 2] {
+        self.0.to_le_bytes()
+    }
+
+    pub fn from_le_bytes(bytes: [u8; 2]) -> Self {
+        Self(i16::from_le_bytes(bytes))
+    }
+
+    /// Splits the value rounded to `decimals` (0 to 2) places into its sign, integer part
+    /// and decimal part, e.g. -0.05 with 2 decimals is `(true, 0, 5)`.
+    fn parts(self, decimals: u8) -> (bool, u16, u16) {
+        let divisor = 10_i32.pow(2 - decimals.min(2) as u32);
+        let value = self.0 as i32;
+        let rounded = (value.abs() + divisor / 2) / divisor;
+        let multiplier = 10_i32.pow(decimals.min(2) as u32);
+
+        (
+            value < 0 && rounded != 0,
+            (rounded / multiplier) as u16,
+            (rounded % multiplier) as u16,
+        )
+    }
+
+    /// Writes the value with `decimals` decimal places, keeping the sign of values
+    /// between -1 and 0 and the leading zeros of the decimals.
+    pub fn write_decimal<W: uWrite + ?Sized>(
+        self,
+        f: &mut Formatter<'_, W>,
+        decimals: u8,
+    ) -> Result<(), W::Error> {
+        let (negative, int_part, dec_part) = self.parts(decimals);
+
+        if negative {
+            f.write_str("-")?;
+        }
+
+        ufmt::uwrite!(f, "{}", int_part)?;
+
+        if decimals > 0 {
+            f.write_str(".")?;
+            if decimals > 1 && dec_part < 10 {
+                f.write_str("0")?;
+            }
+            ufmt::uwrite!(f, "{}", dec_part)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl uDisplay for Temperature {
+    fn fmt<W: uWrite + ?Sized>(&self, f: &mut Formatter<'_, W>) -> Result<(), W::Error> {
+        self.write_decimal(f, 2)
+    }
+}
+
+impl core::fmt::Display for Temperature {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let (negative, int_part, dec_part) = self.parts(2);
+        let sign = if negative { "-" } else { "" };
+
+        write!(f, "{sign}{int_part}.{dec_part:02}")
+    }
 }
 
-impl FloatRepresentation for f64 {
-    fn float_to_parts(self, decimals: u8) -> (u16, u16) {
-        let num_part = self as u16;
-        let multiplier = 10_u16.pow(decimals.into());
+impl Add for Temperature {
+    type Output = Self;
 
-        let dec_part = ((self - num_part as f64) * multiplier as f64) as u16;
+    fn add(self, rhs: Self) -> Self {
+        Self(self.0.saturating_add(rhs.0))
+    }
+}
+
+impl Sub for Temperature {
+    type Output = Self;
+
+    fn sub(self, rhs: Self) -> Self {
+        Self(self.0.saturating_sub(rhs.0))
+    }
+}
+
+impl AddAssign for Temperature {
+    fn add_assign(&mut self, rhs: Self) {
+        *self = *self + rhs;
+    }
+}
 
-        (num_part, dec_part)
+impl SubAssign for Temperature {
+    fn sub_assign(&mut self, rhs: Self) {
+        *self = *self - rhs;
     }
 }
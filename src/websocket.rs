@@ -0,0 +1,99 @@
+use embassy_futures::select::{select, Either};
+use embassy_sync::pubsub::WaitResult;
+use esp_println::println;
+use heapless::String;
+use picoserve::io::{Read, Write};
+use picoserve::response::ws::{Message, SocketRx, SocketTx, WebSocketCallback};
+use ufmt::uwrite;
+
+use crate::commands::{self, Command, CommandError};
+use crate::events::{EventSubscriber, EVENT_DATA_LENGTH};
+
+/// Fits the largest command, a set config with all fields.
+const COMMAND_BUFFER_LENGTH: usize = 256;
+const COMMAND_OK: u8 = 0;
+const COMMAND_INVALID: u8 = 1;
+const COMMAND_REJECTED: u8 = 2;
+
+/// Dashboard connection, streaming the events as `{"event": <name>, "data": <json>}` text
+/// messages and running the commands it receives.
+///
+/// Text messages carry the JSON form of a [`Command`] and are answered with `{"ok": <name>}`
+/// or `{"error": <reason>}`, where the reason of a rejected config is a
+/// [`crate::app::ConfigError`]. Binary messages carry the opcode form and are answered with
+/// the opcode and `0` on success, `1` for an invalid command or `2` for a rejected config.
+pub struct DashboardSocket(pub EventSubscriber);
+
+impl WebSocketCallback for DashboardSocket {
+    async fn run<R: Read, W: Write<Error = R::Error>>(
+        mut self,
+        mut rx: SocketRx<R>,
+        mut tx: SocketTx<W>,
+    ) -> Result<(), W::Error> {
+        let mut buffer = [0; COMMAND_BUFFER_LENGTH];
+
+        let close_reason = loop {
+            match select(rx.next_message(&mut buffer), self.0.next_message()).await {
+                Either::First(Ok(Message::Text(text))) => {
+                    let reply = match serde_json_core::from_str::<Command>(text) {
+                        Ok((command, _)) => {
+                            let mut reply = String::<128>::new();
+                            let name = command.name();
+                            match commands::dispatch(command).await {
+                                Ok(()) => uwrite!(reply, "{{\"ok\":\"{}\"}}", name),
+                                Err(e) => {
+                                    uwrite!(reply, "{{\"error\":{}}}", e.to_json().as_str())
+                                }
+                            }
+                            .unwrap();
+                            reply
+                        }
+                        Err(_) => String::try_from("{\"error\":\"invalid command\"}").unwrap(),
+                    };
+
+                    tx.send_text(&reply).await?;
+                }
+                Either::First(Ok(Message::Binary(bytes))) => {
+                    let opcode = bytes.first().copied().unwrap_or(0);
+                    let status = match Command::from_bytes(bytes) {
+                        Ok(command) => match commands::dispatch(command).await {
+                            Ok(()) => COMMAND_OK,
+                            Err(_) => COMMAND_REJECTED,
+                        },
+                        Err(CommandError::Config(e)) => {
+                            println!("Rejected config: {:?}", e);
+                            COMMAND_REJECTED
+                        }
+                        Err(CommandError::Invalid) => COMMAND_INVALID,
+                    };
+
+                    tx.send_binary(&[opcode, status]).await?;
+                }
+                Either::First(Ok(Message::Ping(data))) => tx.send_pong(data).await?,
+                Either::First(Ok(Message::Pong(_))) => {}
+                Either::First(Ok(Message::Close(_))) => break None,
+                Either::First(Err(e)) => {
+                    println!("WebSocket error: {:?}", e);
+                    break Some((1011, "Error"));
+                }
+                Either::Second(WaitResult::Message(event)) => {
+                    let mut message = String::<{ EVENT_DATA_LENGTH + 32 }>::new();
+                    uwrite!(
+                        message,
+                        "{{\"event\":\"{}\",\"data\":{}}}",
+                        event.name(),
+                        event.to_json().as_str()
+                    )
+                    .unwrap();
+
+                    tx.send_text(&message).await?;
+                }
+                Either::Second(WaitResult::Lagged(missed)) => {
+                    println!("WebSocket lagged, {} events missed", missed);
+                }
+            }
+        };
+
+        tx.close(close_reason).await
+    }
+}
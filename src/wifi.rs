@@ -1,5 +1,5 @@
 use embassy_executor::Spawner;
-use embassy_net::{DhcpConfig, Runner, Stack, StackResources};
+use embassy_net::{Runner, Stack, StackResources};
 use embassy_time::{Duration, Timer};
 use esp_hal::rng::Rng;
 use esp_println as _;
@@ -8,6 +8,8 @@ use esp_wifi::wifi::{self, WifiController, WifiDevice, WifiEvent, WifiState};
 use esp_wifi::EspWifiController;
 
 use crate::mk_static;
+use crate::network::{self, network_settings};
+use crate::readiness::{self, NetworkState};
 
 const SSID: &str = "AHIKZA";
 const PASSWORD: &str = "HKZ993AAA";
@@ -50,6 +52,7 @@ async fn net_task(mut runner: Runner<'static, WifiDevice<'static>>) {
     runner.run().await
 }
 
+/// Brings the interface up in the background, the stack gets its addresses later on.
 pub async fn start_wifi(
     esp_wifi_ctrl: &'static EspWifiController<'static>,
     wifi: esp_hal::peripherals::WIFI,
@@ -58,42 +61,58 @@ pub async fn start_wifi(
 ) -> Stack<'static> {
     let (controller, interfaces) = esp_wifi::wifi::new(esp_wifi_ctrl, wifi).unwrap();
     let wifi_interface = interfaces.sta;
+    let mac = wifi_interface.mac_address();
     let net_seed = rng.random() as u64 | ((rng.random() as u64) << 32);
 
-    let dhcp_config = DhcpConfig::default();
-    let net_config = embassy_net::Config::dhcpv4(dhcp_config);
+    let settings = network_settings().await;
+    if !settings.is_valid() {
+        println!("Stored static IPv4 config is invalid, using DHCP");
+    }
+    let net_config = settings.to_config(mac);
 
     // Init network stack
     let (stack, runner) = embassy_net::new(
         wifi_interface,
         net_config,
-        mk_static!(StackResources<3>, StackResources::<3>::new()),
+        mk_static!(StackResources<12>, StackResources::<12>::new()),
         net_seed,
     );
 
+    network::register(stack, mac);
+
     spawner.spawn(connection_task(controller)).ok();
     spawner.spawn(net_task(runner)).ok();
-
-    wait_for_connection(stack).await;
+    spawner.spawn(address_task(stack)).ok();
 
     stack
 }
 
-async fn wait_for_connection(stack: Stack<'_>) {
-    println!("Waiting for link to be up");
+/// Reports the addresses whenever the stack gets configured, and checks a static IPv4
+/// config works.
+#[embassy_executor::task]
+async fn address_task(stack: Stack<'static>) {
     loop {
-        if stack.is_link_up() {
-            break;
-        }
-        Timer::after(Duration::from_millis(500)).await;
-    }
+        println!("Waiting for link to be up");
+        stack.wait_link_up().await;
+        readiness::set_network(NetworkState::LinkUp);
 
-    println!("Waiting to get IP address...");
-    loop {
+        println!("Waiting to get IP address...");
+        stack.wait_config_up().await;
+        readiness::set_network(NetworkState::Configured);
         if let Some(config) = stack.config_v4() {
             println!("Got IP: {}", config.address);
-            break;
         }
-        Timer::after(Duration::from_millis(500)).await;
+        if let Some(config) = stack.config_v6() {
+            println!("Got IPv6: {}", config.address);
+        }
+        network::check_static_ipv4(stack).await;
+
+        stack.wait_config_down().await;
+        println!("Lost IP address");
+        readiness::set_network(if stack.is_link_up() {
+            NetworkState::LinkUp
+        } else {
+            NetworkState::Down
+        });
     }
 }